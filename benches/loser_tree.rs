@@ -0,0 +1,140 @@
+//! Compares merging sorted runs through the `LoserTree` against a `BinaryHeap`,
+//! counting the key comparisons each makes as well as timing them.
+//!
+//! Run with: cargo bench --bench loser_tree
+
+use std::collections::BinaryHeap;
+use std::cmp::{Ordering, Reverse};
+use std::cell::Cell;
+use std::time;
+
+// The merger is a binary, so the module is pulled in directly (only part of it is used here)
+#[allow(dead_code)]
+#[path = "../src/loser_tree.rs"]
+mod loser_tree;
+
+use loser_tree::LoserTree;
+
+/// A deterministic xorshift generator, good enough for benchmark data
+fn random_numbers(seed: u64, count: usize) -> Vec<u64> {
+    let mut state = seed | 1;
+    (0..count).map(|_| {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        state % 100000
+    }).collect()
+}
+
+/// Splits the keys into k sorted runs
+fn sorted_runs<K: Ord + Clone>(keys: &[K], k: usize) -> Vec<Vec<K>> {
+    let mut runs = vec![Vec::new(); k];
+    for (i, key) in keys.iter().enumerate() {
+        runs[i % k].push(key.clone());
+    }
+    for run in runs.iter_mut() {
+        run.sort();
+    }
+    runs
+}
+
+thread_local!(static COMPARISONS: Cell<u64> = const { Cell::new(0) });
+
+/// A run positioned on its current key, ordered by that key and counting every comparison
+#[derive(PartialEq, Eq)]
+struct Cursor<K> {
+    key: K,
+    position: usize,
+    run: usize,
+}
+
+impl<K: Ord> Ord for Cursor<K> {
+    fn cmp(&self, other: &Cursor<K>) -> Ordering {
+        COMPARISONS.with(|comparisons| comparisons.set(comparisons.get() + 1));
+        self.key.cmp(&other.key)
+    }
+}
+
+impl<K: Ord> PartialOrd for Cursor<K> {
+    fn partial_cmp(&self, other: &Cursor<K>) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+fn cursors<K: Ord + Clone>(runs: &[Vec<K>]) -> Vec<Cursor<K>> {
+    runs.iter().enumerate()
+        .filter(|&(_, run)| !run.is_empty())
+        .map(|(run_index, run)| Cursor { key: run[0].clone(), position: 0, run: run_index })
+        .collect()
+}
+
+fn loser_tree_merge<K: Ord + Clone>(runs: &[Vec<K>]) -> Vec<K> {
+    let mut merged = Vec::new();
+    let mut tree = LoserTree::new(cursors(runs));
+
+    while let Some(cursor) = tree.winner() {
+        merged.push(cursor.key.clone());
+
+        tree.update_winner(|cursor| {
+            cursor.position += 1;
+            match runs[cursor.run].get(cursor.position) {
+                Some(key) => {
+                    cursor.key.clone_from(key);
+                    true
+                },
+                None => false,
+            }
+        });
+    }
+
+    merged
+}
+
+fn heap_merge<K: Ord + Clone>(runs: &[Vec<K>]) -> Vec<K> {
+    let mut merged = Vec::new();
+    let mut heap = BinaryHeap::from(cursors(runs).into_iter().map(Reverse).collect::<Vec<_>>());
+
+    while let Some(Reverse(mut cursor)) = heap.pop() {
+        merged.push(cursor.key.clone());
+
+        if cursor.position + 1 < runs[cursor.run].len() {
+            cursor.position += 1;
+            cursor.key.clone_from(&runs[cursor.run][cursor.position]);
+            heap.push(Reverse(cursor));
+        }
+    }
+
+    merged
+}
+
+fn bench_merges<K: Ord + Clone>(key_type: &str, keys: &[K]) {
+    for k in &[2, 64, 4096] {
+        let runs = sorted_runs(keys, *k);
+
+        for &(name, merge) in &[("heap", heap_merge::<K> as fn(&[Vec<K>]) -> Vec<K>), ("loser tree", loser_tree_merge::<K>)] {
+            COMPARISONS.with(|comparisons| comparisons.set(0));
+            let start = time::Instant::now();
+
+            let merged = merge(&runs);
+
+            let elapsed = start.elapsed();
+            let comparisons = COMPARISONS.with(|comparisons| comparisons.get());
+            assert_eq!(merged.len(), keys.len());
+
+            println!("{:>6} keys, k = {:>4}, {:>10}: {:>6.2} comparisons/row {:>7.2} ns/row",
+                     key_type,
+                     k,
+                     name,
+                     comparisons as f64 / keys.len() as f64,
+                     elapsed.as_secs_f64() * 1e9 / keys.len() as f64);
+        }
+    }
+}
+
+fn main() {
+    let numbers = random_numbers(7, 1 << 22);
+    bench_merges("u64", &numbers);
+
+    let strings = numbers.iter().map(|number| format!("2017-01-01T{:08}", number)).collect::<Vec<String>>();
+    bench_merges("String", &strings);
+}
@@ -0,0 +1,277 @@
+use std::io::prelude::*;
+use std::str::FromStr;
+use std::fmt;
+use std::io;
+use std::str;
+
+use merge_file::{MergeFile, Mergeable};
+use merge_sink::MergeSink;
+use filter::parse_number;
+use merge_output::MergeOutput;
+
+/// A single aggregate calculated over each run of equal merge keys.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Aggregate {
+    Count,
+    Sum(usize),
+    Min(usize),
+    Max(usize),
+}
+
+impl FromStr for Aggregate {
+    type Err = String;
+
+    /// Parses an aggregate in the form `count`, `sum:<column>`, `min:<column>` or `max:<column>`.
+    fn from_str(aggregate: &str) -> Result<Aggregate, String> {
+        let mut parts = aggregate.trim().splitn(2, ':');
+        let function = parts.next().unwrap_or("");
+
+        if function == "count" {
+            return Ok(Aggregate::Count);
+        }
+
+        let column = match parts.next().map(|column| column.parse::<usize>()) {
+            Some(Ok(column)) => column,
+            _ => return Err(format!("Aggregate '{}' needs a column index, eg. {}:3", aggregate, function)),
+        };
+
+        match function {
+            "sum" => Ok(Aggregate::Sum(column)),
+            "min" => Ok(Aggregate::Min(column)),
+            "max" => Ok(Aggregate::Max(column)),
+            _ => Err(format!("Unknown aggregate '{}', expected count, sum, min or max", function)),
+        }
+    }
+}
+
+/// A numeric column value. Whole numbers are kept as integers so sums of them stay exact,
+/// anything else (or a sum mixing the two) falls back to a float.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Number {
+    Integer(i128),
+    Float(f64),
+}
+
+impl Number {
+    /// Parses a (whitespace padded) number out of a field's bytes, see `parse_number`.
+    fn parse(field: &[u8]) -> Option<Number> {
+        let integer = str::from_utf8(field).ok().map(|field| field.trim()).and_then(|field| {
+            field.parse::<i64>().map(i128::from).or_else(|_| field.parse::<u64>().map(i128::from)).ok()
+        });
+
+        match integer {
+            Some(integer) => Some(Number::Integer(integer)),
+            None => parse_number(field).map(Number::Float),
+        }
+    }
+
+    fn as_f64(self) -> f64 {
+        match self {
+            Number::Integer(integer) => integer as f64,
+            Number::Float(float) => float,
+        }
+    }
+
+    /// Adds the two, None if integers overflow.
+    fn checked_add(self, other: Number) -> Option<Number> {
+        match (self, other) {
+            (Number::Integer(a), Number::Integer(b)) => a.checked_add(b).map(Number::Integer),
+            (a, b) => Some(Number::Float(a.as_f64() + b.as_f64())),
+        }
+    }
+
+    fn less_than(self, other: Number) -> bool {
+        match (self, other) {
+            (Number::Integer(a), Number::Integer(b)) => a < b,
+            (a, b) => a.as_f64() < b.as_f64(),
+        }
+    }
+}
+
+impl fmt::Display for Number {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Number::Integer(integer) => write!(f, "{}", integer),
+            Number::Float(float) => write!(f, "{}", float),
+        }
+    }
+}
+
+/// A `MergeSink` that collapses each run of equal merge keys into a single output row.
+///
+/// The row is the merge key followed by each configured aggregate, joined by the delimiter.
+/// As the merge emits lines in merge key order only the current run is ever held in memory.
+pub struct Aggregator<T, W: Write> {
+    aggregates: Vec<Aggregate>,
+    delimiter: char,
+    output: W,
+    current_key: Option<T>,
+    count: u64,
+    values: Vec<Option<Number>>,
+}
+
+impl<T: Mergeable, W: MergeOutput<T>> Aggregator<T, W> where T::Err: fmt::Debug {
+    pub fn new(aggregates: Vec<Aggregate>, delimiter: char, output: W) -> Aggregator<T, W> {
+        let values = vec![None; aggregates.len()];
+
+        Aggregator {
+            aggregates,
+            delimiter,
+            output,
+            current_key: None,
+            count: 0,
+            values,
+        }
+    }
+
+    /// Writes out the row for the current run (if any) and resets the accumulators.
+    fn flush_run(&mut self) -> io::Result<()> {
+        if let Some(key) = self.current_key.take() {
+            let mut row = String::new();
+
+            for (aggregate, value) in self.aggregates.iter().zip(self.values.iter()) {
+                row.push(self.delimiter);
+
+                match (aggregate, value) {
+                    (&Aggregate::Count, _) => row.push_str(&self.count.to_string()),
+                    (&Aggregate::Sum(_), &None) => row.push('0'),
+                    (_, &Some(value)) => row.push_str(&value.to_string()),
+                    (_, &None) => {},
+                }
+            }
+
+            self.output.start_row(&key)?;
+            key.write_to(&mut self.output)?;
+            writeln!(self.output, "{}", row)?;
+        }
+
+        self.count = 0;
+        for value in self.values.iter_mut() {
+            *value = None;
+        }
+
+        Ok(())
+    }
+}
+
+impl<T: Mergeable, W: MergeOutput<T>> MergeSink<T> for Aggregator<T, W> where T::Err: fmt::Debug {
+    fn write_line(&mut self, merge_file: &MergeFile<T>) -> io::Result<()> {
+        if self.current_key.as_ref() != Some(&merge_file.current_merge_key) {
+            self.flush_run()?;
+            self.current_key = Some(merge_file.current_merge_key.clone());
+        }
+
+        self.count += 1;
+
+        for (aggregate, value) in self.aggregates.iter().zip(self.values.iter_mut()) {
+            let column = match *aggregate {
+                Aggregate::Count => continue,
+                Aggregate::Sum(column) | Aggregate::Min(column) | Aggregate::Max(column) => column,
+            };
+
+            let number = match merge_file.column(column).and_then(|field| Number::parse(&field)) {
+                Some(number) => number,
+                _ => {
+                    debug!("MergeFile<{}>: Column {} isn't numeric, skipping it for {:?}", merge_file.filename, column, aggregate);
+                    continue
+                },
+            };
+
+            *value = match (aggregate, *value) {
+                (_, None) => Some(number),
+                (&Aggregate::Sum(_), Some(current)) => match current.checked_add(number) {
+                    Some(sum) => Some(sum),
+                    None => return Err(io::Error::new(io::ErrorKind::InvalidData, format!(
+                        "MergeFile<{}>: The sum of column {} overflowed at line {}", merge_file.filename, column, merge_file.line_number))),
+                },
+                (&Aggregate::Min(_), Some(current)) => Some(if number.less_than(current) { number } else { current }),
+                (&Aggregate::Max(_), Some(current)) => Some(if current.less_than(number) { number } else { current }),
+                (&Aggregate::Count, current) => current,
+            };
+        }
+
+        Ok(())
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        self.flush_run()?;
+        self.output.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Aggregate, Aggregator, Number};
+    use merge_file_manager::MergeFileManager;
+    use merge_file::InputOptions;
+    use test_helpers::{create_file, TempDir};
+
+    #[test]
+    fn parse_aggregate() {
+        assert_eq!("count".parse::<Aggregate>(), Ok(Aggregate::Count));
+        assert_eq!("sum:2".parse::<Aggregate>(), Ok(Aggregate::Sum(2)));
+        assert_eq!("min:0".parse::<Aggregate>(), Ok(Aggregate::Min(0)));
+        assert_eq!("max:10".parse::<Aggregate>(), Ok(Aggregate::Max(10)));
+        assert!("sum".parse::<Aggregate>().is_err());
+        assert!("avg:1".parse::<Aggregate>().is_err());
+    }
+
+    #[test]
+    fn aggregate_merge() {
+        let dir = TempDir::new("aggregate_merge");
+
+        let test_filename_1: &str = &dir.join("file1.tsv");
+        let test_contents_1 = format!("{}\t{}\t{}\n\
+                                       {}\t{}\t{}\n\
+                                       {}\t{}\t{}\n",
+                                        "123", "bbb", "5",
+                                        "124", "bbb", "7",
+                                        "125", "bbb", "1");
+
+        create_file(test_filename_1, test_contents_1);
+
+        let test_filename_2: &str = &dir.join("file2.tsv");
+        let test_contents_2 = format!("{}\t{}\t{}\n\
+                                       {}\t{}\t{}\n\
+                                       {}\t{}\t{}\n",
+                                        "123", "aaa", "2",
+                                        "123", "aaa", "x",
+                                        "125", "aaa", "4");
+
+        create_file(test_filename_2, test_contents_2);
+
+        let cache = MergeFileManager::retrieve_from_glob(&dir.join("file?.tsv"), '\t', 0, "0".to_string(), InputOptions::default()).unwrap();
+
+        let aggregates = vec![Aggregate::Count, Aggregate::Sum(2), Aggregate::Min(2), Aggregate::Max(2)];
+        let mut aggregator = Aggregator::new(aggregates, '\t', Vec::new());
+        MergeFileManager::begin_merge(cache, None, &mut aggregator).unwrap();
+
+        let output = String::from_utf8(aggregator.output).unwrap();
+        assert_eq!(output, "123\t3\t7\t2\t5\n\
+                            124\t1\t7\t7\t7\n\
+                            125\t2\t5\t1\t4\n");
+    }
+
+    #[test]
+    fn integer_sums() {
+        assert_eq!(Number::parse(b" 42 "), Some(Number::Integer(42)));
+        assert_eq!(Number::parse(b"18446744073709551615"), Some(Number::Integer(u64::MAX as i128)));
+        assert_eq!(Number::parse(b"1.5"), Some(Number::Float(1.5)));
+        assert_eq!(Number::parse(b"x"), None);
+
+        let dir = TempDir::new("integer_sums");
+
+        // Past 2^53 a float sum would lose the odd ones
+        let test_filename_1: &str = &dir.join("file1.tsv");
+        create_file(test_filename_1, "1\t9007199254740993\n1\t1\n2\t9223372036854775807\n2\t9223372036854775807\n3\t1\n3\t0.5\n");
+
+        let cache = MergeFileManager::retrieve_from_glob(&dir.join("file?.tsv"), '\t', 0, "0".to_string(), InputOptions::default()).unwrap();
+        let mut aggregator = Aggregator::new(vec![Aggregate::Sum(1), Aggregate::Max(1)], '\t', Vec::new());
+        MergeFileManager::begin_merge(cache, None, &mut aggregator).unwrap();
+
+        let output = String::from_utf8(aggregator.output).unwrap();
+        assert_eq!(output, "1\t9007199254740994\t9007199254740993\n\
+                            2\t18446744073709551614\t9223372036854775807\n\
+                            3\t1.5\t1\n");
+    }
+}
@@ -0,0 +1,444 @@
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::io::prelude::*;
+use std::fs;
+use std::io;
+use csv;
+
+use flate2::write::DeflateEncoder;
+use flate2::{Compression, Crc};
+
+use merge_file::{escape_key, Mergeable};
+use merge_file_manager::MergeFileManager;
+
+/// How many uncompressed bytes go into each block, small enough that even incompressible data fits in a block
+const BLOCK_BYTES: usize = 0xff00;
+
+/// The empty block that marks the end of a BGZF file
+const EOF_BLOCK: [u8; 28] = [
+    0x1f, 0x8b, 0x08, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0xff, 0x06, 0x00, 0x42, 0x43, 0x02, 0x00,
+    0x1b, 0x00, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+];
+
+/// The first field of a block index's header record
+const INDEX_MAGIC: &str = "#file-merger-block-index";
+
+/// The version of the block index layout written by this build.
+///
+/// 1. The header only recorded the filesize, keys were written as is
+/// 2. The header records the key column, keys are written with `escape_key`
+const INDEX_VERSION: u64 = 2;
+
+/// The columns of a block index, in order
+const INDEX_COLUMNS: [&str; 5] = ["line_number", "offset", "block_offset", "block_position", "key"];
+
+/// Which column of the rows holds the merge key, so a `BlockIndex` is only used to read the rows the same way.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct KeyColumn {
+    pub delimiter: char,
+    pub key_index: usize,
+}
+
+/// Writes BGZF, gzip made of independently compressed blocks of at most 64KiB that each record their compressed size.
+/// Any gzip reader can read it, but knowing where a block starts is enough to start decompressing from there.
+///
+/// Created with `indexed` and with `index_row` called before each row it also builds a `BlockIndex` of the first row
+/// starting in each block.
+pub struct BgzfWriter<W: Write> {
+    inner: W,
+    block: Vec<u8>,
+    /// Compressed bytes written so far, where the current block will start
+    block_offset: u64,
+    /// Uncompressed bytes written so far
+    offset: u64,
+    rows: u64,
+    index: Option<BlockIndex>,
+    finished: bool,
+}
+
+impl<W: Write> BgzfWriter<W> {
+    pub fn new(inner: W) -> BgzfWriter<W> {
+        BgzfWriter {
+            inner,
+            block: Vec::with_capacity(BLOCK_BYTES),
+            block_offset: 0,
+            offset: 0,
+            rows: 0,
+            index: None,
+            finished: false,
+        }
+    }
+
+    /// Builds a `BlockIndex` of the rows, whose merge keys are in `key_column`.
+    pub fn indexed(inner: W, key_column: KeyColumn) -> BgzfWriter<W> {
+        BgzfWriter {
+            index: Some(BlockIndex { key_column: Some(key_column), ..BlockIndex::default() }),
+            ..BgzfWriter::new(inner)
+        }
+    }
+
+    /// Notes that a row with this merge key is about to be written, indexing it if it's the first to start in its block.
+    pub fn index_row<K: Mergeable>(&mut self, key: &K) {
+        self.rows += 1;
+
+        let index = match self.index {
+            Some(ref mut index) => index,
+            None => return,
+        };
+
+        if index.entries.last().map(|entry| entry.block_offset) != Some(self.block_offset) {
+            index.entries.push(BlockIndexEntry {
+                key: escape_key(&key.to_bytes()),
+                line_number: self.rows,
+                offset: self.offset,
+                block_offset: self.block_offset,
+                block_position: self.block.len() as u64,
+            });
+        }
+    }
+
+    /// The index built so far, if the writer is `indexed`.
+    pub fn index(&self) -> Option<&BlockIndex> {
+        self.index.as_ref()
+    }
+
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.inner
+    }
+
+    /// Compresses and writes out the current block (if there's anything in it).
+    fn write_block(&mut self) -> io::Result<()> {
+        if self.block.is_empty() {
+            return Ok(());
+        }
+
+        let mut encoder = DeflateEncoder::new(Vec::with_capacity(BLOCK_BYTES), Compression::Default);
+        encoder.write_all(&self.block)?;
+        let compressed = encoder.finish()?;
+
+        let mut crc = Crc::new();
+        crc.update(&self.block);
+
+        // A gzip member with an extra field holding the total block size, less one
+        let block_size = 18 + compressed.len() + 8;
+        if block_size > 0x10000 {
+            return Err(Error::new(ErrorKind::InvalidData, format!("A BGZF block compressed to {} bytes, over the 64KiB limit", block_size)));
+        }
+
+        let mut header = vec![0x1f, 0x8b, 0x08, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0xff, 0x06, 0x00, 0x42, 0x43, 0x02, 0x00];
+        header.extend_from_slice(&((block_size - 1) as u16).to_le_bytes());
+
+        self.inner.write_all(&header)?;
+        self.inner.write_all(&compressed)?;
+        self.inner.write_all(&crc.sum().to_le_bytes())?;
+        self.inner.write_all(&(self.block.len() as u32).to_le_bytes())?;
+
+        self.block_offset += block_size as u64;
+        self.block.clear();
+        Ok(())
+    }
+
+    /// Writes out the last block and the end of file marker, after which nothing more can be written.
+    pub fn try_finish(&mut self) -> io::Result<()> {
+        if !self.finished {
+            self.write_block()?;
+            self.inner.write_all(&EOF_BLOCK)?;
+            self.finished = true;
+        }
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for BgzfWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.finished {
+            return Err(io::Error::other("Write to a finished BGZF file"));
+        }
+
+        let length = buf.len().min(BLOCK_BYTES - self.block.len());
+        self.block.extend_from_slice(&buf[..length]);
+        self.offset += length as u64;
+
+        // Never left full, so a row about to be written always starts in the current block
+        if self.block.len() == BLOCK_BYTES {
+            self.write_block()?;
+        }
+
+        Ok(length)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.write_block()?;
+        self.inner.flush()
+    }
+}
+
+/// Where a row starts in a BGZF file, see `BlockIndex`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BlockIndexEntry {
+    /// As written by `escape_key`
+    pub key: String,
+    pub line_number: u64,
+    /// Where the row starts in the decompressed file
+    pub offset: u64,
+    /// Where the block the row starts in starts in the compressed file
+    pub block_offset: u64,
+    /// Where the row starts in the decompressed block
+    pub block_position: u64,
+}
+
+/// A sidecar index (`<file>.keyidx`) of a BGZF file, the first row starting in each of its blocks and its merge key.
+/// Every row before an entry has a merge key no greater than the entry's.
+///
+/// It's written next to `.bgz` outputs, and read by `MergeFile::fast_forward` to start decompressing from the last
+/// block before its key. The compressed file's size is recorded in the header so an index left behind by an
+/// earlier version of the file is ignored, as is the key column so it's only used to read the key it indexes.
+/// Version 1 indexes recorded neither the key column nor keys that weren't UTF-8, so they're ignored too.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct BlockIndex {
+    pub filesize: u64,
+    /// Only None for an index that's yet to be given one
+    pub key_column: Option<KeyColumn>,
+    pub entries: Vec<BlockIndexEntry>,
+}
+
+impl BlockIndex {
+    /// Where the index of the BGZF file at `path` is kept.
+    pub fn path_for(path: &Path) -> PathBuf {
+        PathBuf::from(format!("{}.keyidx", path.display()))
+    }
+
+    /// Loads the index of the BGZF file at `path`, if it has one matching the file as it is now and indexing `key_column`.
+    pub fn load_for(path: &Path, key_column: KeyColumn) -> Option<BlockIndex> {
+        let index_path = BlockIndex::path_for(path);
+        if !index_path.exists() {
+            return None;
+        }
+
+        match (BlockIndex::read(&index_path), fs::metadata(path)) {
+            (Ok(index), Ok(metadata)) if index.filesize != metadata.len() => {
+                warn!("Ignoring {} as it indexes a {} byte file, {} is {} bytes", index_path.display(), index.filesize, path.display(), metadata.len());
+                None
+            },
+            (Ok(index), Ok(_)) if index.key_column != Some(key_column) => {
+                info!("Ignoring {} as it indexes column {} split on {:?}, not column {} split on {:?}", index_path.display(),
+                      index.key_column.map(|column| column.key_index).unwrap_or_default(), index.key_column.map(|column| column.delimiter).unwrap_or_default(),
+                      key_column.key_index, key_column.delimiter);
+                None
+            },
+            (Ok(index), Ok(_)) => Some(index),
+            (Err(error), _) | (_, Err(error)) => {
+                warn!("Ignoring {}: {}", index_path.display(), error);
+                None
+            },
+        }
+    }
+
+    pub fn read(path: &Path) -> io::Result<BlockIndex> {
+        let invalid = |problem: String| Error::new(ErrorKind::InvalidData, format!("{}: {}", path.display(), problem));
+
+        let mut index_reader = csv::Reader::from_file(path).map_err(|error| invalid(error.to_string()))?
+                                           .has_headers(false)
+                                           .flexible(true);
+        let mut records = index_reader.records();
+
+        let header = match records.next() {
+            Some(record) => record.map_err(|error| invalid(error.to_string()))?,
+            None => return Err(invalid("The block index is empty".to_string())),
+        };
+
+        if header.first().map(|field| field.as_str()) != Some(INDEX_MAGIC) {
+            return Err(invalid("Not a block index".to_string()));
+        }
+
+        let mut index = BlockIndex::default();
+        let (mut version, mut filesize, mut delimiter, mut key_index) = (None, None, None, None);
+
+        for field in header.iter().skip(1) {
+            let (name, value) = field.split_once('=').unwrap_or((field, ""));
+            let number = || value.parse::<u64>().map_err(|_| invalid(format!("{} '{}' isn't a number", name, value)));
+
+            match name {
+                "version" => version = Some(number()?),
+                "filesize" => filesize = Some(number()?),
+                "key_index" => key_index = Some(number()? as usize),
+                "delimiter" => delimiter = Some(MergeFileManager::parse_delimiter(value).ok_or_else(|| invalid(format!("delimiter '{}' isn't a character", value)))?),
+                _ => debug!("Skipping unknown block index header field {}", field),
+            }
+        }
+
+        match (version, filesize, delimiter, key_index) {
+            (Some(INDEX_VERSION), Some(filesize), Some(delimiter), Some(key_index)) => {
+                index.filesize = filesize;
+                index.key_column = Some(KeyColumn { delimiter, key_index });
+            },
+            (Some(version), _, _, _) if version != INDEX_VERSION => return Err(invalid(format!("Not a version {} block index", INDEX_VERSION))),
+            _ => return Err(invalid("The block index header is missing its filesize or key column".to_string())),
+        }
+
+        // Skip the column names
+        for record in records.skip(1) {
+            let record = record.map_err(|error| invalid(error.to_string()))?;
+            let number = |column: usize| record.get(column).and_then(|field| field.parse::<u64>().ok());
+
+            match (number(0), number(1), number(2), number(3), record.get(4)) {
+                (Some(line_number), Some(offset), Some(block_offset), Some(block_position), Some(key)) => index.entries.push(BlockIndexEntry {
+                    key: key.clone(),
+                    line_number,
+                    offset,
+                    block_offset,
+                    block_position,
+                }),
+                _ => return Err(invalid(format!("Invalid block index entry {:?}", record))),
+            }
+        }
+
+        Ok(index)
+    }
+
+    /// Writes the index of the BGZF file at `path` next to it.
+    pub fn write_for(&self, path: &Path) -> io::Result<()> {
+        let index_path = BlockIndex::path_for(path);
+        let csv_error = |error: csv::Error| io::Error::other(format!("{}: {}", index_path.display(), error));
+
+        let key_column = match self.key_column {
+            Some(key_column) => key_column,
+            None => return Err(Error::new(ErrorKind::InvalidInput, format!("{}: The block index doesn't know which column it indexes", index_path.display()))),
+        };
+
+        let mut index = self.clone();
+        index.filesize = fs::metadata(path)?.len();
+
+        let mut index_writer = csv::Writer::from_file(&index_path).map_err(csv_error)?
+                                           .flexible(true);
+
+        index_writer.write([
+            INDEX_MAGIC.to_string(),
+            format!("version={}", INDEX_VERSION),
+            format!("filesize={}", index.filesize),
+            format!("delimiter={}", MergeFileManager::pretty_delimiter(key_column.delimiter)),
+            format!("key_index={}", key_column.key_index),
+        ].iter()).map_err(csv_error)?;
+        index_writer.write(INDEX_COLUMNS.iter()).map_err(csv_error)?;
+
+        for entry in &index.entries {
+            index_writer.write([
+                entry.line_number.to_string(),
+                entry.offset.to_string(),
+                entry.block_offset.to_string(),
+                entry.block_position.to_string(),
+                entry.key.clone(),
+            ].iter()).map_err(csv_error)?;
+        }
+
+        index_writer.flush().map_err(csv_error)?;
+        debug!("Written block index to {}", index_path.display());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::prelude::*;
+    use std::io::{BufReader, SeekFrom};
+    use std::fs::File;
+    use std::fs;
+
+    use flate2::read::{GzDecoder, MultiGzDecoder};
+
+    use super::{BgzfWriter, BlockIndex, KeyColumn, BLOCK_BYTES};
+    use merge_file::{escape_key, ByteString};
+    use test_helpers::TempDir;
+
+    const KEY_COLUMN: KeyColumn = KeyColumn { delimiter: '\t', key_index: 0 };
+
+    #[test]
+    fn bgzf_blocks() {
+        let dir = TempDir::new("bgzf_blocks");
+
+        let path = &dir.path().join("file1.tsv.bgz");
+
+        let mut contents = String::new();
+        let mut writer = BgzfWriter::indexed(File::create(path).unwrap(), KEY_COLUMN);
+        for key in 0..20000 {
+            let row = format!("{}\tsome value to fill the blocks up a bit faster\n", key);
+            writer.index_row(&key);
+            writer.write_all(row.as_bytes()).unwrap();
+            contents.push_str(&row);
+        }
+        writer.try_finish().unwrap();
+
+        let index = writer.index().unwrap().clone();
+        index.write_for(path).unwrap();
+
+        // One entry per block
+        assert_eq!(index.entries.len(), contents.len().div_ceil(BLOCK_BYTES));
+        assert_eq!(index.entries[0].block_offset, 0);
+
+        // Reads back whole as (multi member) gzip, where a plain gzip reader only gets the first block
+        let mut read_back = String::new();
+        MultiGzDecoder::new(File::open(path).unwrap()).unwrap().read_to_string(&mut read_back).unwrap();
+        assert_eq!(read_back, contents);
+
+        let mut first_block = Vec::new();
+        GzDecoder::new(File::open(path).unwrap()).unwrap().read_to_end(&mut first_block).unwrap();
+        assert_eq!(first_block.len(), BLOCK_BYTES);
+
+        // Each entry leads straight to its row
+        let index = BlockIndex::load_for(path, KEY_COLUMN).unwrap();
+        assert_eq!(index.key_column, Some(KEY_COLUMN));
+        for entry in &index.entries {
+            let mut file = File::open(path).unwrap();
+            file.seek(SeekFrom::Start(entry.block_offset)).unwrap();
+
+            let mut decoder = BufReader::new(MultiGzDecoder::new(file).unwrap());
+            let mut skipped = vec![0; entry.block_position as usize];
+            decoder.read_exact(&mut skipped).unwrap();
+
+            let mut row = String::new();
+            decoder.read_line(&mut row).unwrap();
+            assert_eq!(row, contents.lines().nth(entry.line_number as usize - 1).unwrap().to_string() + "\n");
+            assert!(row.starts_with(&format!("{}\t", entry.key)));
+            assert_eq!(&contents[entry.offset as usize..entry.offset as usize + row.len()], row);
+        }
+
+        // An index of a different column is ignored
+        assert!(BlockIndex::load_for(path, KeyColumn { key_index: 1, ..KEY_COLUMN }).is_none());
+        assert!(BlockIndex::load_for(path, KeyColumn { delimiter: ',', ..KEY_COLUMN }).is_none());
+
+        // An index for a different version of the file is ignored
+        fs::OpenOptions::new().append(true).open(path).unwrap().write_all(b"\n").unwrap();
+        assert!(BlockIndex::load_for(path, KEY_COLUMN).is_none());
+    }
+
+    #[test]
+    fn raw_keys() {
+        let dir = TempDir::new("bgzf_raw_keys");
+
+        let path = &dir.path().join("file1.csv.bgz");
+        let key_column = KeyColumn { delimiter: ',', key_index: 1 };
+
+        let key = ByteString(b"caf\xe9".to_vec());
+        let mut writer = BgzfWriter::indexed(File::create(path).unwrap(), key_column);
+        writer.index_row(&key);
+        writer.write_all(b"1,caf\xe9\n").unwrap();
+        writer.try_finish().unwrap();
+        writer.index().unwrap().write_for(path).unwrap();
+
+        let contents = fs::read_to_string(BlockIndex::path_for(path)).unwrap();
+        assert!(contents.starts_with("#file-merger-block-index,version=2,filesize="));
+        assert!(contents.contains(",delimiter=csv,key_index=1\n"));
+
+        let index = BlockIndex::load_for(path, key_column).unwrap();
+        assert_eq!(index.entries[0].key, escape_key(b"caf\xe9"));
+
+        // Version 1 indexes don't say which column they index
+        fs::write(BlockIndex::path_for(path), contents.replace("version=2", "version=1")).unwrap();
+        assert!(BlockIndex::load_for(path, key_column).is_none());
+
+        // Nor do unindexed writers build one
+        let mut writer = BgzfWriter::new(Vec::new());
+        writer.index_row(&key);
+        assert!(writer.index().is_none());
+    }
+}
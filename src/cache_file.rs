@@ -0,0 +1,1033 @@
+use std::io::{Error, ErrorKind, SeekFrom};
+use std::fs::{File, OpenOptions, TryLockError};
+use std::time::{SystemTime, UNIX_EPOCH};
+use std::collections::{BTreeMap, HashMap};
+use std::io::prelude::*;
+use std::str::FromStr;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::fmt;
+use std::env;
+use std::fs;
+use std::io;
+use csv;
+
+use merge_file_manager::MergeFileManager;
+use merge_file::{escape_key, Checkpoint};
+use partition::{stable_hash_extend, STABLE_HASH_START};
+use settings::KeyType;
+
+/// The version of the cache file layout written by this build.
+///
+/// 1. The original headerless layout, one positional record per file (still read, see `CacheFile::read`)
+/// 2. A header record and a record of column names before the entries
+/// 3. Filenames under the `base_dir` recorded in the header are relative to it
+/// 4. Merge keys are written with `escape_key`, so keys that aren't UTF-8 are kept byte for byte
+pub const CACHE_FORMAT_VERSION: u32 = 4;
+
+/// The first field of a cache file's header record, telling it apart from a headerless version 1 cache
+const CACHE_MAGIC: &str = "#file-merger-cache";
+
+/// The columns of a version 1 cache, in order
+const LEGACY_COLUMNS: [&str; 6] = ["filename", "beginning_merge_key", "ending_merge_key", "delimiter", "key_index", "filesize"];
+
+/// The columns written out, in order
+const COLUMNS: [&str; 11] = ["filename", "beginning_merge_key", "ending_merge_key", "delimiter", "key_index", "filesize", "mtime_ns", "fingerprint",
+                             "line_count", "byte_count", "checkpoints"];
+
+/// How many bytes from each end of a data file go into its fingerprint
+const FINGERPRINT_BLOCK_BYTES: u64 = 64 * 1024;
+
+/// What to do with a cache entry whose data file changed after the cache was written.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum StaleCachePolicy {
+    /// Read the file again instead of trusting what the cache says about it (the default)
+    #[default]
+    Rescan,
+    /// Fail, naming the file and what changed
+    Reject,
+}
+
+impl FromStr for StaleCachePolicy {
+    type Err = String;
+
+    fn from_str(policy: &str) -> Result<StaleCachePolicy, String> {
+        match policy.trim().to_lowercase().as_ref() {
+            "rescan" => Ok(StaleCachePolicy::Rescan),
+            "reject" => Ok(StaleCachePolicy::Reject),
+            _ => Err(format!("Unknown stale cache policy '{}', expected rescan or reject", policy)),
+        }
+    }
+}
+
+/// What to do on meeting a cache another file-merger holds a `CacheLock` on.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum CacheLockPolicy {
+    /// Wait for it to let go (the default)
+    #[default]
+    Wait,
+    /// Fail straight away, naming the cache
+    Fail,
+}
+
+impl FromStr for CacheLockPolicy {
+    type Err = String;
+
+    fn from_str(policy: &str) -> Result<CacheLockPolicy, String> {
+        match policy.trim().to_lowercase().as_ref() {
+            "wait" => Ok(CacheLockPolicy::Wait),
+            "fail" => Ok(CacheLockPolicy::Fail),
+            _ => Err(format!("Unknown cache lock policy '{}', expected wait or fail", policy)),
+        }
+    }
+}
+
+/// How cache files are loaded and written, shared by every cache a run touches.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CacheOptions {
+    /// Resolves relative filenames instead of the base directory recorded in the cache (see `CacheFile::read`)
+    pub base_dir: Option<PathBuf>,
+    pub stale_policy: StaleCachePolicy,
+    pub lock_policy: CacheLockPolicy,
+}
+
+/// An advisory lock on a cache file, held until it's dropped.
+///
+/// The lock is taken on a `.<cache>.lock` file next to the cache rather than the cache itself, which `CacheFile::write`
+/// replaces. Writers hold it exclusively for as long as they read and replace the cache, and readers share it, so a
+/// reader never sees a cache part way through being refreshed and two writers never interleave.
+pub struct CacheLock {
+    /// None when reading a cache in a directory we can't create the lock file in, nobody can be writing it
+    file: Option<File>,
+}
+
+impl CacheLock {
+    /// Takes a lock any number of readers can share, waiting for or failing on a writer as `policy` says.
+    pub fn shared(path: &Path, policy: CacheLockPolicy) -> io::Result<CacheLock> {
+        CacheLock::acquire(path, policy, false)
+    }
+
+    /// Takes the only lock on the cache, waiting for or failing on anyone else as `policy` says.
+    pub fn exclusive(path: &Path, policy: CacheLockPolicy) -> io::Result<CacheLock> {
+        CacheLock::acquire(path, policy, true)
+    }
+
+    fn acquire(path: &Path, policy: CacheLockPolicy, exclusive: bool) -> io::Result<CacheLock> {
+        let lock_path = CacheLock::path_for(path);
+        let file = match OpenOptions::new().read(true).write(true).create(true).truncate(false).open(&lock_path) {
+            Ok(file) => file,
+            // Read only caches, eg. shared ones or on a read only mount, are read without a lock
+            Err(ref error) if !exclusive && matches!(error.kind(), ErrorKind::PermissionDenied | ErrorKind::ReadOnlyFilesystem) => {
+                debug!("Reading {} without a lock, {} can't be created: {}", path.display(), lock_path.display(), error);
+                return Ok(CacheLock { file: None });
+            },
+            Err(error) => return Err(Error::new(error.kind(), format!("Unable to lock {} with {}: {}", path.display(), lock_path.display(), error))),
+        };
+
+        let locked = if exclusive { file.try_lock() } else { file.try_lock_shared() };
+        match locked {
+            Ok(()) => (),
+            Err(TryLockError::WouldBlock) => {
+                let holder = if exclusive { "using" } else { "writing" };
+                match policy {
+                    CacheLockPolicy::Wait => {
+                        info!("Another file-merger is {} {}, waiting for it to finish", holder, path.display());
+                        if exclusive { file.lock()? } else { file.lock_shared()? }
+                    },
+                    CacheLockPolicy::Fail => return Err(Error::new(ErrorKind::WouldBlock, format!(
+                        "Another file-merger is {} {}, try again once it's finished or use --cache-lock wait", holder, path.display()))),
+                }
+            },
+            Err(TryLockError::Error(error)) => return Err(error),
+        }
+
+        debug!("Locked {} ({})", path.display(), if exclusive { "exclusive" } else { "shared" });
+        Ok(CacheLock { file: Some(file) })
+    }
+
+    /// Where the lock for the cache at `path` is taken, eg. data.cache -> .data.cache.lock
+    fn path_for(path: &Path) -> PathBuf {
+        let filename = path.file_name().map(|filename| filename.to_string_lossy().into_owned()).unwrap_or_default();
+        path.with_file_name(format!(".{}.lock", filename))
+    }
+}
+
+impl Drop for CacheLock {
+    fn drop(&mut self) {
+        if let Some(ref file) = self.file {
+            let _ = file.unlock();
+        }
+    }
+}
+
+/// A quick fingerprint of a data file's contents: the `stable_hash` of its size and first and last blocks, in hex.
+/// It catches most rewrites that keep the size and modification time without reading the whole file.
+pub fn fingerprint(path: &Path) -> io::Result<String> {
+    let mut file = File::open(path)?;
+    let filesize = file.metadata()?.len();
+
+    let mut hash = stable_hash_extend(STABLE_HASH_START, &filesize.to_le_bytes());
+
+    let mut block = Vec::new();
+    (&mut file).take(FINGERPRINT_BLOCK_BYTES).read_to_end(&mut block)?;
+    hash = stable_hash_extend(hash, &block);
+
+    if filesize > FINGERPRINT_BLOCK_BYTES {
+        block.clear();
+        file.seek(SeekFrom::Start(filesize.saturating_sub(FINGERPRINT_BLOCK_BYTES).max(FINGERPRINT_BLOCK_BYTES)))?;
+        file.read_to_end(&mut block)?;
+        hash = stable_hash_extend(hash, &block);
+    }
+
+    Ok(format!("{:016x}", hash))
+}
+
+/// A file's modification time in nanoseconds since the Unix epoch.
+fn mtime_ns(metadata: &fs::Metadata) -> io::Result<u64> {
+    let since = metadata.modified()?.duration_since(UNIX_EPOCH).map_err(|error| Error::new(ErrorKind::InvalidData, error.to_string()))?;
+    Ok(since.as_secs() * 1_000_000_000 + since.subsec_nanos() as u64)
+}
+
+/// What a cache file says about itself.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CacheHeader {
+    pub version: u32,
+    /// The key type the merge keys were read as, version 1 caches didn't record it
+    pub key_type: Option<KeyType>,
+    /// The file-merger version that wrote the cache
+    pub tool_version: String,
+    /// When the cache was written, in seconds since the Unix epoch
+    pub created: u64,
+    /// The directory relative filenames are relative to, itself relative to the cache file's directory if it's relative.
+    /// Before version 3 filenames were as given, relative ones being relative to wherever file-merger ran
+    pub base_dir: Option<PathBuf>,
+}
+
+impl CacheHeader {
+    /// A header for a cache being written now.
+    pub fn new(key_type: KeyType) -> CacheHeader {
+        CacheHeader {
+            version: CACHE_FORMAT_VERSION,
+            key_type: Some(key_type),
+            tool_version: env!("CARGO_PKG_VERSION").to_string(),
+            created: SystemTime::now().duration_since(UNIX_EPOCH).map(|since| since.as_secs()).unwrap_or(0),
+            base_dir: None,
+        }
+    }
+
+    /// What we know about a headerless version 1 cache.
+    fn legacy() -> CacheHeader {
+        CacheHeader {
+            version: 1,
+            key_type: None,
+            tool_version: String::new(),
+            created: 0,
+            base_dir: None,
+        }
+    }
+
+    fn to_record(&self) -> Vec<String> {
+        let mut record = vec![CACHE_MAGIC.to_string(), format!("version={}", self.version)];
+        if let Some(ref key_type) = self.key_type {
+            record.push(format!("key_type={:?}", key_type));
+        }
+        record.push(format!("tool_version={}", self.tool_version));
+        record.push(format!("created={}", self.created));
+        if let Some(ref base_dir) = self.base_dir {
+            record.push(format!("base_dir={}", base_dir.display()));
+        }
+        record
+    }
+
+    /// Parses the header record, rejecting versions newer than we know how to read.
+    /// Unknown fields are skipped, so they can be added without breaking older readers.
+    fn from_record(record: &[String], path: &Path) -> io::Result<CacheHeader> {
+        let invalid = |problem: String| Error::new(ErrorKind::InvalidData, format!("{}: Invalid cache header, {}", path.display(), problem));
+        let mut header = CacheHeader::legacy();
+        let mut version = None;
+
+        for field in record.iter().skip(1) {
+            let mut parts = field.splitn(2, '=');
+            let (name, value) = (parts.next().unwrap_or(""), parts.next().unwrap_or(""));
+
+            match name {
+                "version" => version = Some(value.parse::<u32>().map_err(|_| invalid(format!("version '{}' isn't a number", value)))?),
+                "key_type" => header.key_type = Some(value.parse::<KeyType>().map_err(invalid)?),
+                "tool_version" => header.tool_version = value.to_string(),
+                "created" => header.created = value.parse::<u64>().map_err(|_| invalid(format!("created '{}' isn't a timestamp", value)))?,
+                "base_dir" => header.base_dir = Some(PathBuf::from(value)),
+                _ => debug!("Skipping unknown cache header field {}", field),
+            }
+        }
+
+        header.version = match version {
+            Some(version) if version > CACHE_FORMAT_VERSION => return Err(Error::new(ErrorKind::InvalidData, format!(
+                "{} is a version {} cache file written by file-merger {}, this file-merger ({}) only reads cache versions up to {}. \
+                 Upgrade file-merger or rebuild the cache with --glob and --cache-file",
+                path.display(), version, header.tool_version, env!("CARGO_PKG_VERSION"), CACHE_FORMAT_VERSION))),
+            Some(version) if version >= 2 => version,
+            Some(version) => return Err(invalid(format!("version {} caches don't have a header", version))),
+            None => return Err(invalid("it has no version".to_string())),
+        };
+
+        Ok(header)
+    }
+}
+
+/// One data file recorded in a cache.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CacheEntry {
+    pub filename: String,
+    /// The merge keys are as written by `escape_key`, see `merge_file::parse_escaped_key`
+    pub beginning_merge_key: String,
+    /// Empty if the file was never read to its end
+    pub ending_merge_key: String,
+    pub delimiter: char,
+    pub key_index: usize,
+    /// The data file's size, mtime and fingerprint when the cache was written, older caches may lack any of them
+    pub filesize: Option<u64>,
+    pub mtime_ns: Option<u64>,
+    pub fingerprint: Option<String>,
+    /// How many lines, and bytes of decompressed lines, the data file has, if it was read to its end
+    pub line_count: Option<u64>,
+    pub byte_count: Option<u64>,
+    /// Written as `line_number:offset:key` one per line (keys never span lines)
+    pub checkpoints: Vec<Checkpoint>,
+}
+
+impl CacheEntry {
+    /// Records the data file's current size, mtime and fingerprint, so later changes to it can be noticed.
+    pub fn record_file_state(&mut self) -> io::Result<()> {
+        let path = Path::new(&self.filename);
+        let metadata = fs::metadata(path)?;
+
+        self.filesize = Some(metadata.len());
+        self.mtime_ns = Some(mtime_ns(&metadata)?);
+        self.fingerprint = Some(fingerprint(path)?);
+        Ok(())
+    }
+
+    /// Escapes the merge keys of an entry from a cache written before they were, when they were written as is.
+    fn escape_keys(&mut self) {
+        self.beginning_merge_key = escape_key(self.beginning_merge_key.as_bytes());
+        self.ending_merge_key = escape_key(self.ending_merge_key.as_bytes());
+        for checkpoint in self.checkpoints.iter_mut() {
+            checkpoint.key = escape_key(checkpoint.key.as_bytes());
+        }
+    }
+
+    /// Describes how the data file differs from what was recorded of it, if it does.
+    /// Only what was recorded is checked, so an entry from an older cache may be missing changes.
+    pub fn changes(&self) -> io::Result<Option<String>> {
+        let path = Path::new(&self.filename);
+        let metadata = fs::metadata(path)?;
+
+        if let Some(filesize) = self.filesize {
+            if metadata.len() != filesize {
+                return Ok(Some(format!("its size went from {} to {} bytes", filesize, metadata.len())));
+            }
+        }
+
+        if let Some(recorded_mtime_ns) = self.mtime_ns {
+            if mtime_ns(&metadata)? != recorded_mtime_ns {
+                return Ok(Some("it was modified".to_string()));
+            }
+        }
+
+        if let Some(ref recorded_fingerprint) = self.fingerprint {
+            if &fingerprint(path)? != recorded_fingerprint {
+                return Ok(Some("its contents changed".to_string()));
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Describes how the data file differs between this and a `newer` entry for it, going by what both recorded.
+    /// Like `changes`, but between two caches rather than against the file on disk.
+    pub fn changes_to(&self, newer: &CacheEntry) -> Option<String> {
+        match (self.filesize, newer.filesize) {
+            (Some(filesize), Some(newer_filesize)) if filesize != newer_filesize => {
+                return Some(format!("its size went from {} to {} bytes", filesize, newer_filesize));
+            },
+            _ => (),
+        }
+
+        match (self.mtime_ns, newer.mtime_ns) {
+            (Some(mtime_ns), Some(newer_mtime_ns)) if mtime_ns != newer_mtime_ns => return Some("it was modified".to_string()),
+            _ => (),
+        }
+
+        match (self.fingerprint.as_ref(), newer.fingerprint.as_ref()) {
+            (Some(fingerprint), Some(newer_fingerprint)) if fingerprint != newer_fingerprint => return Some("its contents changed".to_string()),
+            _ => (),
+        }
+
+        // Older caches may have nothing but the merge keys to go by
+        if !self.ending_merge_key.is_empty() && !newer.ending_merge_key.is_empty()
+           && (self.beginning_merge_key != newer.beginning_merge_key || self.ending_merge_key != newer.ending_merge_key) {
+            return Some(format!("its merge keys went from {} - {} to {} - {}",
+                                self.beginning_merge_key, self.ending_merge_key, newer.beginning_merge_key, newer.ending_merge_key));
+        }
+
+        None
+    }
+
+    fn to_record(&self) -> Vec<String> {
+        vec![
+            self.filename.clone(),
+            self.beginning_merge_key.clone(),
+            self.ending_merge_key.clone(),
+            MergeFileManager::pretty_delimiter(self.delimiter),
+            self.key_index.to_string(),
+            self.filesize.map(|filesize| filesize.to_string()).unwrap_or_default(),
+            self.mtime_ns.map(|mtime_ns| mtime_ns.to_string()).unwrap_or_default(),
+            self.fingerprint.clone().unwrap_or_default(),
+            self.line_count.map(|line_count| line_count.to_string()).unwrap_or_default(),
+            self.byte_count.map(|byte_count| byte_count.to_string()).unwrap_or_default(),
+            self.checkpoints.iter()
+                            .map(|checkpoint| format!("{}:{}:{}", checkpoint.line_number, checkpoint.offset, checkpoint.key))
+                            .collect::<Vec<String>>()
+                            .join("\n"),
+        ]
+    }
+}
+
+/// Where each of the columns we read is in the records.
+struct ColumnIndexes {
+    filename: usize,
+    beginning_merge_key: usize,
+    ending_merge_key: usize,
+    delimiter: usize,
+    key_index: usize,
+    filesize: Option<usize>,
+    mtime_ns: Option<usize>,
+    fingerprint: Option<usize>,
+    line_count: Option<usize>,
+    byte_count: Option<usize>,
+    checkpoints: Option<usize>,
+}
+
+impl ColumnIndexes {
+    fn new<S: AsRef<str>>(columns: &[S], path: &Path) -> io::Result<ColumnIndexes> {
+        let find = |name: &str| columns.iter().position(|column| column.as_ref() == name);
+        let require = |name: &str| find(name).ok_or_else(|| Error::new(ErrorKind::InvalidData, format!("{}: The cache has no {} column", path.display(), name)));
+
+        Ok(ColumnIndexes {
+            filename: require("filename")?,
+            beginning_merge_key: require("beginning_merge_key")?,
+            ending_merge_key: require("ending_merge_key")?,
+            delimiter: require("delimiter")?,
+            key_index: require("key_index")?,
+            filesize: find("filesize"),
+            mtime_ns: find("mtime_ns"),
+            fingerprint: find("fingerprint"),
+            line_count: find("line_count"),
+            byte_count: find("byte_count"),
+            checkpoints: find("checkpoints"),
+        })
+    }
+
+    fn entry(&self, record: &[String]) -> Result<CacheEntry, String> {
+        let field = |index: usize| record.get(index).map(|field| field.as_str()).unwrap_or("");
+
+        let delimiter = MergeFileManager::parse_delimiter(field(self.delimiter)).ok_or_else(|| "the delimiter is empty".to_string())?;
+
+        let key_index = field(self.key_index).parse::<usize>().map_err(|_| format!("key_index '{}' isn't a column index", field(self.key_index)))?;
+
+        // Older caches leave these empty, or don't have the columns at all
+        let number = |column: Option<usize>, name: &str| match column.map(field) {
+            Some("") | None => Ok(None),
+            Some(number) => number.parse::<u64>().map(Some).map_err(|_| format!("{} '{}' isn't a number", name, number)),
+        };
+
+        let filesize = number(self.filesize, "filesize")?;
+        let mtime_ns = number(self.mtime_ns, "mtime_ns")?;
+        let line_count = number(self.line_count, "line_count")?;
+        let byte_count = number(self.byte_count, "byte_count")?;
+
+        let fingerprint = match self.fingerprint.map(field) {
+            Some("") | None => None,
+            Some(fingerprint) => Some(fingerprint.to_string()),
+        };
+
+        let mut checkpoints = Vec::new();
+        for checkpoint in self.checkpoints.map(field).unwrap_or("").lines() {
+            let mut parts = checkpoint.splitn(3, ':');
+            let (line_number, offset) = (parts.next().and_then(|part| part.parse::<u64>().ok()), parts.next().and_then(|part| part.parse::<u64>().ok()));
+
+            match (line_number, offset, parts.next()) {
+                (Some(line_number), Some(offset), Some(key)) => checkpoints.push(Checkpoint {
+                    key: key.to_string(),
+                    line_number,
+                    offset,
+                }),
+                _ => return Err(format!("checkpoint '{}' isn't line_number:offset:key", checkpoint)),
+            }
+        }
+
+        Ok(CacheEntry {
+            filename: field(self.filename).to_string(),
+            beginning_merge_key: field(self.beginning_merge_key).to_string(),
+            ending_merge_key: field(self.ending_merge_key).to_string(),
+            delimiter,
+            key_index,
+            filesize,
+            mtime_ns,
+            fingerprint,
+            line_count,
+            byte_count,
+            checkpoints,
+        })
+    }
+}
+
+/// How the entries of two caches differ, see `CacheFile::diff`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CacheDiff {
+    pub added: Vec<CacheEntry>,
+    pub removed: Vec<CacheEntry>,
+    /// The older and newer entries for each file that changed in between, and how it changed
+    pub changed: Vec<(CacheEntry, CacheEntry, String)>,
+}
+
+impl fmt::Display for CacheDiff {
+    /// One line per file, `+` for added, `-` for removed and `~` for changed, followed by a summary line.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for entry in &self.added {
+            writeln!(f, "+ {}", entry.filename)?;
+        }
+        for entry in &self.removed {
+            writeln!(f, "- {}", entry.filename)?;
+        }
+        for (_, newer, change) in &self.changed {
+            writeln!(f, "~ {} ({})", newer.filename, change)?;
+        }
+        writeln!(f, "{} added, {} removed and {} changed", self.added.len(), self.removed.len(), self.changed.len())
+    }
+}
+
+/// The contents of a cache file, the files available to merge and what we know about each of them.
+///
+/// Version 2 caches start with a header record (`#file-merger-cache,version=2,key_type=...`) and then a record of
+/// column names, the entries follow. Columns are found by name, so new ones can be added without a version bump as
+/// long as older readers can safely ignore them. Headerless version 1 caches are migrated as they're read, and are
+/// written back out as the current version.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CacheFile {
+    pub header: CacheHeader,
+    pub entries: Vec<CacheEntry>,
+}
+
+impl CacheFile {
+    pub fn new(key_type: KeyType, entries: Vec<CacheEntry>) -> CacheFile {
+        CacheFile {
+            header: CacheHeader::new(key_type),
+            entries,
+        }
+    }
+
+    /// Reads the cache at `path`, resolving relative filenames against `base_dir` if given,
+    /// or else the base directory recorded in the cache.
+    pub fn read(path: &Path, base_dir: Option<&Path>) -> io::Result<CacheFile> {
+        let csv_error = |error: csv::Error| Error::new(ErrorKind::InvalidData, format!("{}: {}", path.display(), error));
+
+        let mut cache_reader = csv::Reader::from_file(path).map_err(csv_error)?
+                                           .has_headers(false)
+                                           .flexible(true);
+        let mut records = cache_reader.records().enumerate();
+
+        let first_record = match records.next() {
+            Some((_, record)) => record.map_err(csv_error)?,
+            None => return Ok(CacheFile { header: CacheHeader::legacy(), entries: Vec::new() }),
+        };
+
+        let (header, columns, first_entry) = if first_record.first().map(|field| field.as_str()) == Some(CACHE_MAGIC) {
+            let header = CacheHeader::from_record(&first_record, path)?;
+            let columns = match records.next() {
+                Some((_, record)) => ColumnIndexes::new(&record.map_err(csv_error)?, path)?,
+                None => return Err(Error::new(ErrorKind::InvalidData, format!("{}: The cache ends before its column names", path.display()))),
+            };
+            (header, columns, None)
+        } else {
+            info!("{} has no header, migrating it from a version 1 cache", path.display());
+            (CacheHeader::legacy(), ColumnIndexes::new(&LEGACY_COLUMNS, path)?, Some((0, first_record)))
+        };
+
+        // An override is relative to where we're running, the recorded one to where the cache is
+        let base_dir = match base_dir {
+            Some(base_dir) => Some(base_dir.to_path_buf()),
+            None => header.base_dir.as_ref().map(|base_dir| {
+                let cache_dir = path.parent().unwrap_or_else(|| Path::new(""));
+                // Written as "." by `write`, joining that would leave "dir/./file" filenames
+                if base_dir == Path::new(".") { cache_dir.to_path_buf() } else { cache_dir.join(base_dir) }
+            }),
+        };
+
+        let mut entries = Vec::new();
+
+        for (index, record) in first_entry.into_iter().map(|(index, record)| (index, Ok(record))).chain(records) {
+            let record = record.map_err(csv_error)?;
+            let mut entry = columns.entry(&record).map_err(|problem| {
+                Error::new(ErrorKind::InvalidData, format!("{}: Invalid cache entry on line {}, {}", path.display(), index + 1, problem))
+            })?;
+
+            if header.version < 4 {
+                entry.escape_keys();
+            }
+
+            if let Some(ref base_dir) = base_dir {
+                if Path::new(&entry.filename).is_relative() {
+                    entry.filename = base_dir.join(&entry.filename).to_string_lossy().into_owned();
+                }
+            }
+
+            debug!("Cache entry: {:?}", entry);
+            entries.push(entry);
+        }
+
+        Ok(CacheFile {
+            header,
+            entries,
+        })
+    }
+
+    /// Writes the cache out in the current format version, whatever version it was read as.
+    /// It's written to a temporary file next to `path` and then renamed over it, so readers only ever see a whole cache.
+    /// Writers should hold the cache's `CacheLock::exclusive` so they don't overwrite each other's changes.
+    ///
+    /// The filenames under the cache's directory are written relative to it, with "." as the base directory,
+    /// so a cache kept alongside its data can be moved with it (and loaded with `--cache-base-dir` if it's moved alone).
+    pub fn write(&self, path: &Path) -> io::Result<()> {
+        let temp_path = CacheFile::temp_path(path);
+
+        let written = self.write_to(&temp_path).and_then(|_| fs::rename(&temp_path, path));
+        if written.is_err() {
+            let _ = fs::remove_file(&temp_path);
+        }
+
+        written.map_err(|error| Error::new(error.kind(), format!("{}: {}", path.display(), error)))
+    }
+
+    fn write_to(&self, path: &Path) -> io::Result<()> {
+        let csv_error = |error: csv::Error| match error {
+            csv::Error::Io(error) => error,
+            error => io::Error::other(error.to_string()),
+        };
+
+        let mut header = self.header.clone();
+        header.version = CACHE_FORMAT_VERSION;
+        header.base_dir = Some(PathBuf::from("."));
+
+        let current_dir = env::current_dir()?;
+        let base_dir = current_dir.join(path.parent().unwrap_or_else(|| Path::new("")));
+
+        let mut cache_writer = csv::Writer::from_file(path).map_err(csv_error)?
+                                           .flexible(true);
+
+        cache_writer.write(header.to_record().iter()).map_err(csv_error)?;
+        cache_writer.write(COLUMNS.iter()).map_err(csv_error)?;
+
+        for entry in &self.entries {
+            // Filenames under the base directory are written relative to it, any others in full
+            let filename = current_dir.join(&entry.filename);
+            let mut record = entry.to_record();
+            record[0] = filename.strip_prefix(&base_dir).unwrap_or(&filename).to_string_lossy().into_owned();
+            cache_writer.write(record.iter()).map_err(csv_error)?;
+        }
+
+        cache_writer.flush().map_err(csv_error)
+    }
+
+    /// Where a new version of the cache at `path` is written before it replaces it, eg. data.cache -> .data.cache.1234.tmp
+    fn temp_path(path: &Path) -> PathBuf {
+        let filename = path.file_name().map(|filename| filename.to_string_lossy().into_owned()).unwrap_or_default();
+        path.with_file_name(format!(".{}.{}.tmp", filename, process::id()))
+    }
+
+    /// Combines the entries of several caches, one per file sorted by filename.
+    /// A file in more than one cache keeps the entry with the newest mtime, or the one from the most recently written
+    /// cache if that doesn't settle it, or the first listed if nothing does.
+    pub fn union(caches: &[CacheFile]) -> Vec<CacheEntry> {
+        let mut newest: BTreeMap<String, (CacheEntry, (Option<u64>, u64))> = BTreeMap::new();
+
+        for cache in caches {
+            for entry in &cache.entries {
+                let age = (entry.mtime_ns, cache.header.created);
+                let is_newer = match newest.get(&entry.filename) {
+                    Some((_, newest_age)) => age > *newest_age,
+                    None => true,
+                };
+
+                if is_newer {
+                    newest.insert(entry.filename.clone(), (entry.clone(), age));
+                }
+            }
+        }
+
+        newest.into_iter().map(|(_, (entry, _))| entry).collect()
+    }
+
+    /// The entries for files `processed` doesn't have, or that changed since it recorded them.
+    pub fn subtract(&self, processed: &CacheFile) -> Vec<CacheEntry> {
+        let processed = CacheFile::by_filename(&processed.entries);
+
+        self.entries.iter()
+                    .filter(|entry| match processed.get(entry.filename.as_str()) {
+                        Some(processed_entry) => processed_entry.changes_to(entry).is_some(),
+                        None => true,
+                    })
+                    .cloned()
+                    .collect()
+    }
+
+    /// The files added to, removed from and changed in `newer` since this cache, each sorted by filename.
+    pub fn diff(&self, newer: &CacheFile) -> CacheDiff {
+        let older_entries = CacheFile::by_filename(&self.entries);
+        let newer_entries = CacheFile::by_filename(&newer.entries);
+        let mut diff = CacheDiff::default();
+
+        for entry in newer_entries.values() {
+            match older_entries.get(entry.filename.as_str()) {
+                Some(older_entry) => if let Some(change) = older_entry.changes_to(entry) {
+                    diff.changed.push(((*older_entry).clone(), (*entry).clone(), change));
+                },
+                None => diff.added.push((*entry).clone()),
+            }
+        }
+
+        diff.removed = older_entries.values()
+                                    .filter(|entry| !newer_entries.contains_key(entry.filename.as_str()))
+                                    .map(|entry| (*entry).clone())
+                                    .collect();
+
+        diff.added.sort_by(|a, b| a.filename.cmp(&b.filename));
+        diff.removed.sort_by(|a, b| a.filename.cmp(&b.filename));
+        diff.changed.sort_by(|a, b| a.1.filename.cmp(&b.1.filename));
+        diff
+    }
+
+    /// Looks entries up by filename, using the first of any listed more than once (as `retrieve_from_cache` does).
+    fn by_filename(entries: &[CacheEntry]) -> HashMap<&str, &CacheEntry> {
+        let mut by_filename = HashMap::new();
+        for entry in entries {
+            by_filename.entry(entry.filename.as_str()).or_insert(entry);
+        }
+        by_filename
+    }
+
+    /// Errors unless the cache's merge keys were read as `key_type` (or it's too old to say).
+    pub fn check_key_type(&self, key_type: &KeyType, path: &Path) -> io::Result<()> {
+        match self.header.key_type {
+            Some(ref cache_key_type) if cache_key_type != key_type => Err(Error::new(ErrorKind::InvalidData, format!(
+                "{} was built with --key-type {:?}, rebuild it or merge with --key-type {:?} instead of {:?}",
+                path.display(), cache_key_type, cache_key_type, key_type))),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::ErrorKind;
+    use std::sync::mpsc::channel;
+    use std::time::Duration;
+    use std::path::{Path, PathBuf};
+    use std::thread;
+    use std::fs;
+
+    use super::{CacheEntry, CacheFile, CacheHeader, CacheLock, CacheLockPolicy, StaleCachePolicy, CACHE_FORMAT_VERSION};
+    use merge_file::Checkpoint;
+    use settings::KeyType;
+    use test_helpers::{create_file, TempDir};
+
+    #[test]
+    fn versioned_cache_file() {
+        let dir = TempDir::new("versioned_cache_file");
+
+        let cache_path = &dir.path().join("test.cache");
+
+        let entry = CacheEntry {
+            filename: "/data/file1.tsv".to_string(),
+            beginning_merge_key: "123".to_string(),
+            ending_merge_key: "125".to_string(),
+            delimiter: '\t',
+            key_index: 2,
+            filesize: Some(36),
+            mtime_ns: Some(1500000000123456789),
+            fingerprint: Some("0123456789abcdef".to_string()),
+            line_count: Some(3),
+            byte_count: Some(36),
+            checkpoints: vec![Checkpoint { key: "124".to_string(), line_number: 2, offset: 12 }, Checkpoint { key: "1:2".to_string(), line_number: 3, offset: 24 }],
+        };
+
+        let cache = CacheFile::new(KeyType::Unsigned32Integer, vec![entry.clone()]);
+        cache.write(cache_path).unwrap();
+
+        let contents = fs::read_to_string(cache_path).unwrap();
+        assert!(contents.starts_with(&format!("#file-merger-cache,version={},key_type=Unsigned32Integer,tool_version={},created=",
+                                              CACHE_FORMAT_VERSION, env!("CARGO_PKG_VERSION"))));
+        assert!(contents.contains(",base_dir=.\n"));
+        assert!(contents.ends_with("\nfilename,beginning_merge_key,ending_merge_key,delimiter,key_index,filesize,mtime_ns,fingerprint,\
+                                    line_count,byte_count,checkpoints\n\
+                                    /data/file1.tsv,123,125,tsv,2,36,1500000000123456789,0123456789abcdef,3,36,\"2:12:124\n3:24:1:2\"\n"));
+
+        let read_back = CacheFile::read(cache_path, None).unwrap();
+        assert_eq!(read_back.entries, cache.entries);
+        assert_eq!(read_back.header, CacheHeader { base_dir: Some(PathBuf::from(".")), ..cache.header.clone() });
+        assert!(read_back.check_key_type(&KeyType::Unsigned32Integer, cache_path).is_ok());
+        assert!(read_back.check_key_type(&KeyType::String, cache_path).is_err());
+
+        // Columns are found by name, unknown header fields and columns are skipped
+        create_file(cache_path.to_str().unwrap(), "#file-merger-cache,version=2,key_type=String,future=1\n\
+                                                   filesize,filename,future,beginning_merge_key,ending_merge_key,delimiter,key_index\n\
+                                                   36,/data/file1.tsv,x,123,125,tsv,2\n");
+        assert_eq!(CacheFile::read(cache_path, None).unwrap().entries, vec![CacheEntry {
+            mtime_ns: None,
+            fingerprint: None,
+            line_count: None,
+            byte_count: None,
+            checkpoints: Vec::new(),
+            ..entry.clone()
+        }]);
+
+        // Keys used to be written as is, so are escaped when read from older caches
+        create_file(cache_path.to_str().unwrap(), "#file-merger-cache,version=3,key_type=String\n\
+                                                   filename,beginning_merge_key,ending_merge_key,delimiter,key_index,checkpoints\n\
+                                                   /data/file1.tsv,a\\b,c\\d,tsv,2,2:12:b\\c\n");
+        let legacy_entry = &CacheFile::read(cache_path, None).unwrap().entries[0];
+        assert_eq!((legacy_entry.beginning_merge_key.as_str(), legacy_entry.ending_merge_key.as_str()), ("a\\\\b", "c\\\\d"));
+        assert_eq!(legacy_entry.checkpoints[0].key, "b\\\\c");
+
+        // Caches from the future are rejected
+        create_file(cache_path.to_str().unwrap(), "#file-merger-cache,version=99,tool_version=9.0.0\nfilename\n");
+        let error = CacheFile::read(cache_path, None).unwrap_err().to_string();
+        assert!(error.contains("is a version 99 cache file written by file-merger 9.0.0"));
+    }
+
+    #[test]
+    fn relative_filenames() {
+        let dir = TempDir::new("relative_filenames");
+
+        let cache_dir = &dir.path().join("cache");
+        let moved_dir = &dir.path().join("moved");
+        fs::create_dir_all(cache_dir.join("data")).unwrap();
+        create_file(dir.join("cache/data/file1.tsv"), "1\ta\n");
+
+        let cache_path = cache_dir.join("data.cache");
+        let entry = |filename: &str| CacheEntry { filename: filename.to_string(), delimiter: '\t', ..CacheEntry::default() };
+        let cache = CacheFile::new(KeyType::String, vec![entry(&dir.join("cache/data/file1.tsv")), entry("/data/file2.tsv")]);
+        cache.write(&cache_path).unwrap();
+
+        // Only the files under the cache's directory are relative
+        let contents = fs::read_to_string(&cache_path).unwrap();
+        assert!(contents.contains(",base_dir=.\n"));
+        assert!(contents.contains("\ndata/file1.tsv,"));
+        assert!(contents.contains("\n/data/file2.tsv,"));
+        assert_eq!(CacheFile::read(&cache_path, None).unwrap().entries, cache.entries);
+
+        // Moved along with its data they follow it there, or wherever they're told they are
+        fs::rename(cache_dir, moved_dir).unwrap();
+        let moved_path = moved_dir.join("data.cache");
+        let moved = CacheFile::read(&moved_path, None).unwrap();
+        assert_eq!(moved.entries, vec![entry(&dir.join("moved/data/file1.tsv")), entry("/data/file2.tsv")]);
+        assert!(Path::new(&moved.entries[0].filename).exists());
+        let elsewhere = CacheFile::read(&moved_path, Some(Path::new("/elsewhere"))).unwrap();
+        assert_eq!(elsewhere.entries[0].filename, "/elsewhere/data/file1.tsv");
+
+        // Written again after the move it's still relative
+        moved.write(&moved_path).unwrap();
+        assert!(fs::read_to_string(&moved_path).unwrap().contains("\ndata/file1.tsv,"));
+
+        // A relative base directory is relative to the cache's directory
+        create_file(moved_path.to_str().unwrap(), "#file-merger-cache,version=3,base_dir=../data\nfilename,beginning_merge_key,ending_merge_key,delimiter,key_index\n\
+                                                   file1.tsv,123,125,tsv,0\n");
+        assert_eq!(CacheFile::read(&moved_path, None).unwrap().entries[0].filename, dir.join("moved/../data/file1.tsv"));
+
+        // Before version 3 relative filenames were left to the working directory
+        create_file(moved_path.to_str().unwrap(), "#file-merger-cache,version=2\nfilename,beginning_merge_key,ending_merge_key,delimiter,key_index\n\
+                                                   data/file1.tsv,123,125,tsv,0\n");
+        assert_eq!(CacheFile::read(&moved_path, None).unwrap().entries[0].filename, "data/file1.tsv");
+    }
+
+    #[test]
+    fn legacy_cache_file() {
+        let dir = TempDir::new("legacy_cache_file");
+
+        let cache_path = &dir.path().join("test.cache");
+        create_file(cache_path.to_str().unwrap(), "/data/file1.tsv,123,125,tsv,0,36\n/data/file2.tsv,123,,|,1,\n");
+
+        let cache = CacheFile::read(cache_path, None).unwrap();
+        assert_eq!(cache.header.version, 1);
+        assert_eq!(cache.header.key_type, None);
+        assert_eq!(cache.entries.len(), 2);
+        assert_eq!(cache.entries[1].ending_merge_key, "");
+        assert_eq!(cache.entries[1].delimiter, '|');
+        assert_eq!(cache.entries[1].filesize, None);
+
+        // Without a key type on record any key type goes
+        assert!(cache.check_key_type(&KeyType::Signed32Integer, cache_path).is_ok());
+
+        // Rewritten as the current version
+        cache.write(cache_path).unwrap();
+        let migrated = CacheFile::read(cache_path, None).unwrap();
+        assert_eq!(migrated.header.version, CACHE_FORMAT_VERSION);
+        assert_eq!(migrated.entries, cache.entries);
+
+        // A bad entry names the line it's on
+        create_file(cache_path.to_str().unwrap(), "/data/file1.tsv,123,125,tsv,0,36\n/data/file2.tsv,123,125,tsv,first,36\n");
+        let error = CacheFile::read(cache_path, None).unwrap_err().to_string();
+        assert!(error.ends_with("Invalid cache entry on line 2, key_index 'first' isn't a column index"));
+    }
+
+    #[test]
+    fn stale_entries() {
+        let dir = TempDir::new("stale_entries");
+
+        let data_path: &str = &dir.join("file1.tsv");
+        create_file(data_path, "123\tabc\n125\tdef\n");
+
+        let mut entry = CacheEntry {
+            filename: data_path.to_string(),
+            beginning_merge_key: "123".to_string(),
+            ending_merge_key: "125".to_string(),
+            delimiter: '\t',
+            ..CacheEntry::default()
+        };
+
+        // Nothing recorded, nothing to compare
+        assert_eq!(entry.changes().unwrap(), None);
+
+        entry.record_file_state().unwrap();
+        assert_eq!(entry.filesize, Some(16));
+        assert!(entry.mtime_ns.is_some());
+        assert_eq!(entry.changes().unwrap(), None);
+
+        // Same size, new contents
+        create_file(data_path, "123\tabc\n126\tdef\n");
+        entry.mtime_ns = None;
+        assert_eq!(entry.changes().unwrap(), Some("its contents changed".to_string()));
+
+        create_file(data_path, "123\tabc\n");
+        assert_eq!(entry.changes().unwrap(), Some("its size went from 16 to 8 bytes".to_string()));
+
+        let _ = fs::remove_file(data_path);
+        assert!(entry.changes().is_err());
+
+        assert_eq!("Reject".parse::<StaleCachePolicy>(), Ok(StaleCachePolicy::Reject));
+        assert!("ignore".parse::<StaleCachePolicy>().is_err());
+    }
+
+    fn set_entry(filename: &str, ending_merge_key: &str, mtime_ns: u64) -> CacheEntry {
+        CacheEntry {
+            filename: filename.to_string(),
+            beginning_merge_key: "1".to_string(),
+            ending_merge_key: ending_merge_key.to_string(),
+            delimiter: '\t',
+            filesize: Some(100),
+            mtime_ns: Some(mtime_ns),
+            ..CacheEntry::default()
+        }
+    }
+
+    fn set_cache(created: u64, entries: Vec<CacheEntry>) -> CacheFile {
+        CacheFile {
+            header: CacheHeader { created, ..CacheHeader::new(KeyType::Unsigned32Integer) },
+            entries,
+        }
+    }
+
+    /// A cache from monday and one from tuesday, when b.tsv grew
+    fn monday_and_tuesday() -> (CacheFile, CacheFile) {
+        (set_cache(1000, vec![set_entry("/data/b.tsv", "20", 10), set_entry("/data/a.tsv", "10", 10)]),
+         set_cache(2000, vec![set_entry("/data/b.tsv", "25", 20), set_entry("/data/c.tsv", "30", 20)]))
+    }
+
+    #[test]
+    fn union() {
+        let (monday, tuesday) = monday_and_tuesday();
+
+        // Sorted and deduplicated, b's newer mtime wins even from the older cache
+        let week = CacheFile::union(&[tuesday.clone(), monday.clone()]);
+        assert_eq!(week, vec![set_entry("/data/a.tsv", "10", 10), set_entry("/data/b.tsv", "25", 20), set_entry("/data/c.tsv", "30", 20)]);
+
+        // Without mtimes the more recently written cache wins
+        let mut undated = monday.clone();
+        undated.entries[0].mtime_ns = None;
+        let mut undated_later = tuesday.clone();
+        undated_later.entries[0].mtime_ns = None;
+        assert_eq!(CacheFile::union(&[undated_later, undated])[1].ending_merge_key, "25");
+
+        // As it does when the mtimes tie, whichever order the caches come in
+        let mut tied = monday.clone();
+        tied.entries[0].mtime_ns = Some(20);
+        assert_eq!(CacheFile::union(&[tuesday.clone(), tied.clone()])[1], set_entry("/data/b.tsv", "25", 20));
+        assert_eq!(CacheFile::union(&[tied, tuesday])[1], set_entry("/data/b.tsv", "25", 20));
+    }
+
+    #[test]
+    fn subtract() {
+        let (monday, tuesday) = monday_and_tuesday();
+        let week = set_cache(3000, CacheFile::union(&[monday.clone(), tuesday.clone()]));
+
+        // Files processed on monday are left out, unless they changed since
+        assert_eq!(week.subtract(&monday), vec![set_entry("/data/b.tsv", "25", 20), set_entry("/data/c.tsv", "30", 20)]);
+        assert_eq!(week.subtract(&tuesday), vec![set_entry("/data/a.tsv", "10", 10)]);
+        assert_eq!(week.subtract(&week), vec![]);
+    }
+
+    #[test]
+    fn diff() {
+        let (monday, tuesday) = monday_and_tuesday();
+
+        let diff = monday.diff(&tuesday);
+        assert_eq!(diff.added, vec![set_entry("/data/c.tsv", "30", 20)]);
+        assert_eq!(diff.removed, vec![set_entry("/data/a.tsv", "10", 10)]);
+        assert_eq!(diff.changed, vec![(set_entry("/data/b.tsv", "20", 10), set_entry("/data/b.tsv", "25", 20), "it was modified".to_string())]);
+        assert_eq!(diff.to_string(), "+ /data/c.tsv\n- /data/a.tsv\n~ /data/b.tsv (it was modified)\n1 added, 1 removed and 1 changed\n");
+
+        // Entries from older caches are compared on what little they have
+        let mut legacy = monday.clone();
+        legacy.entries[0].mtime_ns = None;
+        let mut legacy_later = tuesday.clone();
+        legacy_later.entries[0].mtime_ns = None;
+        legacy_later.entries[0].filesize = None;
+        assert_eq!(legacy.diff(&legacy_later).changed[0].2, "its merge keys went from 1 - 20 to 1 - 25");
+        assert!(monday.diff(&monday).to_string().ends_with("0 added, 0 removed and 0 changed\n"));
+    }
+
+    #[test]
+    fn cache_locks() {
+        let dir = TempDir::new("cache_locks");
+
+        let cache_path = &dir.path().join("test.cache");
+
+        // Readers share, a writer waits for them or fails
+        let reader = CacheLock::shared(cache_path, CacheLockPolicy::Fail).unwrap();
+        let other_reader = CacheLock::shared(cache_path, CacheLockPolicy::Fail).unwrap();
+        let error = CacheLock::exclusive(cache_path, CacheLockPolicy::Fail).err().unwrap();
+        assert_eq!(error.kind(), ErrorKind::WouldBlock);
+        assert_eq!(error.to_string(), format!("Another file-merger is using {}, try again once it's finished or use --cache-lock wait", cache_path.display()));
+        drop(reader);
+        drop(other_reader);
+
+        // A reader waits for the writer to finish
+        let writer = CacheLock::exclusive(cache_path, CacheLockPolicy::Fail).unwrap();
+        assert!(CacheLock::shared(cache_path, CacheLockPolicy::Fail).is_err());
+
+        let (sender, receiver) = channel();
+        let waiting_path = cache_path.clone();
+        let waiting = thread::spawn(move || {
+            let _reader = CacheLock::shared(&waiting_path, CacheLockPolicy::Wait).unwrap();
+            sender.send(()).unwrap();
+        });
+        assert!(receiver.recv_timeout(Duration::from_millis(100)).is_err());
+
+        drop(writer);
+        assert!(receiver.recv_timeout(Duration::from_secs(10)).is_ok());
+        waiting.join().unwrap();
+
+        assert_eq!("Fail".parse::<CacheLockPolicy>(), Ok(CacheLockPolicy::Fail));
+        assert!("block".parse::<CacheLockPolicy>().is_err());
+    }
+}
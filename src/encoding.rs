@@ -0,0 +1,268 @@
+use std::io::{Error, ErrorKind};
+use std::io::prelude::*;
+use std::str::FromStr;
+use std::char;
+use std::str;
+use std::io;
+
+/// How many raw bytes are decoded at a time
+const CHUNK_BYTES: usize = 64 * 1024;
+
+/// The encoding input files are read in.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum InputEncoding {
+    /// Lines are merged byte for byte, whatever their encoding (the default)
+    #[default]
+    Bytes,
+    /// Like `Bytes`, but any invalid UTF-8 is an error
+    Utf8,
+    /// ISO-8859-1, transcoded to UTF-8
+    Latin1,
+    /// UTF-16 with the byte order taken from its BOM (little endian without one), transcoded to UTF-8
+    Utf16,
+    /// UTF-16 little endian, transcoded to UTF-8
+    Utf16Le,
+    /// UTF-16 big endian, transcoded to UTF-8
+    Utf16Be,
+}
+
+impl FromStr for InputEncoding {
+    type Err = String;
+
+    fn from_str(encoding: &str) -> Result<InputEncoding, String> {
+        match encoding.trim().to_lowercase().as_ref() {
+            "bytes"                              => Ok(InputEncoding::Bytes),
+            "utf-8" | "utf8"                     => Ok(InputEncoding::Utf8),
+            "latin-1" | "latin1" | "iso-8859-1"  => Ok(InputEncoding::Latin1),
+            "utf-16" | "utf16"                   => Ok(InputEncoding::Utf16),
+            "utf-16le" | "utf16le"               => Ok(InputEncoding::Utf16Le),
+            "utf-16be" | "utf16be"               => Ok(InputEncoding::Utf16Be),
+            _ => Err(format!("Unknown input encoding '{}', expected bytes, utf-8, latin-1, utf-16, utf-16le or utf-16be", encoding)),
+        }
+    }
+}
+
+impl InputEncoding {
+    /// Wraps the (decompressed) reader so it reads as UTF-8, or returns it untouched for `Bytes`.
+    pub fn decode(self, reader: Box<dyn Read + Send>) -> Box<dyn Read + Send> {
+        match self {
+            InputEncoding::Bytes => reader,
+            encoding => Box::new(Transcoder::new(reader, encoding)),
+        }
+    }
+}
+
+/// Reads a stream in one of the `InputEncoding`s, handing it out as UTF-8.
+///
+/// Anything that can't be decoded (invalid UTF-8, an unpaired UTF-16 surrogate, a stream ending part way through a
+/// character) is an `InvalidData` error naming the byte offset into the raw stream, rather than being replaced or dropped.
+pub struct Transcoder<R: Read> {
+    inner: R,
+    encoding: InputEncoding,
+    /// Raw bytes read but not yet decoded, eg. the first half of a UTF-16 code unit
+    raw: Vec<u8>,
+    /// Offset into the raw stream of the first byte in `raw`
+    raw_offset: u64,
+    decoded: Vec<u8>,
+    decoded_position: usize,
+    hit_eof: bool,
+}
+
+impl<R: Read> Transcoder<R> {
+    pub fn new(inner: R, encoding: InputEncoding) -> Transcoder<R> {
+        Transcoder {
+            inner,
+            encoding,
+            raw: Vec::new(),
+            raw_offset: 0,
+            decoded: Vec::new(),
+            decoded_position: 0,
+            hit_eof: false,
+        }
+    }
+
+    fn invalid(&self, problem: &str) -> Error {
+        Error::new(ErrorKind::InvalidData, format!("{} at byte {} of the {:?} input", problem, self.raw_offset, self.encoding))
+    }
+
+    /// Reads the next chunk of raw bytes and decodes as much of it as possible, returning false at EOF.
+    fn fill_decoded(&mut self) -> io::Result<bool> {
+        self.decoded.clear();
+        self.decoded_position = 0;
+
+        while self.decoded.is_empty() {
+            if self.hit_eof {
+                if self.raw.is_empty() {
+                    return Ok(false);
+                }
+                return Err(self.invalid("Input ends part way through a character"));
+            }
+
+            let raw_length = self.raw.len();
+            self.raw.resize(raw_length + CHUNK_BYTES, 0);
+            let read = match self.inner.read(&mut self.raw[raw_length..]) {
+                Ok(read) => read,
+                Err(error) => {
+                    self.raw.truncate(raw_length);
+                    return Err(error);
+                },
+            };
+            self.raw.truncate(raw_length + read);
+            self.hit_eof = read == 0;
+
+            let consumed = self.decode_raw()?;
+            self.raw.drain(..consumed);
+            self.raw_offset += consumed as u64;
+        }
+
+        Ok(true)
+    }
+
+    /// Decodes the complete characters at the front of `raw` into `decoded`, returning how many raw bytes that used.
+    fn decode_raw(&mut self) -> io::Result<usize> {
+        match self.encoding {
+            InputEncoding::Bytes => {
+                self.decoded.extend_from_slice(&self.raw);
+                Ok(self.raw.len())
+            },
+            InputEncoding::Utf8 => match str::from_utf8(&self.raw) {
+                Ok(_) => {
+                    self.decoded.extend_from_slice(&self.raw);
+                    Ok(self.raw.len())
+                },
+                // Hand out what's valid first, the error comes up once the reader gets as far as it.
+                // Only the start of a character at the very end just needs the rest of it.
+                Err(error) if error.valid_up_to() > 0 || error.error_len().is_none() => {
+                    self.decoded.extend_from_slice(&self.raw[..error.valid_up_to()]);
+                    Ok(error.valid_up_to())
+                },
+                Err(_) => Err(self.invalid("Invalid UTF-8")),
+            },
+            InputEncoding::Latin1 => {
+                // Every byte is the code point of the same value
+                for &byte in &self.raw {
+                    if byte < 0x80 {
+                        self.decoded.push(byte);
+                    } else {
+                        self.decoded.push(0xc0 | (byte >> 6));
+                        self.decoded.push(0x80 | (byte & 0x3f));
+                    }
+                }
+                Ok(self.raw.len())
+            },
+            InputEncoding::Utf16 => {
+                if self.raw_offset == 0 && self.raw.len() < 2 && !self.hit_eof {
+                    return Ok(0);
+                }
+
+                // Settle the byte order from the BOM (and skip it), then carry on as that encoding
+                self.encoding = match (self.raw.first(), self.raw.get(1)) {
+                    (Some(&0xfe), Some(&0xff)) => InputEncoding::Utf16Be,
+                    _ => InputEncoding::Utf16Le,
+                };
+
+                let bom = match (self.raw.first(), self.raw.get(1)) {
+                    (Some(&0xff), Some(&0xfe)) | (Some(&0xfe), Some(&0xff)) => 2,
+                    _ => 0,
+                };
+
+                self.raw_offset += bom as u64;
+                self.raw.drain(..bom);
+                self.decode_raw()
+            },
+            InputEncoding::Utf16Le | InputEncoding::Utf16Be => {
+                let big_endian = self.encoding == InputEncoding::Utf16Be;
+                let mut units = self.raw.chunks(2).filter(|unit| unit.len() == 2).map(|unit| {
+                    if big_endian { (unit[0] as u16) << 8 | unit[1] as u16 } else { (unit[1] as u16) << 8 | unit[0] as u16 }
+                }).peekable();
+
+                let mut consumed = 0;
+                let mut encoded = [0; 4];
+                while let Some(unit) = units.next() {
+                    let code_point = match unit {
+                        0xd800..=0xdbff => match units.peek() {
+                            Some(&low @ 0xdc00..=0xdfff) => {
+                                units.next();
+                                0x10000 + (((unit as u32) - 0xd800) << 10) + ((low as u32) - 0xdc00)
+                            },
+                            // The low surrogate is in the next chunk
+                            None => break,
+                            Some(_) if consumed > 0 => break,
+                            Some(_) => return Err(self.invalid("Unpaired UTF-16 surrogate")),
+                        },
+                        0xdc00..=0xdfff if consumed > 0 => break,
+                        0xdc00..=0xdfff => return Err(self.invalid("Unpaired UTF-16 surrogate")),
+                        unit => unit as u32,
+                    };
+
+                    // Surrogates are handled above, so every code point here is a valid char
+                    let character = char::from_u32(code_point).unwrap();
+                    self.decoded.extend_from_slice(character.encode_utf8(&mut encoded).as_bytes());
+                    consumed += if code_point >= 0x10000 { 4 } else { 2 };
+                }
+
+                Ok(consumed)
+            },
+        }
+    }
+}
+
+impl<R: Read> Read for Transcoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.decoded_position == self.decoded.len() && !self.fill_decoded()? {
+            return Ok(0);
+        }
+
+        let available = &self.decoded[self.decoded_position..];
+        let length = available.len().min(buf.len());
+        buf[..length].copy_from_slice(&available[..length]);
+        self.decoded_position += length;
+
+        Ok(length)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::prelude::*;
+    use std::io::ErrorKind;
+
+    use super::{InputEncoding, Transcoder};
+
+    fn decode(encoding: InputEncoding, raw: &[u8]) -> Result<String, String> {
+        let mut decoded = String::new();
+        Transcoder::new(raw, encoding).read_to_string(&mut decoded).map_err(|error| {
+            assert_eq!(error.kind(), ErrorKind::InvalidData);
+            error.to_string()
+        })?;
+        Ok(decoded)
+    }
+
+    #[test]
+    fn transcoding() {
+        assert_eq!(decode(InputEncoding::Latin1, b"caf\xe9\tna\xefve\n"), Ok("café\tnaïve\n".to_string()));
+        assert_eq!(decode(InputEncoding::Utf8, "café\n".as_bytes()), Ok("café\n".to_string()));
+
+        // With and without a BOM, including a character outside the BMP
+        let text = "1\té😀\n2\tx\n";
+        let little_endian = text.encode_utf16().flat_map(|unit| vec![unit as u8, (unit >> 8) as u8]).collect::<Vec<u8>>();
+        let big_endian = text.encode_utf16().flat_map(|unit| vec![(unit >> 8) as u8, unit as u8]).collect::<Vec<u8>>();
+
+        assert_eq!(decode(InputEncoding::Utf16Le, &little_endian), Ok(text.to_string()));
+        assert_eq!(decode(InputEncoding::Utf16Be, &big_endian), Ok(text.to_string()));
+        assert_eq!(decode(InputEncoding::Utf16, &little_endian), Ok(text.to_string()));
+        assert_eq!(decode(InputEncoding::Utf16, &[&[0xfe, 0xff][..], &big_endian].concat()), Ok(text.to_string()));
+        assert_eq!(decode(InputEncoding::Utf16, &[&[0xff, 0xfe][..], &little_endian].concat()), Ok(text.to_string()));
+    }
+
+    #[test]
+    fn invalid_input() {
+        assert_eq!(decode(InputEncoding::Utf8, b"ok\ncaf\xe9\n"), Err("Invalid UTF-8 at byte 6 of the Utf8 input".to_string()));
+        assert_eq!(decode(InputEncoding::Utf8, b"caf\xc3"), Err("Input ends part way through a character at byte 3 of the Utf8 input".to_string()));
+        assert_eq!(decode(InputEncoding::Utf16Le, b"a\x00\x00\xdc"), Err("Unpaired UTF-16 surrogate at byte 2 of the Utf16Le input".to_string()));
+        assert_eq!(decode(InputEncoding::Utf16Le, b"a\x00b"), Err("Input ends part way through a character at byte 2 of the Utf16Le input".to_string()));
+
+        assert_eq!("UTF-16LE".parse::<InputEncoding>(), Ok(InputEncoding::Utf16Le));
+        assert!("ebcdic".parse::<InputEncoding>().is_err());
+    }
+}
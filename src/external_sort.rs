@@ -0,0 +1,294 @@
+use std::collections::HashMap;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::slice;
+use std::fmt;
+use std::mem;
+use std::fs;
+use std::io;
+
+use merge_file::{InputOptions, MergeFile, Mergeable};
+use merge_file_manager::MergeFileManager;
+use merge_output::{numbered_path, MergeOutput, OutputFile};
+use merge_sink::MergeWriter;
+
+/// Spills intermediate runs to a temp dir, for inputs that are unsorted or too many to keep open at once.
+///
+/// `sort_files` is the first half of an external merge sort. Lines are read into memory until roughly
+/// `memory_limit` bytes are buffered, that chunk is sorted on the merge key and spilled as a gzip compressed run.
+///
+/// `merge_in_passes` merges sorted files a batch at a time into runs until few enough are left to open at once,
+/// which are then handed back as regular `MergeFile`s for `MergeFileManager::begin_merge`.
+///
+/// The runs are removed once this is dropped.
+pub struct ExternalSort {
+    directory: PathBuf,
+    memory_limit: u64,
+    run_count: usize,
+    reader_threads: usize,
+    options: InputOptions,
+}
+
+impl ExternalSort {
+    /// Creates a directory of our own under `temp_dir` to spill the runs into.
+    pub fn new(temp_dir: &Path, memory_limit: u64) -> io::Result<ExternalSort> {
+        fs::create_dir_all(temp_dir)?;
+
+        let mut attempt = 0;
+        loop {
+            let directory = temp_dir.join(format!("file-merger-sort.{}.{}", process::id(), attempt));
+
+            match fs::create_dir(&directory) {
+                Ok(()) => {
+                    debug!("Spilling runs into {}", directory.display());
+                    return Ok(ExternalSort {
+                        directory,
+                        memory_limit,
+                        run_count: 0,
+                        reader_threads: 0,
+                        options: InputOptions::default(),
+                    });
+                },
+                Err(ref error) if error.kind() == io::ErrorKind::AlreadyExists => attempt += 1,
+                Err(error) => return Err(error),
+            }
+        }
+    }
+
+    /// Reads up to `reader_threads` of the inputs (and runs) ahead on background threads, see `MergeFileManager::read_ahead`.
+    pub fn read_ahead(mut self, reader_threads: usize) -> ExternalSort {
+        self.reader_threads = reader_threads;
+        self
+    }
+
+    /// How to read the input files. Runs are always written out as they're decoded.
+    pub fn input_options(mut self, options: InputOptions) -> ExternalSort {
+        self.options = options;
+        self
+    }
+
+    fn next_run_path(&mut self) -> PathBuf {
+        let run_path = numbered_path(&self.directory.join("run.gz"), self.run_count);
+        self.run_count += 1;
+        run_path
+    }
+
+    fn is_run(&self, filename: &str) -> bool {
+        Path::new(filename).starts_with(&self.directory)
+    }
+
+    /// Opens a mix of input files and runs, only applying the `InputOptions` encoding to the input files.
+    fn open_files<T>(&self, filenames: &[String], delimiter: char, key_index: usize, default_key: T) -> io::Result<HashMap<String, MergeFile<T>>>
+        where T: Mergeable, T::Err: fmt::Debug {
+        let (runs, inputs): (Vec<String>, Vec<String>) = filenames.iter().cloned().partition(|filename| self.is_run(filename));
+
+        // Runs hold the lines as they were decoded, so are split into columns the same way
+        let run_options = InputOptions { format: self.options.format, ..InputOptions::default() };
+        let mut merge_files = MergeFileManager::retrieve_from_filenames(&runs, delimiter, key_index, default_key.clone(), run_options)?;
+        merge_files.extend(MergeFileManager::retrieve_from_filenames(&inputs, delimiter, key_index, default_key, self.options)?);
+
+        MergeFileManager::read_ahead(&mut merge_files, self.reader_threads);
+
+        Ok(merge_files)
+    }
+
+    /// Reads every line of the input files (one file at a time), spilling a sorted run each time the memory limit
+    /// is reached. Returns the filenames of the sorted runs.
+    pub fn sort_files<T>(&mut self, filenames: &[String], delimiter: char, key_index: usize, default_key: T) -> io::Result<Vec<String>>
+        where T: Mergeable, T::Err: fmt::Debug {
+        // Read the inputs in a fixed order so the runs come out the same every time
+        let mut filenames = filenames.to_vec();
+        filenames.sort();
+
+        let mut runs = Vec::new();
+        let mut chunk: Vec<(T, Vec<u8>)> = Vec::new();
+        let mut chunk_bytes = 0;
+
+        for filename in &filenames {
+            // Only one input is open at a time, empty ones don't come back at all
+            let mut inputs = self.open_files(slice::from_ref(filename), delimiter, key_index, default_key.clone())?;
+            let mut merge_file = match inputs.remove(filename) {
+                Some(merge_file) => merge_file,
+                None => continue,
+            };
+
+            info!("Sorting the lines of {}", merge_file.filename);
+
+            // Each MergeFile is already positioned on its first line
+            loop {
+                let line = mem::take(&mut merge_file.line);
+                chunk_bytes += (mem::size_of::<(T, Vec<u8>)>() + line.len() + merge_file.current_merge_key.heap_size()) as u64;
+                chunk.push((merge_file.current_merge_key.clone(), line));
+
+                if chunk_bytes >= self.memory_limit {
+                    runs.push(self.spill(&mut chunk)?);
+                    chunk_bytes = 0;
+                }
+
+                if !merge_file.advance()? {
+                    break;
+                }
+            }
+        }
+
+        if !chunk.is_empty() {
+            runs.push(self.spill(&mut chunk)?);
+        }
+
+        info!("Sorted the inputs into {} runs", runs.len());
+        Ok(runs)
+    }
+
+    /// Sorts the chunk and writes it out as the next run, leaving the chunk empty.
+    fn spill<T: Mergeable>(&mut self, chunk: &mut Vec<(T, Vec<u8>)>) -> io::Result<String> {
+        // sort_by is stable, so lines with equal keys stay in the order they were read
+        chunk.sort_by(|a, b| a.0.cmp(&b.0));
+
+        let run_path = self.next_run_path();
+        let mut run = OutputFile::create(&run_path, None)?;
+
+        for (_, line) in chunk.drain(..) {
+            run.write_all(&line)?;
+            run.write_all(b"\n")?;
+        }
+
+        MergeOutput::<T>::finish(&mut run)?;
+        debug!("Spilled sorted run {}", run_path.display());
+
+        Ok(run_path.to_string_lossy().into_owned())
+    }
+
+    /// Merges the sorted files in passes, each pass merging batches of up to `max_open_files` files into a single run,
+    /// until no more than `max_open_files` are left. Those are opened and returned, ready for the final merge.
+    pub fn merge_in_passes<T>(&mut self, filenames: Vec<String>, max_open_files: usize, delimiter: char, key_index: usize, default_key: T)
+        -> io::Result<HashMap<String, MergeFile<T>>>
+        where T: Mergeable, T::Err: fmt::Debug {
+        let mut filenames = filenames;
+        filenames.sort();
+
+        let mut pass = 0;
+        while filenames.len() > max_open_files {
+            pass += 1;
+            info!("Merge pass {} over {} files, {} at a time", pass, filenames.len(), max_open_files);
+
+            let mut runs = Vec::new();
+            for batch in filenames.chunks(max_open_files) {
+                if batch.len() == 1 {
+                    runs.push(batch[0].clone());
+                    continue;
+                }
+
+                let merge_files = self.open_files(batch, delimiter, key_index, default_key.clone())?;
+
+                let run_path = self.next_run_path();
+                let mut writer = MergeWriter::new(OutputFile::create(&run_path, None)?);
+                MergeFileManager::begin_merge(merge_files, None, &mut writer)?;
+                runs.push(run_path.to_string_lossy().into_owned());
+
+                // Intermediate runs are only read once, don't let them pile up
+                for filename in batch.iter().filter(|filename| self.is_run(filename)) {
+                    fs::remove_file(filename)?;
+                }
+            }
+
+            filenames = runs;
+        }
+
+        self.open_files(&filenames, delimiter, key_index, default_key)
+    }
+}
+
+impl Drop for ExternalSort {
+    fn drop(&mut self) {
+        if let Err(error) = fs::remove_dir_all(&self.directory) {
+            warn!("Unable to remove the sorted runs in {}: {}", self.directory.display(), error);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::ExternalSort;
+    use merge_file_manager::MergeFileManager;
+    use merge_file::InputOptions;
+    use test_helpers::{create_file, TempDir};
+
+    #[test]
+    fn external_sort() {
+        let dir = TempDir::new("external_sort");
+
+        let test_filename_1: &str = &dir.join("file1.tsv");
+        let test_contents_1 = format!("{}\t{}\n\
+                                       {}\t{}\n\
+                                       {}\t{}\n\
+                                       {}\t{}\n",
+                                        "17", "aaa",
+                                        "3", "aaa",
+                                        "42", "aaa",
+                                        "8", "aaa");
+
+        create_file(test_filename_1, test_contents_1);
+
+        let test_filename_2: &str = &dir.join("file2.tsv");
+        let test_contents_2 = format!("{}\t{}\n\
+                                       {}\t{}\n\
+                                       {}\t{}\n",
+                                        "25", "bbb",
+                                        "1", "bbb",
+                                        "8", "bbb");
+
+        create_file(test_filename_2, test_contents_2);
+
+        let inputs = MergeFileManager::glob_filenames(&dir.join("file?.tsv")).unwrap();
+
+        // A tiny memory limit spills a run every couple of lines
+        let mut external_sort = ExternalSort::new(dir.path(), 64).unwrap();
+        let runs = external_sort.sort_files(&inputs, '\t', 0, 0u32).unwrap();
+        assert!(runs.len() > 1);
+
+        let runs = external_sort.merge_in_passes(runs, 16, '\t', 0, 0u32).unwrap();
+        let mut merged: Vec<String> = Vec::new();
+        MergeFileManager::begin_merge(runs, None, &mut merged).unwrap();
+
+        let keys = merged.iter().map(|line| line.split('\t').next().unwrap().parse::<u32>().unwrap()).collect::<Vec<u32>>();
+        assert_eq!(keys, vec![1, 3, 8, 8, 17, 25, 42]);
+
+        // Dropping the sort cleans up its runs
+        let run_directory = external_sort.directory.clone();
+        drop(external_sort);
+        assert!(!run_directory.exists());
+    }
+
+    #[test]
+    fn multi_pass_merge() {
+        let dir = TempDir::new("multi_pass_merge");
+
+        let filenames = (0..5).map(|i| dir.join(&format!("file{}.tsv", i))).collect::<Vec<String>>();
+        for (i, filename) in filenames.iter().enumerate() {
+            let contents = (0..4).map(|line| format!("{}\tfile{}\n", line * 5 + i, i)).collect::<String>();
+            create_file(filename, contents);
+        }
+
+        let direct = MergeFileManager::retrieve_from_glob(&dir.join("file?.tsv"), '\t', 0, 0u32, InputOptions::default()).unwrap();
+        let mut expected: Vec<String> = Vec::new();
+        MergeFileManager::begin_merge(direct, None, &mut expected).unwrap();
+
+        // Five files two at a time takes two passes (5 -> 3 -> 2)
+        let mut external_sort = ExternalSort::new(dir.path(), 1 << 20).unwrap();
+        let runs = external_sort.merge_in_passes(filenames.clone(), 2, '\t', 0, 0u32).unwrap();
+        assert_eq!(runs.len(), 2);
+
+        // Intermediate runs are removed once merged, only those still to be merged are left on disk
+        let runs_on_disk = runs.keys().filter(|filename| external_sort.is_run(filename)).count();
+        assert_eq!(fs::read_dir(&external_sort.directory).unwrap().count(), runs_on_disk);
+
+        let mut merged: Vec<String> = Vec::new();
+        MergeFileManager::begin_merge(runs, None, &mut merged).unwrap();
+        assert_eq!(merged.len(), 20);
+        assert_eq!(merged, expected);
+
+    }
+}
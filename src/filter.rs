@@ -0,0 +1,331 @@
+use std::str::FromStr;
+use std::str;
+use std::io;
+
+use merge_file::MergeFile;
+use merge_sink::MergeSink;
+
+/// How a column is compared against a literal.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Comparison {
+    Equal,
+    NotEqual,
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+}
+
+/// The literal a column is compared against.
+/// Numbers compare numerically (a non-numeric column never matches), strings compare bytewise.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Literal {
+    Number(f64),
+    Text(String),
+}
+
+/// A compiled row predicate, eg. `col[4] == "US" && (col[6] > 100 || !col[2] == "")`.
+///
+/// The expression is parsed once up front, evaluating it borrows the columns straight out
+/// of the `MergeFile`'s current line so no allocation happens per line.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Predicate {
+    Compare(usize, Comparison, Literal),
+    Not(Box<Predicate>),
+    And(Box<Predicate>, Box<Predicate>),
+    Or(Box<Predicate>, Box<Predicate>),
+}
+
+impl Predicate {
+    /// Returns true if the `MergeFile`'s current line satisfies the predicate.
+    pub fn matches<T>(&self, merge_file: &MergeFile<T>) -> bool {
+        match *self {
+            Predicate::Compare(column, comparison, ref literal) => {
+                match merge_file.column(column) {
+                    Some(field) => compare(&field, comparison, literal),
+                    None => false,
+                }
+            },
+            Predicate::Not(ref predicate) => !predicate.matches(merge_file),
+            Predicate::And(ref left, ref right) => left.matches(merge_file) && right.matches(merge_file),
+            Predicate::Or(ref left, ref right) => left.matches(merge_file) || right.matches(merge_file),
+        }
+    }
+}
+
+fn compare(field: &[u8], comparison: Comparison, literal: &Literal) -> bool {
+    let ordering = match *literal {
+        Literal::Number(number) => match parse_number(field).and_then(|field| field.partial_cmp(&number)) {
+            Some(ordering) => ordering,
+            None => return false,
+        },
+        Literal::Text(ref text) => field.cmp(text.as_bytes()),
+    };
+
+    match comparison {
+        Comparison::Equal => ordering.is_eq(),
+        Comparison::NotEqual => ordering.is_ne(),
+        Comparison::Less => ordering.is_lt(),
+        Comparison::LessOrEqual => ordering.is_le(),
+        Comparison::Greater => ordering.is_gt(),
+        Comparison::GreaterOrEqual => ordering.is_ge(),
+    }
+}
+
+/// Parses a (whitespace padded) number out of a field's bytes.
+pub fn parse_number(field: &[u8]) -> Option<f64> {
+    str::from_utf8(field).ok().and_then(|field| field.trim().parse::<f64>().ok())
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Column(usize),
+    Comparison(Comparison),
+    Literal(Literal),
+    And,
+    Or,
+    Not,
+    Open,
+    Close,
+}
+
+fn tokenize(expression: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = expression.char_indices().peekable();
+
+    while let Some((position, c)) = chars.next() {
+        let token = match c {
+            ' ' | '\t' => continue,
+            '(' => Token::Open,
+            ')' => Token::Close,
+            '&' | '|' => {
+                if chars.next().map(|(_, next)| next) != Some(c) {
+                    return Err(format!("Expected '{}{}' at position {}", c, c, position));
+                }
+                if c == '&' { Token::And } else { Token::Or }
+            },
+            '=' | '!' | '<' | '>' => {
+                let followed_by_equals = chars.peek().map(|&(_, next)| next) == Some('=');
+                if followed_by_equals {
+                    chars.next();
+                }
+
+                match (c, followed_by_equals) {
+                    ('=', true) => Token::Comparison(Comparison::Equal),
+                    ('!', true) => Token::Comparison(Comparison::NotEqual),
+                    ('<', true) => Token::Comparison(Comparison::LessOrEqual),
+                    ('>', true) => Token::Comparison(Comparison::GreaterOrEqual),
+                    ('<', false) => Token::Comparison(Comparison::Less),
+                    ('>', false) => Token::Comparison(Comparison::Greater),
+                    ('!', false) => Token::Not,
+                    _ => return Err(format!("Expected '==' at position {}", position)),
+                }
+            },
+            '"' => {
+                let mut text = String::new();
+                loop {
+                    match chars.next() {
+                        Some((_, '"')) => break,
+                        Some((_, '\\')) => match chars.next() {
+                            Some((_, escaped)) => text.push(escaped),
+                            None => return Err("Unterminated string in filter".to_string()),
+                        },
+                        Some((_, c)) => text.push(c),
+                        None => return Err("Unterminated string in filter".to_string()),
+                    }
+                }
+                Token::Literal(Literal::Text(text))
+            },
+            _ => {
+                // Either a number or a col[N] reference, read up until the next separator
+                let mut word = c.to_string();
+                while let Some(&(_, next)) = chars.peek() {
+                    if next.is_whitespace() || "()=!<>&|\"".contains(next) {
+                        break;
+                    }
+                    word.push(next);
+                    chars.next();
+                }
+
+                if word.starts_with("col[") && word.ends_with(']') {
+                    match word[4..word.len() - 1].parse::<usize>() {
+                        Ok(column) => Token::Column(column),
+                        Err(_) => return Err(format!("Invalid column reference '{}'", word)),
+                    }
+                } else {
+                    match word.parse::<f64>() {
+                        Ok(number) => Token::Literal(Literal::Number(number)),
+                        Err(_) => return Err(format!("Unexpected '{}' at position {}, strings need to be quoted", word, position)),
+                    }
+                }
+            },
+        };
+
+        tokens.push(token);
+    }
+
+    Ok(tokens)
+}
+
+/// A recursive descent parser over the tokens, lowest precedence first: `||`, `&&`, `!`, comparisons.
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+}
+
+impl Parser {
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn parse_or(&mut self) -> Result<Predicate, String> {
+        let mut predicate = self.parse_and()?;
+        while self.peek() == Some(&Token::Or) {
+            self.next();
+            predicate = Predicate::Or(Box::new(predicate), Box::new(self.parse_and()?));
+        }
+        Ok(predicate)
+    }
+
+    fn parse_and(&mut self) -> Result<Predicate, String> {
+        let mut predicate = self.parse_unary()?;
+        while self.peek() == Some(&Token::And) {
+            self.next();
+            predicate = Predicate::And(Box::new(predicate), Box::new(self.parse_unary()?));
+        }
+        Ok(predicate)
+    }
+
+    fn parse_unary(&mut self) -> Result<Predicate, String> {
+        match self.next() {
+            Some(Token::Not) => Ok(Predicate::Not(Box::new(self.parse_unary()?))),
+            Some(Token::Open) => {
+                let predicate = self.parse_or()?;
+                match self.next() {
+                    Some(Token::Close) => Ok(predicate),
+                    _ => Err("Missing closing ')' in filter".to_string()),
+                }
+            },
+            Some(Token::Column(column)) => {
+                match (self.next(), self.next()) {
+                    (Some(Token::Comparison(comparison)), Some(Token::Literal(literal))) => {
+                        Ok(Predicate::Compare(column, comparison, literal))
+                    },
+                    _ => Err(format!("Expected a comparison like col[{}] == \"value\"", column)),
+                }
+            },
+            Some(token) => Err(format!("Unexpected {:?} in filter", token)),
+            None => Err("Filter ended unexpectedly".to_string()),
+        }
+    }
+}
+
+impl FromStr for Predicate {
+    type Err = String;
+
+    fn from_str(expression: &str) -> Result<Predicate, String> {
+        let mut parser = Parser {
+            tokens: tokenize(expression)?,
+            position: 0,
+        };
+
+        let predicate = parser.parse_or()?;
+
+        match parser.peek() {
+            Some(token) => Err(format!("Unexpected {:?} at the end of the filter", token)),
+            None => Ok(predicate),
+        }
+    }
+}
+
+/// A `MergeSink` that only passes lines matching a `Predicate` through to the wrapped sink.
+pub struct FilteredSink<S> {
+    predicate: Predicate,
+    sink: S,
+}
+
+impl<S> FilteredSink<S> {
+    pub fn new(predicate: Predicate, sink: S) -> FilteredSink<S> {
+        FilteredSink {
+            predicate,
+            sink,
+        }
+    }
+}
+
+impl<T, S: MergeSink<T>> MergeSink<T> for FilteredSink<S> {
+    fn write_line(&mut self, merge_file: &MergeFile<T>) -> io::Result<()> {
+        if self.predicate.matches(merge_file) {
+            self.sink.write_line(merge_file)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        self.sink.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Comparison, Literal, Predicate, FilteredSink};
+    use merge_file_manager::MergeFileManager;
+    use merge_file::InputOptions;
+    use test_helpers::{create_file, TempDir};
+
+    #[test]
+    fn parse_predicate() {
+        assert_eq!("col[4] == \"US\"".parse::<Predicate>(),
+                   Ok(Predicate::Compare(4, Comparison::Equal, Literal::Text("US".to_string()))));
+
+        assert_eq!("!col[1]>=2.5 || col[0]<\"a\" && col[2] != -1".parse::<Predicate>(),
+                   Ok(Predicate::Or(
+                       Box::new(Predicate::Not(Box::new(Predicate::Compare(1, Comparison::GreaterOrEqual, Literal::Number(2.5))))),
+                       Box::new(Predicate::And(
+                           Box::new(Predicate::Compare(0, Comparison::Less, Literal::Text("a".to_string()))),
+                           Box::new(Predicate::Compare(2, Comparison::NotEqual, Literal::Number(-1.0))))))));
+
+        assert!("col[4] == US".parse::<Predicate>().is_err());
+        assert!("col[4] = \"US\"".parse::<Predicate>().is_err());
+        assert!("(col[4] == 1".parse::<Predicate>().is_err());
+        assert!("col[4] == 1 col[5] == 2".parse::<Predicate>().is_err());
+    }
+
+    #[test]
+    fn filtered_merge() {
+        let dir = TempDir::new("filtered_merge");
+
+        let test_filename_1: &str = &dir.join("file1.tsv");
+        let test_contents_1 = format!("{}\t{}\t{}\n\
+                                       {}\t{}\t{}\n\
+                                       {}\t{}\t{}\n",
+                                        "123", "US", "50",
+                                        "124", "AU", "150",
+                                        "125", "US", "150");
+
+        create_file(test_filename_1, test_contents_1);
+
+        let test_filename_2: &str = &dir.join("file2.tsv");
+        let test_contents_2 = format!("{}\t{}\t{}\n\
+                                       {}\t{}\t{}\n",
+                                        "123", "US", "500",
+                                        "126", "US", "n/a");
+
+        create_file(test_filename_2, test_contents_2);
+
+        let cache = MergeFileManager::retrieve_from_glob(&dir.join("file?.tsv"), '\t', 0, "0".to_string(), InputOptions::default()).unwrap();
+
+        let predicate = "col[1] == \"US\" && col[2] > 100".parse::<Predicate>().unwrap();
+        let mut sink = FilteredSink::new(predicate, Vec::new());
+        MergeFileManager::begin_merge(cache, None, &mut sink).unwrap();
+
+        assert_eq!(sink.sink, vec!["123\tUS\t500".to_string(), "125\tUS\t150".to_string()]);
+    }
+}
@@ -0,0 +1,351 @@
+use std::borrow::Cow;
+use std::str::FromStr;
+
+/// How each line of an input is split into its columns.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum InputFormat {
+    /// Columns are separated by the delimiter, nothing is quoted (the default)
+    #[default]
+    Delimited,
+    /// Columns are separated by the delimiter, and any column can be wrapped in double quotes to hold the delimiter,
+    /// with a double quote inside one written as two. A quoted column can't span lines.
+    Csv,
+    /// Each line is a JSON object, its columns are the object's top level members in the order they're written
+    JsonLines,
+}
+
+impl FromStr for InputFormat {
+    type Err = String;
+
+    fn from_str(format: &str) -> Result<InputFormat, String> {
+        match format.trim().to_lowercase().as_ref() {
+            "delimited"             => Ok(InputFormat::Delimited),
+            "csv"                   => Ok(InputFormat::Csv),
+            "jsonl" | "json-lines"  => Ok(InputFormat::JsonLines),
+            _ => Err(format!("Unknown input format '{}', expected delimited, csv or jsonl", format)),
+        }
+    }
+}
+
+impl InputFormat {
+    /// Splits a line into its columns, each as it's written in the line (still quoted, or for JSON Lines the whole
+    /// `"name": value` member). The delimiter isn't used for JSON Lines.
+    pub fn fields<'a, 'd>(self, line: &'a [u8], delimiter: &'d [u8]) -> Fields<'a, 'd> {
+        let remaining = match self {
+            InputFormat::JsonLines => {
+                // Step inside the object, anything else has no members
+                let line = skip_whitespace(line);
+                if line.first() == Some(&b'{') { Some(&line[1..]) } else { None }
+            },
+            _ => Some(line),
+        };
+
+        Fields {
+            remaining,
+            delimiter,
+            format: self,
+        }
+    }
+
+    /// The value a column written as `field` holds, see `fields`. CSV columns are unquoted, JSON Lines members are
+    /// cut down to their value with strings unescaped (anything else, like a number, is left as written).
+    /// Only a value that needs unescaping is copied.
+    pub fn value(self, field: &[u8]) -> Cow<'_, [u8]> {
+        match self {
+            InputFormat::Delimited => Cow::Borrowed(field),
+            InputFormat::Csv => {
+                if field.len() < 2 || field[0] != b'"' || field[field.len() - 1] != b'"' {
+                    return Cow::Borrowed(field);
+                }
+
+                let quoted = &field[1..field.len() - 1];
+                if !quoted.contains(&b'"') {
+                    return Cow::Borrowed(quoted);
+                }
+
+                let mut value = Vec::with_capacity(quoted.len());
+                let mut bytes = quoted.iter();
+                while let Some(&byte) = bytes.next() {
+                    value.push(byte);
+                    if byte == b'"' {
+                        bytes.next();
+                    }
+                }
+                Cow::Owned(value)
+            },
+            InputFormat::JsonLines => {
+                let value = skip_whitespace(&field[json_value_end(field)..]);
+                let value = match value.first() {
+                    Some(&b':') => skip_whitespace(&value[1..]),
+                    _ => return Cow::Borrowed(b""),
+                };
+
+                if value.first() == Some(&b'"') {
+                    let end = json_value_end(value);
+                    unescape_json(&value[1..end.saturating_sub(1).max(1)])
+                } else {
+                    Cow::Borrowed(value)
+                }
+            },
+        }
+    }
+
+    /// The value of the column at `index` of the line, see `value`.
+    pub fn column<'a>(self, line: &'a [u8], delimiter: &[u8], index: usize) -> Option<Cow<'a, [u8]>> {
+        self.fields(line, delimiter).nth(index).map(|field| self.value(field))
+    }
+}
+
+/// Returns where the (possibly multibyte) delimiter first appears in the bytes.
+pub fn find_delimiter(bytes: &[u8], delimiter: &[u8]) -> Option<usize> {
+    if delimiter.len() == 1 {
+        bytes.iter().position(|byte| *byte == delimiter[0])
+    } else {
+        bytes.windows(delimiter.len()).position(|window| window == delimiter)
+    }
+}
+
+/// Splits a line's bytes into its fields, see `InputFormat::fields`.
+pub struct Fields<'a, 'd> {
+    remaining: Option<&'a [u8]>,
+    delimiter: &'d [u8],
+    format: InputFormat,
+}
+
+impl<'a, 'd> Iterator for Fields<'a, 'd> {
+    type Item = &'a [u8];
+
+    fn next(&mut self) -> Option<&'a [u8]> {
+        let remaining = self.remaining?;
+
+        let (field_end, next_field) = match self.format {
+            InputFormat::Delimited => match find_delimiter(remaining, self.delimiter) {
+                Some(position) => (position, Some(position + self.delimiter.len())),
+                None => (remaining.len(), None),
+            },
+            InputFormat::Csv => {
+                // A quoted field runs on to the delimiter after its closing quote
+                let quoted_end = if remaining.first() == Some(&b'"') { csv_quoted_end(remaining) } else { 0 };
+
+                match find_delimiter(&remaining[quoted_end..], self.delimiter) {
+                    Some(position) => (quoted_end + position, Some(quoted_end + position + self.delimiter.len())),
+                    None => (remaining.len(), None),
+                }
+            },
+            InputFormat::JsonLines => {
+                let member = skip_whitespace(remaining);
+                if member.is_empty() || member[0] == b'}' {
+                    self.remaining = None;
+                    return None;
+                }
+
+                // "name" : value
+                let mut end = json_value_end(member);
+                let after_name = skip_whitespace(&member[end..]);
+                if after_name.first() == Some(&b':') {
+                    let value_start = member.len() - skip_whitespace(&after_name[1..]).len();
+                    end = value_start + json_value_end(&member[value_start..]);
+                }
+
+                let after_value = skip_whitespace(&member[end..]);
+                self.remaining = match after_value.first() {
+                    Some(&b',') => Some(&after_value[1..]),
+                    _ => None,
+                };
+                return Some(&member[..end]);
+            },
+        };
+
+        self.remaining = next_field.map(|next_field| &remaining[next_field..]);
+        Some(&remaining[..field_end])
+    }
+}
+
+/// Where the quoted CSV field at the start of the bytes ends, just past its closing quote (or the end of the bytes).
+fn csv_quoted_end(bytes: &[u8]) -> usize {
+    let mut position = 1;
+    while position < bytes.len() {
+        if bytes[position] == b'"' {
+            if bytes.get(position + 1) != Some(&b'"') {
+                return position + 1;
+            }
+            position += 1;
+        }
+        position += 1;
+    }
+    bytes.len()
+}
+
+fn skip_whitespace(bytes: &[u8]) -> &[u8] {
+    let start = bytes.iter().position(|byte| !byte.is_ascii_whitespace()).unwrap_or(bytes.len());
+    &bytes[start..]
+}
+
+/// Where the JSON value at the start of the bytes ends. Strings run to their closing quote, objects and arrays to
+/// their closing bracket, anything else up to the next `,`, `}`, `]` or whitespace.
+fn json_value_end(bytes: &[u8]) -> usize {
+    let mut depth = 0;
+    let mut in_string = false;
+    let mut escaped = false;
+
+    for (position, &byte) in bytes.iter().enumerate() {
+        if in_string {
+            if escaped {
+                escaped = false;
+            } else if byte == b'\\' {
+                escaped = true;
+            } else if byte == b'"' {
+                in_string = false;
+                if depth == 0 {
+                    return position + 1;
+                }
+            }
+            continue;
+        }
+
+        match byte {
+            b'"' => in_string = true,
+            b'{' | b'[' => depth += 1,
+            b'}' | b']' if depth > 0 => {
+                depth -= 1;
+                if depth == 0 {
+                    return position + 1;
+                }
+            },
+            b',' | b'}' | b']' if depth == 0 => return position,
+            byte if depth == 0 && byte.is_ascii_whitespace() => return position,
+            _ => {},
+        }
+    }
+
+    bytes.len()
+}
+
+/// Unescapes the inside of a JSON string, leaving any escape it doesn't understand as written.
+fn unescape_json(string: &[u8]) -> Cow<'_, [u8]> {
+    if !string.contains(&b'\\') {
+        return Cow::Borrowed(string);
+    }
+
+    let mut value = Vec::with_capacity(string.len());
+    let mut position = 0;
+
+    while position < string.len() {
+        let byte = string[position];
+        position += 1;
+
+        if byte != b'\\' || position == string.len() {
+            value.push(byte);
+            continue;
+        }
+
+        let escape = string[position];
+        position += 1;
+
+        match escape {
+            b'"' | b'\\' | b'/' => value.push(escape),
+            b'b' => value.push(0x08),
+            b'f' => value.push(0x0c),
+            b'n' => value.push(b'\n'),
+            b'r' => value.push(b'\r'),
+            b't' => value.push(b'\t'),
+            b'u' => {
+                let (character, length) = json_unicode_escape(&string[position..]);
+                match character {
+                    Some(character) => {
+                        let mut encoded = [0; 4];
+                        value.extend_from_slice(character.encode_utf8(&mut encoded).as_bytes());
+                        position += length;
+                    },
+                    None => value.extend_from_slice(b"\\u"),
+                }
+            },
+            _ => {
+                value.push(b'\\');
+                value.push(escape);
+            },
+        }
+    }
+
+    Cow::Owned(value)
+}
+
+/// Decodes the hex digits after a `\u`, along with the low surrogate's `\uXXXX` if it's a high surrogate.
+/// Returns the character and how many bytes it took, or None if it isn't valid.
+fn json_unicode_escape(bytes: &[u8]) -> (Option<char>, usize) {
+    let hex = |bytes: &[u8]| {
+        bytes.get(..4)
+             .and_then(|digits| ::std::str::from_utf8(digits).ok())
+             .and_then(|digits| u32::from_str_radix(digits, 16).ok())
+    };
+
+    match hex(bytes) {
+        Some(high) if (0xd800..0xdc00).contains(&high) => {
+            match (bytes.get(4..6), hex(&bytes[bytes.len().min(6)..])) {
+                (Some(b"\\u"), Some(low)) if (0xdc00..0xe000).contains(&low) => {
+                    (::std::char::from_u32(0x10000 + ((high - 0xd800) << 10) + (low - 0xdc00)), 10)
+                },
+                _ => (None, 0),
+            }
+        },
+        Some(code) => (::std::char::from_u32(code), 4),
+        None => (None, 0),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::InputFormat;
+
+    fn fields(format: InputFormat, line: &str) -> Vec<String> {
+        format.fields(line.as_bytes(), b",").map(|field| String::from_utf8_lossy(field).into_owned()).collect()
+    }
+
+    fn column(format: InputFormat, line: &str, index: usize) -> Option<String> {
+        format.column(line.as_bytes(), b",", index).map(|value| String::from_utf8_lossy(&value[..]).into_owned())
+    }
+
+    #[test]
+    fn delimited() {
+        assert_eq!(fields(InputFormat::Delimited, "a,\"b,c\",d"), vec!["a", "\"b", "c\"", "d"]);
+        assert_eq!(fields(InputFormat::Delimited, ""), vec![""]);
+        assert_eq!(column(InputFormat::Delimited, "a,\"b\"", 1), Some("\"b\"".to_string()));
+    }
+
+    #[test]
+    fn csv() {
+        let line = "123,\"Smith, John\",\"say \"\"hi\"\"\",,\"unterminated, quote";
+        assert_eq!(fields(InputFormat::Csv, line), vec!["123", "\"Smith, John\"", "\"say \"\"hi\"\"\"", "", "\"unterminated, quote"]);
+        assert_eq!(column(InputFormat::Csv, line, 0), Some("123".to_string()));
+        assert_eq!(column(InputFormat::Csv, line, 1), Some("Smith, John".to_string()));
+        assert_eq!(column(InputFormat::Csv, line, 2), Some("say \"hi\"".to_string()));
+        assert_eq!(column(InputFormat::Csv, line, 3), Some("".to_string()));
+        assert_eq!(column(InputFormat::Csv, line, 5), None);
+
+        // A quote part way through a field is just a character
+        assert_eq!(fields(InputFormat::Csv, "a\"b,c"), vec!["a\"b", "c"]);
+        assert_eq!(fields(InputFormat::Csv, "\"a\"b,c"), vec!["\"a\"b", "c"]);
+    }
+
+    #[test]
+    fn json_lines() {
+        let line = r#" { "id": 123, "name" : "Smith, \"John\"", "tags": ["a", {"b": "}"}], "ok":true,"none":null } "#;
+        assert_eq!(fields(InputFormat::JsonLines, line), vec![r#""id": 123"#, r#""name" : "Smith, \"John\"""#,
+                                                             r#""tags": ["a", {"b": "}"}]"#, r#""ok":true"#, r#""none":null"#]);
+        assert_eq!(column(InputFormat::JsonLines, line, 0), Some("123".to_string()));
+        assert_eq!(column(InputFormat::JsonLines, line, 1), Some("Smith, \"John\"".to_string()));
+        assert_eq!(column(InputFormat::JsonLines, line, 2), Some(r#"["a", {"b": "}"}]"#.to_string()));
+        assert_eq!(column(InputFormat::JsonLines, line, 3), Some("true".to_string()));
+        assert_eq!(column(InputFormat::JsonLines, line, 5), None);
+
+        assert_eq!(column(InputFormat::JsonLines, r#"{"a":"tab\there é 😀 \/ \q"}"#, 0), Some("tab\there é 😀 / \\q".to_string()));
+        assert_eq!(column(InputFormat::JsonLines, r#"{"a":"\u00e9\ud83d\ude00\ud83d"}"#, 0), Some("é😀\\ud83d".to_string()));
+        assert_eq!(fields(InputFormat::JsonLines, "{}"), Vec::<String>::new());
+        assert_eq!(fields(InputFormat::JsonLines, "[1, 2]"), Vec::<String>::new());
+        assert_eq!(fields(InputFormat::JsonLines, "not json"), Vec::<String>::new());
+
+        assert_eq!("jsonl".parse::<InputFormat>(), Ok(InputFormat::JsonLines));
+        assert_eq!("CSV".parse::<InputFormat>(), Ok(InputFormat::Csv));
+        assert!("tsv".parse::<InputFormat>().is_err());
+    }
+}
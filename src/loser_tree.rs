@@ -0,0 +1,219 @@
+use std::cmp::Ordering;
+use std::mem;
+
+/// A tournament (loser) tree over k players, always able to name the lowest one.
+///
+/// Each internal node remembers the loser of the match played there and the overall winner sits at the root,
+/// so when the winner changes (eg. its `MergeFile` moves onto the next line) it only has to replay the matches
+/// on its way back up: one comparison per level, log k in total. A `BinaryHeap` pop and push makes a few more
+/// comparisons (see `benches/loser_tree.rs`), but keeps its players inline where the tree looks each loser up by index,
+/// so it only pays off once comparing keys costs more than that extra load (eg. string keys).
+///
+/// Players that have been removed sort after everyone else, ties go to the player that was added first.
+pub struct LoserTree<T> {
+    players: Vec<Option<T>>,
+    losers: Vec<usize>,
+}
+
+impl<T: Ord> LoserTree<T> {
+    pub fn new(players: Vec<T>) -> LoserTree<T> {
+        let player_count = players.len();
+        let mut tree = LoserTree {
+            players: players.into_iter().map(Some).collect(),
+            losers: vec![0; player_count.max(1)],
+        };
+
+        // Play every match bottom up, player i starts out at node player_count + i
+        let mut winners = vec![0; player_count * 2];
+        for player in 0..player_count {
+            winners[player_count + player] = player;
+        }
+
+        for node in (1..player_count).rev() {
+            let (left, right) = (winners[node * 2], winners[node * 2 + 1]);
+            if tree.beats(left, right) {
+                winners[node] = left;
+                tree.losers[node] = right;
+            } else {
+                winners[node] = right;
+                tree.losers[node] = left;
+            }
+        }
+
+        if player_count > 1 {
+            tree.losers[0] = winners[1];
+        }
+
+        tree
+    }
+
+    /// Returns true if player `a` wins its match against player `b`.
+    fn beats(&self, a: usize, b: usize) -> bool {
+        match (&self.players[a], &self.players[b]) {
+            (Some(a_player), Some(b_player)) => {
+                // Non short-circuiting, the outcome is unpredictable so this avoids a branch per level
+                let ordering = a_player.cmp(b_player);
+                (ordering == Ordering::Less) | ((ordering == Ordering::Equal) & (a < b))
+            },
+            (Some(_), None) => true,
+            (None, _) => false,
+        }
+    }
+
+    /// Replays the matches from the winner's leaf back up to the root, after the winner has changed.
+    fn replay_winner(&mut self) {
+        let mut winner = self.losers[0];
+        let mut node = (winner + self.players.len()) / 2;
+
+        while node > 0 {
+            if self.beats(self.losers[node], winner) {
+                mem::swap(&mut self.losers[node], &mut winner);
+            }
+            node /= 2;
+        }
+
+        self.losers[0] = winner;
+    }
+
+    /// The lowest remaining player, if any are left.
+    pub fn winner(&self) -> Option<&T> {
+        self.players.get(self.losers[0]).and_then(|player| player.as_ref())
+    }
+
+    /// Updates the winner in place (eg. advancing it), then replays its matches to find the new winner.
+    /// If `update` returns false the winner is done (eg. it hit EOF), so it's removed and returned instead.
+    pub fn update_winner<F: FnOnce(&mut T) -> bool>(&mut self, update: F) -> Option<T> {
+        let removed = match self.players.get_mut(self.losers[0]) {
+            Some(winner) => match winner.as_mut().map(update) {
+                Some(false) => winner.take(),
+                _ => None,
+            },
+            None => return None,
+        };
+
+        self.replay_winner();
+        removed
+    }
+
+    /// Removes the winner from the tournament, returning it.
+    pub fn remove_winner(&mut self) -> Option<T> {
+        let winner = match self.players.get_mut(self.losers[0]) {
+            Some(winner) => winner.take(),
+            None => return None,
+        };
+
+        self.replay_winner();
+        winner
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cmp::Ordering;
+
+    use super::LoserTree;
+
+    /// A deterministic xorshift generator, good enough for test data
+    fn random_numbers(seed: u64, count: usize) -> Vec<u64> {
+        let mut state = seed | 1;
+        (0..count).map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state % 100000
+        }).collect()
+    }
+
+    /// Splits the keys into k sorted runs
+    fn sorted_runs<K: Ord + Clone>(keys: &[K], k: usize) -> Vec<Vec<K>> {
+        let mut runs = vec![Vec::new(); k];
+        for (i, key) in keys.iter().enumerate() {
+            runs[i % k].push(key.clone());
+        }
+        for run in runs.iter_mut() {
+            run.sort();
+        }
+        runs
+    }
+
+    /// A run positioned on its current key, ordered by that key
+    #[derive(PartialEq, Eq)]
+    struct Cursor<K> {
+        key: K,
+        position: usize,
+        run: usize,
+    }
+
+    impl<K: Ord> Ord for Cursor<K> {
+        fn cmp(&self, other: &Cursor<K>) -> Ordering {
+            self.key.cmp(&other.key)
+        }
+    }
+
+    impl<K: Ord> PartialOrd for Cursor<K> {
+        fn partial_cmp(&self, other: &Cursor<K>) -> Option<Ordering> {
+            Some(self.cmp(other))
+        }
+    }
+
+    fn cursors<K: Ord + Clone>(runs: &[Vec<K>]) -> Vec<Cursor<K>> {
+        runs.iter().enumerate()
+            .filter(|&(_, run)| !run.is_empty())
+            .map(|(run_index, run)| Cursor { key: run[0].clone(), position: 0, run: run_index })
+            .collect()
+    }
+
+    fn loser_tree_merge<K: Ord + Clone>(runs: &[Vec<K>]) -> Vec<K> {
+        let mut merged = Vec::new();
+        let mut tree = LoserTree::new(cursors(runs));
+
+        while let Some(cursor) = tree.winner() {
+            merged.push(cursor.key.clone());
+
+            tree.update_winner(|cursor| {
+                cursor.position += 1;
+                match runs[cursor.run].get(cursor.position) {
+                    Some(key) => {
+                        cursor.key.clone_from(key);
+                        true
+                    },
+                    None => false,
+                }
+            });
+        }
+
+        merged
+    }
+
+    #[test]
+    fn loser_tree() {
+        let numbers = random_numbers(42, 1000);
+        let mut expected = numbers.clone();
+        expected.sort();
+
+        // Includes an odd number of players, one player, and more players than keys (some start out empty)
+        for k in &[1, 2, 3, 7, 64, 1500] {
+            assert_eq!(loser_tree_merge(&sorted_runs(&numbers, *k)), expected);
+        }
+
+        assert!(LoserTree::<u64>::new(Vec::new()).winner().is_none());
+
+        // Ties go to the earliest player
+        let mut tree = LoserTree::new(vec![1, 0, 0].into_iter().enumerate().map(|(run, key)| Cursor { key, position: 0, run }).collect());
+        assert_eq!(tree.remove_winner().unwrap().run, 1);
+        assert_eq!(tree.remove_winner().unwrap().run, 2);
+        assert_eq!(tree.remove_winner().unwrap().run, 0);
+        assert!(tree.remove_winner().is_none());
+
+        // A finished winner is removed as it's updated, leaving the rest in order
+        let mut tree = LoserTree::new(vec![3, 1, 2]);
+        assert_eq!(tree.update_winner(|key| { *key = 4; true }), None);
+        assert_eq!(tree.winner(), Some(&2));
+        assert_eq!(tree.update_winner(|_| false), Some(2));
+        assert_eq!(tree.winner(), Some(&3));
+        assert_eq!(tree.update_winner(|_| false), Some(3));
+        assert_eq!(tree.update_winner(|_| false), Some(4));
+        assert!(tree.winner().is_none());
+        assert_eq!(tree.update_winner(|_| true), None);
+    }
+}
@@ -1,14 +1,13 @@
-/// Filemerger
-///
-/// Takes a series of files that are assumed to be sorted based on your merge key
-/// Splits each line based on a user supplied delimiter
-/// Extracts a specific column to use as the merge key
-/// Merges all files together into a single stream based on the merge key
-///
-/// We have a large number of code paths below due to Rust's type checker!
+//! Filemerger
+//!
+//! Takes a series of files that are assumed to be sorted based on your merge key
+//! Splits each line based on a user supplied delimiter
+//! Extracts a specific column to use as the merge key
+//! Merges all files together into a single stream based on the merge key
+//!
+//! We have a large number of code paths below due to Rust's type checker!
 
 #[macro_use] extern crate log;
-extern crate rustc_serialize;
 extern crate yaml_rust;
 extern crate getopts;
 extern crate flate2;
@@ -17,23 +16,52 @@ extern crate glob;
 extern crate csv;
 
 mod merge_file_manager;
+mod cache_file;
 mod merge_file;
+mod bgzf;
+mod loser_tree;
+mod external_sort;
+mod merge_sink;
+mod merge_output;
+mod partition;
+mod aggregate;
+mod set_operation;
+mod filter;
+mod encoding;
+mod input_format;
 mod settings;
 
-use merge_file_manager::MergeFileManager;
+#[cfg(test)]
+mod test_helpers;
+
+use merge_file_manager::{CacheStats, MergeFileManager};
+use external_sort::ExternalSort;
 use std::collections::HashMap;
-use settings::MergeSettingsParser;
-use merge_file::Mergeable;
-use merge_file::MergeFile;
-use std::path::PathBuf;
-use settings::KeyType;
+use settings::{MergeSettings, MergeSettingsParser};
+use merge_sink::{Annotation, MergeSink, MergeWriter};
+use filter::FilteredSink;
+use merge_output::{MergeOutput, OutputFile, ShardedOutput};
+use bgzf::KeyColumn;
+use input_format::InputFormat;
+use partition::{HashPartitionedOutput, PartitionedOutput};
+use aggregate::Aggregator;
+use set_operation::SetOperator;
+use merge_file::{ByteString, Mergeable};
+use merge_file::{InputOptions, MergeFile};
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+use settings::{CacheCommand, KeyType};
+use cache_file::{CacheFile, CacheLock, CacheOptions, StaleCachePolicy};
+use std::process;
 use std::env;
 use std::fmt;
+use std::io;
 
-fn retrieve_from_cache<T>(cache_path: &PathBuf, default_key: T, key_type: KeyType, mut merge_cache: HashMap<String, MergeFile<T>>)
+fn retrieve_from_cache<T>(cache_path: &Path, default_key: T, key_type: KeyType, options: InputOptions, cache_options: &CacheOptions,
+                          mut merge_cache: HashMap<String, MergeFile<T>>)
     -> HashMap<String, MergeFile<T>>
     where T: Mergeable, T::Err: fmt::Debug {
-    match MergeFileManager::retrieve_from_cache(cache_path, default_key, key_type) {
+    match MergeFileManager::retrieve_from_cache(cache_path, default_key, key_type, options, cache_options) {
         Ok(merge_files) => {
             merge_cache.extend(merge_files);
             debug!("Added cachefile {} to the cache", cache_path.display())
@@ -41,15 +69,36 @@ fn retrieve_from_cache<T>(cache_path: &PathBuf, default_key: T, key_type: KeyTyp
         Err(error) => {
             error!("Unable to load from cache file: {}", cache_path.display());
             error!("Error was: {}", error);
+            process::exit(1);
         }
     }
     merge_cache
 }
 
-fn retrieve_from_glob<T>(glob_choice: &str, delimiter: char, index: usize, default_key: T, key_type: KeyType, mut merge_cache: HashMap<String, MergeFile<T>>)
+/// Reads caches for the cache commands that work on their entries alone, bailing if any can't be read
+/// or were built with a different key type.
+fn read_caches(cache_paths: &[PathBuf], cache_options: &CacheOptions, key_type: &KeyType) -> Vec<CacheFile> {
+    let mut caches = Vec::new();
+    for cache_path in cache_paths {
+        let cache = CacheLock::shared(cache_path, cache_options.lock_policy)
+                              .and_then(|_lock| CacheFile::read(cache_path, cache_options.base_dir.as_deref()))
+                              .and_then(|cache| cache.check_key_type(key_type, cache_path).map(|_| cache));
+        match cache {
+            Ok(cache) => caches.push(cache),
+            Err(error) => {
+                error!("Unable to load from cache file: {}", cache_path.display());
+                error!("Error was: {}", error);
+                process::exit(1);
+            },
+        }
+    }
+    caches
+}
+
+fn retrieve_from_glob<T>(glob_choice: &str, delimiter: char, index: usize, default_key: T, options: InputOptions, mut merge_cache: HashMap<String, MergeFile<T>>)
     -> HashMap<String, MergeFile<T>>
     where T: Mergeable, T::Err: fmt::Debug {
-    match MergeFileManager::retrieve_from_glob(glob_choice, delimiter, index, default_key, key_type) {
+    match MergeFileManager::retrieve_from_glob(glob_choice, delimiter, index, default_key, options) {
         Ok(merge_files) => {
             merge_cache.extend(merge_files);
             debug!("Added glob {} to the cache", glob_choice);
@@ -57,125 +106,353 @@ fn retrieve_from_glob<T>(glob_choice: &str, delimiter: char, index: usize, defau
         Err(error) => {
             error!("Unable to load from glob: {}", glob_choice);
             error!("Error was: {}", error);
+            process::exit(1);
         }
     }
     merge_cache
 }
 
-fn write_cache<T>(cache_path: &PathBuf, merge_cache: HashMap<String, MergeFile<T>>, default_key: T)
+fn input_options(settings: &MergeSettings) -> InputOptions {
+    InputOptions {
+        encoding: settings.input_encoding,
+        format: settings.input_format,
+        tolerate_read_errors: settings.tolerate_corrupt_inputs,
+    }
+}
+
+fn cache_options(settings: &MergeSettings) -> CacheOptions {
+    CacheOptions {
+        base_dir: settings.cache_base_dir.clone(),
+        stale_policy: settings.stale_cache_policy,
+        lock_policy: settings.cache_lock_policy,
+    }
+}
+
+fn input_filenames(settings: &MergeSettings) -> Vec<String> {
+    let mut filenames = Vec::new();
+
+    if let Some(ref cache_path) = settings.cache_path {
+        match MergeFileManager::cache_filenames(cache_path, &cache_options(settings)) {
+            Ok(cache_filenames) => filenames.extend(cache_filenames),
+            Err(error) => {
+                error!("Unable to load from cache file: {}", cache_path.display());
+                error!("Error was: {}", error);
+                process::exit(1);
+            },
+        }
+    }
+
+    if let Some(ref glob_choices) = settings.glob_choices {
+        for glob_choice in glob_choices {
+            match MergeFileManager::glob_filenames(glob_choice) {
+                Ok(glob_filenames) => filenames.extend(glob_filenames),
+                Err(error) => {
+                    error!("Unable to load from glob: {}", glob_choice);
+                    error!("Error was: {}", error);
+                    process::exit(1);
+                },
+            }
+        }
+    }
+
+    filenames.sort();
+    filenames.dedup();
+    filenames
+}
+
+fn write_cache<T>(cache_path: &Path, merge_cache: HashMap<String, MergeFile<T>>, default_key: T, key_type: KeyType, checkpoint_lines: Option<u64>,
+                  cache_options: &CacheOptions)
     where T: Mergeable, T::Err: fmt::Debug {
-    match MergeFileManager::write_cache(cache_path, merge_cache, default_key) {
+    match MergeFileManager::write_cache(cache_path, merge_cache, default_key, key_type, checkpoint_lines, cache_options) {
         Ok(result) => {info!("{}", result)},
-        Err(result) => {error!("{}", result)},
+        Err(result) => {
+            error!("{}", result);
+            process::exit(1);
+        },
+    }
+}
+
+/// Where the merge key ends up in the rows written out, None if they don't include it as a delimited column.
+fn output_key_column(settings: &MergeSettings) -> Option<KeyColumn> {
+    if settings.input_format != InputFormat::Delimited {
+        return None;
     }
+
+    let key_index = if settings.aggregates.is_some() {
+        // Aggregates are written after the key
+        0
+    } else if settings.set_operation.is_some() {
+        if settings.keys_only { 0 } else { settings.key_index }
+    } else {
+        let key_index = match settings.output_columns {
+            Some(ref columns) => columns.iter().position(|column| *column == settings.key_index)?,
+            None => settings.key_index,
+        };
+
+        if settings.prepend_annotations {
+            key_index + settings.annotations.len()
+        } else {
+            key_index
+        }
+    };
+
+    Some(KeyColumn { delimiter: settings.delimiter, key_index })
 }
 
-fn begin_merge<T>(mut merge_cache: HashMap<String, MergeFile<T>>, key_start: Option<String>, key_end: Option<String>, print_merge_output: bool)
+/// Merges the files, returning what went wrong for the caller to report so it can clean up before exiting.
+fn begin_merge<T>(mut merge_cache: HashMap<String, MergeFile<T>>, settings: &MergeSettings) -> Result<(), String>
     where T: Mergeable, T::Err: fmt::Debug {
+    // Set operations need every input, including the empty ones never opened and those the fast forward is about to drop
+    let filenames = if settings.set_operation.is_some() { input_filenames(settings) } else { Vec::new() };
+
+    // Binary searching to the start position loses count of the lines skipped over
+    if settings.annotations.contains(&Annotation::LineNumber) {
+        for merge_file in merge_cache.values_mut() {
+            merge_file.track_line_numbers();
+        }
+    }
+
     // If we have a start position, then fast forward to it
-    if key_start.is_some() {
-        merge_cache = MergeFileManager::fast_forward_cache(merge_cache, key_start.unwrap());
+    if let Some(ref key_start) = settings.key_start {
+        merge_cache = MergeFileManager::fast_forward_cache(merge_cache, key_start.clone())
+                          .map_err(|error| format!("Unable to fast forward to {}: {}", key_start, error))?;
+    }
+
+    // Hand the reading off to threads once positioned, as only files read inline can seek or binary search
+    MergeFileManager::read_ahead(&mut merge_cache, settings.reader_threads);
+
+    let key_column = output_key_column(settings);
+    let output: Box<dyn MergeOutput<T>> = match settings.output_path {
+        Some(ref output_path) if settings.partitioning.is_some() => {
+            let template = output_path.to_string_lossy().into_owned();
+            let partitioned_output = PartitionedOutput::new(template, settings.partitioning.clone().unwrap(), key_column)
+                                     .map_err(|error| format!("Unable to partition the output: {}", error))?;
+            Box::new(partitioned_output)
+        },
+        Some(ref output_path) if settings.hash_partitions.is_some() => {
+            let hash_partitioned_output = HashPartitionedOutput::new(output_path.clone(), settings.hash_partitions.unwrap(), key_column)
+                                          .map_err(|error| format!("Unable to create the hash partitioned output files: {}", error))?;
+            Box::new(hash_partitioned_output)
+        },
+        Some(ref output_path) if settings.split_bytes.is_some() || settings.split_rows.is_some() => {
+            Box::new(ShardedOutput::new(output_path.clone(),
+                                        settings.split_bytes,
+                                        settings.split_rows,
+                                        settings.split_whole_keys,
+                                        key_column,
+                                        settings.key_type.clone()))
+        },
+        Some(ref output_path) => {
+            let output_file = OutputFile::create(output_path, key_column)
+                                  .map_err(|error| format!("Unable to create output file {}: {}", output_path.display(), error))?;
+            Box::new(output_file)
+        },
+        None => Box::new(BufWriter::new(io::stdout())),
+    };
+    let key_end = settings.key_end.clone();
+
+    let mut sink: Box<dyn MergeSink<T>> = if let Some(ref aggregates) = settings.aggregates {
+        Box::new(Aggregator::new(aggregates.clone(), settings.delimiter, output))
+    } else if let Some(ref set_operation) = settings.set_operation {
+        Box::new(SetOperator::new(set_operation.clone(), filenames, settings.keys_only, output))
+    } else {
+        Box::new(MergeWriter::new(output).project(settings.output_columns.clone())
+                                         .annotate(settings.annotations.clone(), settings.prepend_annotations))
+    };
+
+    // Filter rows before they reach any of the above
+    if let Some(ref filter) = settings.filter {
+        sink = Box::new(FilteredSink::new(filter.clone(), sink));
     }
 
-    MergeFileManager::begin_merge(merge_cache, key_end, print_merge_output);
+    MergeFileManager::begin_merge(merge_cache, key_end, &mut *sink).map(|_| ()).map_err(|error| format!("Merge failed: {}", error))
 }
 
-fn main() {
-    // Allocate an empty cache for each KeyType variant, it's hacky and there's plenty of code duplication
-    // but we need to do it as Rust cannot have a single HashMap that can contiain two types of MergeFile<T>
-    // and because each match arm of KeyType is a different type we would have to use some box trait magic.
-    let mut merge_cache_string = HashMap::new();
-    let mut merge_cache_i32 = HashMap::new();
-    let mut merge_cache_u32 = HashMap::new();
+/// Sorts and/or merges the inputs down through temporary runs, opening only a few files at a time,
+/// then merges what's left as usual.
+fn merge_through_runs<T>(settings: &MergeSettings, default_key: T)
+    where T: Mergeable, T::Err: fmt::Debug {
+    let filenames = input_filenames(settings);
 
-    // Set up argument parsing
-    let args = env::args().collect::<Vec<String>>();
-    let parser = MergeSettingsParser::new(args);
-    let settings_result = parser.parse();
+    let mut external_sort = match ExternalSort::new(&settings.temp_dir, settings.sort_memory) {
+        Ok(external_sort) => external_sort.read_ahead(settings.reader_threads).input_options(input_options(settings)),
+        Err(error) => {
+            error!("Unable to create a directory for the runs under {}: {}", settings.temp_dir.display(), error);
+            process::exit(1);
+        },
+    };
 
-    if settings_result.is_err() {
-        parser.error_usage_and_bail(settings_result.unwrap_err().as_ref());
+    let sorted_filenames = if settings.sort {
+        external_sort.sort_files(&filenames, settings.delimiter, settings.key_index, default_key.clone())
     } else {
-        let settings = settings_result.unwrap();
-        let cache_present = settings.cache_path.is_some();
-        let glob_present = settings.glob_choices.is_some();
-
-        let mut cache_path = PathBuf::from("");
-
-        if cache_present {
-            cache_path = settings.cache_path.unwrap();
-
-            if cache_path.exists() {
-                match settings.key_type {
-                    KeyType::Unsigned32Integer => {
-                        merge_cache_u32 = retrieve_from_cache(&cache_path,
-                                                              0u32,
-                                                              settings.key_type.clone(),
-                                                              merge_cache_u32);
-                    },
-                    KeyType::Signed32Integer => {
-                        merge_cache_i32 = retrieve_from_cache(&cache_path,
-                                                              0i32,
-                                                              settings.key_type.clone(),
-                                                              merge_cache_i32);
-                    },
-                    KeyType::String => {
-                        merge_cache_string = retrieve_from_cache(&cache_path,
-                                                                 "0".to_string(),
-                                                                 settings.key_type.clone(),
-                                                                 merge_cache_string);
-                    }
-                }
+        Ok(filenames)
+    };
+
+    let runs = sorted_filenames.and_then(|sorted_filenames| {
+        external_sort.merge_in_passes(sorted_filenames,
+                                      settings.max_open_files.unwrap_or(usize::MAX),
+                                      settings.delimiter,
+                                      settings.key_index,
+                                      default_key)
+    });
+
+    let merged = runs.map_err(|error| format!("Unable to merge the input files through runs: {}", error))
+                     .and_then(|runs| begin_merge(runs, settings));
+
+    // Exiting skips the destructor, clean up the runs first
+    drop(external_sort);
+
+    if let Err(error) = merged {
+        error!("{}", error);
+        process::exit(1);
+    }
+}
+
+fn run_cache_command<T>(settings: &MergeSettings, command: &CacheCommand, default_key: T)
+    where T: Mergeable, T::Err: fmt::Debug {
+    // Cache commands can't be given without a cache file
+    let cache_path = settings.cache_path.as_ref().unwrap();
+
+    match *command {
+        CacheCommand::Refresh => {
+            let glob_choices = settings.glob_choices.clone().unwrap_or_default();
+            match MergeFileManager::refresh_cache(cache_path, &glob_choices, settings.delimiter, settings.key_index, default_key, settings.key_type.clone(),
+                                                  input_options(settings), &cache_options(settings), settings.cache_checkpoint_lines) {
+                Ok(refresh) => info!("{} in {}", refresh, cache_path.display()),
+                Err(error) => {
+                    error!("Unable to refresh the cache file: {}", cache_path.display());
+                    error!("Error was: {}", error);
+                    process::exit(1);
+                },
             }
-        }
+        },
+        CacheCommand::Show | CacheCommand::Stats | CacheCommand::Query => {
+            let inspected = MergeFileManager::inspect_cache(cache_path, default_key, settings.key_type.clone(), input_options(settings),
+                                                            &cache_options(settings));
+            let merge_files = match inspected {
+                Ok(merge_files) => merge_files,
+                Err(error) => {
+                    error!("Unable to load from cache file: {}", cache_path.display());
+                    error!("Error was: {}", error);
+                    process::exit(1);
+                },
+            };
 
-        if glob_present {
-            let glob_choices = settings.glob_choices.unwrap();
-            for glob_choice in glob_choices {
-                match settings.key_type {
-                    KeyType::Unsigned32Integer => {
-                        merge_cache_u32 = retrieve_from_glob(&glob_choice,
-                                                             settings.delimiter,
-                                                             settings.key_index,
-                                                             0u32,
-                                                             settings.key_type.clone(),
-                                                             merge_cache_u32);
+            match *command {
+                CacheCommand::Show => print!("{}", MergeFileManager::show_cache(&merge_files)),
+                CacheCommand::Stats => println!("{}", CacheStats::of(&merge_files)),
+                _ => match MergeFileManager::query_cache(merge_files, settings.key_start.as_deref(), settings.key_end.as_deref()) {
+                    Ok(merge_files) => for merge_file in merge_files {
+                        println!("{}", merge_file.filename);
                     },
-                    KeyType::Signed32Integer => {
-                        merge_cache_i32 = retrieve_from_glob(&glob_choice,
-                                                             settings.delimiter,
-                                                             settings.key_index,
-                                                             0i32, settings.key_type.clone(),
-                                                             merge_cache_i32);
+                    Err(error) => {
+                        error!("Unable to query the cache file: {}", error);
+                        process::exit(1);
                     },
-                    KeyType::String => {
-                        merge_cache_string = retrieve_from_glob(&glob_choice,
-                                                                settings.delimiter,
-                                                                settings.key_index,
-                                                                "0".to_string(),
-                                                                settings.key_type.clone(),
-                                                                merge_cache_string);
-                    }
-                }
+                },
             }
+        },
+        CacheCommand::Union | CacheCommand::Subtract => {
+            let caches = read_caches(&settings.other_caches, &cache_options(settings), &settings.key_type);
+            let entries = if *command == CacheCommand::Union {
+                CacheFile::union(&caches)
+            } else {
+                caches[0].subtract(&CacheFile::new(settings.key_type.clone(), CacheFile::union(&caches[1..])))
+            };
 
-            if cache_present {
-                match settings.key_type {
-                    KeyType::Unsigned32Integer => write_cache(&cache_path, merge_cache_u32, 0u32),
-                    KeyType::Signed32Integer => write_cache(&cache_path, merge_cache_i32, 0i32),
-                    KeyType::String => write_cache(&cache_path, merge_cache_string, "0".to_string()),
-                }
-
-                // Bail early as glob + cache == don't perform merge
-                return;
+            let entry_count = entries.len();
+            let written = CacheLock::exclusive(cache_path, settings.cache_lock_policy)
+                                    .and_then(|_lock| CacheFile::new(settings.key_type.clone(), entries).write(cache_path));
+            match written {
+                Ok(()) => info!("Written {} cache entries to {}", entry_count, cache_path.display()),
+                Err(error) => {
+                    error!("Unable to write the cache out to disk: {}", error);
+                    process::exit(1);
+                },
             }
+        },
+        CacheCommand::Diff => {
+            let caches = read_caches(&[settings.other_caches[0].clone(), cache_path.clone()], &cache_options(settings), &settings.key_type);
+            print!("{}", caches[0].diff(&caches[1]));
+        },
+    }
+}
+
+fn run<T>(settings: MergeSettings, default_key: T)
+    where T: Mergeable, T::Err: fmt::Debug {
+    if let Some(ref command) = settings.cache_command {
+        run_cache_command(&settings, command, default_key);
+        return;
+    }
+
+    let writing_cache = settings.glob_choices.is_some() && settings.cache_path.is_some();
+
+    // Building a cache opens every file up front, refreshing it reads them to their end one at a time instead
+    if writing_cache && settings.max_open_files.is_some() {
+        run_cache_command(&settings, &CacheCommand::Refresh, default_key);
+        return;
+    }
+
+    // Unsorted inputs, or more inputs than we can have open at once, are merged through runs first
+    if settings.sort || settings.max_open_files.is_some() {
+        merge_through_runs(&settings, default_key);
+        return;
+    }
+
+    let mut merge_cache = HashMap::new();
+
+    if let Some(ref cache_path) = settings.cache_path {
+        if cache_path.exists() {
+            // When rebuilding the cache changed files are simply read again
+            let stale_policy = if writing_cache { StaleCachePolicy::Rescan } else { settings.stale_cache_policy };
+            let cache_options = CacheOptions { stale_policy, ..cache_options(&settings) };
+            merge_cache = retrieve_from_cache(cache_path, default_key.clone(), settings.key_type.clone(), input_options(&settings), &cache_options, merge_cache);
+        }
+    }
+
+    if let Some(ref glob_choices) = settings.glob_choices {
+        for glob_choice in glob_choices {
+            merge_cache = retrieve_from_glob(glob_choice,
+                                             settings.delimiter,
+                                             settings.key_index,
+                                             default_key.clone(),
+                                             input_options(&settings),
+                                             merge_cache);
         }
 
-        // Begin the merge process
-        match settings.key_type {
-            KeyType::Unsigned32Integer => begin_merge(merge_cache_u32, settings.key_start, settings.key_end, true),
-            KeyType::Signed32Integer => begin_merge(merge_cache_i32, settings.key_start, settings.key_end, true),
-            KeyType::String => begin_merge(merge_cache_string, settings.key_start, settings.key_end, true),
+        if let Some(ref cache_path) = settings.cache_path {
+            write_cache(cache_path, merge_cache, default_key, settings.key_type.clone(), settings.cache_checkpoint_lines, &cache_options(&settings));
+
+            // Bail early as glob + cache == don't perform merge
+            return;
         }
     }
+
+    // Begin the merge process
+    if let Err(error) = begin_merge(merge_cache, &settings) {
+        error!("{}", error);
+        process::exit(1);
+    }
+}
+
+fn main() {
+    // Set up argument parsing
+    let args = env::args().collect::<Vec<String>>();
+    let parser = MergeSettingsParser::new(args);
+    let settings = match parser.parse() {
+        Ok(settings) => settings,
+        Err(failure) => parser.error_usage_and_bail(failure.as_ref()),
+    };
+
+    // Each KeyType variant is a different MergeFile<T>, so monomorphise the run once per key type
+    match settings.key_type {
+        KeyType::Unsigned32Integer => run(settings, 0u32),
+        KeyType::Signed32Integer => run(settings, 0i32),
+        KeyType::String => run(settings, ByteString(b"0".to_vec())),
+    }
+
+    let read_errors = merge_file::tolerated_read_errors();
+    if read_errors > 0 {
+        warn!("Tolerated {} read errors, each of those inputs was only read up to where it failed", read_errors);
+    }
 }
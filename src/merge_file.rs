@@ -1,39 +1,353 @@
 // File IO modules
-use std::io::{Error, ErrorKind};
+use std::io::{Error, ErrorKind, SeekFrom};
 use std::io::prelude::*;
 use std::io::BufReader;
+use std::sync::mpsc::{channel, sync_channel, Receiver, Sender};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::convert::Infallible;
+use std::borrow::Cow;
 use std::str::FromStr;
 use std::path::Path;
-use std::io::Lines;
 use std::fs::File;
+use std::thread;
 use std::cmp;
+use std::str;
+use std::vec;
+use std::mem;
 use std::fmt;
 use std::io;
 
 // Optional decompressors for merge files
-use flate2::read::GzDecoder;
+use flate2::read::MultiGzDecoder;
 use bzip2::read::BzDecoder;
 
 // Other project dependencies
-use settings::KeyType;
+use bgzf::{BlockIndex, KeyColumn};
+use encoding::InputEncoding;
+use input_format::InputFormat;
+
+pub trait Mergeable: Clone + Default + FromStr + fmt::Display + fmt::Debug + PartialOrd + Ord {
+    /// Parses a key out of the raw bytes of its column into `self`, returning false if it isn't a valid key.
+    /// This is called for every line, so keys that own an allocation should reuse it.
+    fn parse_from(&mut self, bytes: &[u8]) -> bool {
+        match str::from_utf8(bytes).ok().and_then(|key| key.parse::<Self>().ok()) {
+            Some(key) => {
+                *self = key;
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// Writes the key out exactly as it was read.
+    fn write_to(&self, output: &mut dyn Write) -> io::Result<()> {
+        write!(output, "{}", self)
+    }
+
+    /// The key exactly as it was read, unlike `Display` which replaces bytes that aren't UTF-8.
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        self.write_to(&mut bytes).expect("Writing to a Vec can't fail");
+        bytes
+    }
+
+    /// Parses a key out of raw bytes (see `parse_from`), eg. ones from `to_bytes`.
+    fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let mut key = Self::default();
+        if key.parse_from(bytes) {
+            Some(key)
+        } else {
+            None
+        }
+    }
 
-pub trait Mergeable: Clone + FromStr + fmt::Display + fmt::Debug + PartialOrd + Ord {}
+    /// Bytes the key holds on the heap, used when estimating how much memory buffered keys take up.
+    fn heap_size(&self) -> usize {
+        0
+    }
+}
 
 impl Mergeable for u32 {}
 impl Mergeable for i32 {}
-impl Mergeable for String {}
+impl Mergeable for String {
+    fn parse_from(&mut self, bytes: &[u8]) -> bool {
+        match str::from_utf8(bytes) {
+            Ok(key) => {
+                self.clear();
+                self.push_str(key);
+                true
+            },
+            Err(_) => false,
+        }
+    }
+
+    fn heap_size(&self) -> usize {
+        self.capacity()
+    }
+}
+
+/// A merge key compared byte by byte, so it orders the same as a `String` but needn't be valid UTF-8.
+#[derive(Clone, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ByteString(pub Vec<u8>);
+
+impl FromStr for ByteString {
+    type Err = Infallible;
+
+    fn from_str(key: &str) -> Result<ByteString, Infallible> {
+        Ok(ByteString(key.as_bytes().to_vec()))
+    }
+}
+
+impl fmt::Display for ByteString {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", String::from_utf8_lossy(&self.0))
+    }
+}
+
+impl fmt::Debug for ByteString {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", String::from_utf8_lossy(&self.0))
+    }
+}
+
+impl Mergeable for ByteString {
+    fn parse_from(&mut self, bytes: &[u8]) -> bool {
+        self.0.clear();
+        self.0.extend_from_slice(bytes);
+        true
+    }
+
+    fn write_to(&self, output: &mut dyn Write) -> io::Result<()> {
+        output.write_all(&self.0)
+    }
+
+    fn heap_size(&self) -> usize {
+        self.0.capacity()
+    }
+}
+
+/// How input files are read, shared by every `MergeFile` in a merge.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct InputOptions {
+    /// Anything but `InputEncoding::Bytes` is transcoded to UTF-8
+    pub encoding: InputEncoding,
+    /// How lines are split into columns, to find the merge key and for anything else looking at a line's columns
+    pub format: InputFormat,
+    /// Treat a failed read as the end of that file (counting it, see `tolerated_read_errors`) instead of an error
+    pub tolerate_read_errors: bool,
+}
+
+/// Read errors treated as EOF by `MergeFile`s with `InputOptions::tolerate_read_errors` set, across the whole process
+static TOLERATED_READ_ERRORS: AtomicUsize = AtomicUsize::new(0);
+
+/// How many read errors have been tolerated so far, see `InputOptions::tolerate_read_errors`.
+pub fn tolerated_read_errors() -> usize {
+    TOLERATED_READ_ERRORS.load(Ordering::SeqCst)
+}
+
+/// Writes a merge key's raw bytes (see `Mergeable::to_bytes`) as text that can be read back byte for byte with `unescape_key`.
+/// UTF-8 is kept as is, apart from `\` which is doubled. Control characters and bytes that aren't UTF-8 are written as `\xNN`.
+pub fn escape_key(bytes: &[u8]) -> String {
+    let mut escaped = String::with_capacity(bytes.len());
+
+    for chunk in bytes.utf8_chunks() {
+        for character in chunk.valid().chars() {
+            match character {
+                '\\' => escaped.push_str("\\\\"),
+                character if character.is_ascii_control() => escaped.push_str(&format!("\\x{:02x}", character as u8)),
+                character => escaped.push(character),
+            }
+        }
+        for byte in chunk.invalid() {
+            escaped.push_str(&format!("\\x{:02x}", byte));
+        }
+    }
+
+    escaped
+}
+
+/// The raw bytes of a merge key written by `escape_key`, or None if it isn't a valid escaped key.
+pub fn unescape_key(escaped: &str) -> Option<Vec<u8>> {
+    let mut bytes = Vec::with_capacity(escaped.len());
+    let mut rest = escaped.as_bytes();
+
+    while let Some((&byte, after)) = rest.split_first() {
+        rest = after;
+        if byte != b'\\' {
+            bytes.push(byte);
+            continue;
+        }
+
+        match rest.split_first() {
+            Some((&b'\\', after)) => {
+                bytes.push(b'\\');
+                rest = after;
+            },
+            Some((&b'x', after)) if after.len() >= 2 => {
+                let hex = str::from_utf8(&after[..2]).ok()?;
+                bytes.push(u8::from_str_radix(hex, 16).ok()?);
+                rest = &after[2..];
+            },
+            _ => return None,
+        }
+    }
+
+    Some(bytes)
+}
+
+/// Parses a merge key written by `escape_key`.
+pub fn parse_escaped_key<T: Mergeable>(escaped: &str) -> Option<T> {
+    unescape_key(escaped).and_then(|bytes| T::from_bytes(&bytes))
+}
+
+/// The merge key and position of a line part way through a file, so a later read can skip straight to it.
+/// Every line before it has a merge key no greater than its own.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Checkpoint {
+    /// As written by `escape_key`, so keys that aren't UTF-8 survive being written to a cache
+    pub key: String,
+    pub line_number: u64,
+    /// Where the line starts in the decompressed file
+    pub offset: u64,
+}
+
+/// `MergeFile::fast_forward` stops binary searching and reads line by line once it's within this many bytes of its key
+const BINARY_SEARCH_LINEAR_BYTES: u64 = 64 * 1024;
+
+/// Lines read by a `ReadAhead` thread are sent over in batches of this many
+const READ_AHEAD_BATCH_LINES: usize = 1024;
+
+/// How many batches a `ReadAhead` thread can get in front of the merge
+const READ_AHEAD_BATCHES: usize = 4;
+
+/// Where a `MergeFile`'s lines come from
+enum LineReader {
+    /// Decompressed and split on the merge thread
+    Inline(BufReader<Box<dyn Read + Send>>),
+    /// Decompressed and split on a background thread
+    ReadAhead(ReadAhead),
+    /// Only while switching between the above
+    Detached,
+}
+
+/// Receives batches of raw lines (line endings included) from a background thread.
+/// The lines are sent back once they've been read so the thread can read into them again, rather than
+/// allocating every line. The thread exits at EOF, on a read error, or once the receiving `MergeFile` is dropped.
+struct ReadAhead {
+    receiver: Receiver<io::Result<Vec<Vec<u8>>>>,
+    batch: vec::IntoIter<Vec<u8>>,
+    /// Lines that have been read, returned to the thread along with the next batch it's asked for
+    spent: Vec<Vec<u8>>,
+    recycler: Sender<Vec<Vec<u8>>>,
+}
+
+impl ReadAhead {
+    fn spawn(mut reader: BufReader<Box<dyn Read + Send>>, filename: String) -> ReadAhead {
+        let (sender, receiver) = sync_channel(READ_AHEAD_BATCHES);
+        let (recycler, recycled) = channel::<Vec<Vec<u8>>>();
+
+        thread::spawn(move || {
+            let mut spare_lines = Vec::new();
+
+            loop {
+                while let Ok(lines) = recycled.try_recv() {
+                    spare_lines.extend(lines);
+                }
+
+                let mut batch = Vec::with_capacity(READ_AHEAD_BATCH_LINES);
+
+                while batch.len() < READ_AHEAD_BATCH_LINES {
+                    let mut line: Vec<u8> = spare_lines.pop().unwrap_or_default();
+                    line.clear();
+                    match reader.read_until(b'\n', &mut line) {
+                        Ok(0) => break,
+                        Ok(_) => batch.push(line),
+                        Err(error) => {
+                            // Hand over the lines read before the error, the merge still uses them when tolerating errors
+                            if !batch.is_empty() && sender.send(Ok(batch)).is_err() {
+                                return;
+                            }
+                            let _ = sender.send(Err(error));
+                            return;
+                        },
+                    }
+                }
+
+                let hit_eof = batch.len() < READ_AHEAD_BATCH_LINES;
+
+                // An error sending means the MergeFile is gone, so stop reading
+                if !batch.is_empty() && sender.send(Ok(batch)).is_err() {
+                    return;
+                }
+
+                if hit_eof {
+                    debug!("Read ahead thread for {} hit EOF", filename);
+                    return;
+                }
+            }
+        });
+
+        ReadAhead {
+            receiver,
+            batch: Vec::new().into_iter(),
+            spent: Vec::new(),
+            recycler,
+        }
+    }
+
+    /// Swaps the next line into `buffer`, returning its length in bytes (0 at EOF) like `BufRead::read_until`.
+    /// What was in `buffer` goes back to the thread to read another line into.
+    fn read_line(&mut self, buffer: &mut Vec<u8>) -> io::Result<usize> {
+        loop {
+            if let Some(mut line) = self.batch.next() {
+                mem::swap(buffer, &mut line);
+                self.spent.push(line);
+                return Ok(buffer.len());
+            }
+
+            // The thread may have already hit EOF and gone, in which case the lines aren't needed
+            if !self.spent.is_empty() {
+                let _ = self.recycler.send(mem::take(&mut self.spent));
+            }
+
+            match self.receiver.recv() {
+                Ok(Ok(batch)) => self.batch = batch.into_iter(),
+                Ok(Err(error)) => return Err(error),
+                // The thread hung up, it has sent everything
+                Err(_) => return Ok(0),
+            }
+        }
+    }
+}
 
 pub struct MergeFile<T> {
     pub filename: String,
     pub filesize: u64,
-    lines: Lines<BufReader<Box<Read>>>,
-    pub line: String,
+    reader: LineReader,
+    pub line: Vec<u8>,
+    pub line_number: u64,
+    pub line_offset: u64,
+    buffer: Vec<u8>,
+    bytes_read: u64,
     pub delimiter: char,
+    pub format: InputFormat,
     pub key_index: usize,
     pub current_merge_key: T,
     pub beginning_merge_key: T,
     pub ending_merge_key: T,
-    pub key_type: KeyType,
+    /// How many lines, and bytes of (decompressed) lines, the file has, once known
+    pub line_count: Option<u64>,
+    pub byte_count: Option<u64>,
+    /// Recorded while reading with `record_checkpoints`, or loaded from a cache for `fast_forward` to skip ahead with
+    pub checkpoints: Vec<Checkpoint>,
+    checkpoint_every: Option<u64>,
+    /// Whether `fast_forward` may binary search, losing track of line numbers
+    binary_search: bool,
+    encoding: InputEncoding,
+    compressed: bool,
+    /// Whether it's gzip, which may be BGZF with a `BlockIndex` to skip ahead with
+    gzip: bool,
+    tolerate_read_errors: bool,
 }
 
 impl<T: Mergeable> MergeFile<T> where T::Err: fmt::Debug {
@@ -45,28 +359,40 @@ impl<T: Mergeable> MergeFile<T> where T::Err: fmt::Debug {
     /// ```
     /// let mut merge_file = MergeFile::new("/path/to/data.psv", '|', 1);
     /// ```
-    pub fn new(filename: &str, delimiter: char, key_index: usize, default_key: T, key_type: KeyType) -> io::Result<MergeFile<T>> {
+    ///
+    /// The binary itself always passes its `InputOptions` to `open`.
+    #[allow(dead_code)]
+    pub fn new(filename: &str, delimiter: char, key_index: usize, default_key: T) -> io::Result<MergeFile<T>> {
+        MergeFile::open(filename, delimiter, key_index, default_key, InputOptions::default())
+    }
+
+    /// Constructs a new `MergeFile`, reading the file as described by the `InputOptions`.
+    pub fn open(filename: &str, delimiter: char, key_index: usize, default_key: T, options: InputOptions) -> io::Result<MergeFile<T>> {
         // Unit test: Create MergeFile with valid test data
         // Unit test: Create MergeFile with invalid test data
         let filepath = Path::new(filename);
 
         let file_ext = match filepath.extension() {
             Some(extension) => extension,
-            None => return Err(Error::new(ErrorKind::Other, format!("Couldn't find file extension in {:?}", filepath))),
+            None => return Err(io::Error::other(format!("Couldn't find file extension in {:?}", filepath))),
         };
 
-        let file = try!(File::open(filepath));
-        let filesize = try!(file.metadata()).len();
+        let file = File::open(filepath)?;
+        let filesize = file.metadata()?.len();
 
         // Figure out the input file's decompressor
-        let decompressor: Box<Read> = match file_ext.to_str() {
+        let compressed = matches!(file_ext.to_str(), Some("bz2") | Some("gz") | Some("bgz"));
+        let gzip = matches!(file_ext.to_str(), Some("gz") | Some("bgz"));
+
+        let decompressor: Box<dyn Read + Send> = match file_ext.to_str() {
             Some("bz2") => {
                 debug!("Using BzDecompressor as the input decompressor.");
                 Box::new(BzDecoder::new(file))
             },
-            Some("gz") => {
-                debug!("Using GzDecoder as the input decompressor.");
-                Box::new(GzDecoder::new(file).unwrap())
+            Some("gz") | Some("bgz") => {
+                // Concatenated gzip members (like BGZF blocks) read as one file
+                debug!("Using MultiGzDecoder as the input decompressor.");
+                Box::new(MultiGzDecoder::new(file)?)
             },
             Some(_) => {
                 debug!("Assuming the file is uncompressed.");
@@ -74,78 +400,382 @@ impl<T: Mergeable> MergeFile<T> where T::Err: fmt::Debug {
             },
             None => {
                 warn!("Unable to aquire file extention for {}", filename);
-                return Err(Error::new(ErrorKind::Other, "File extension invalid?"))
+                return Err(io::Error::other("File extension invalid?"))
             },
         };
 
         let mut merge_file = MergeFile {
             filename: filename.to_string(),
-            filesize: filesize,
-            lines: BufReader::new(decompressor).lines(),
-            delimiter: delimiter,
-            key_index: key_index,
-            line: "".to_string(),
+            filesize,
+            reader: LineReader::Inline(BufReader::new(options.encoding.decode(decompressor))),
+            delimiter,
+            format: options.format,
+            key_index,
+            line: Vec::new(),
+            line_number: 0,
+            line_offset: 0,
+            buffer: Vec::new(),
+            bytes_read: 0,
             current_merge_key: default_key.clone(),
             beginning_merge_key: default_key.clone(),
             ending_merge_key: default_key.clone(),
-            key_type: key_type,
+            line_count: None,
+            byte_count: None,
+            checkpoints: Vec::new(),
+            checkpoint_every: None,
+            binary_search: true,
+            encoding: options.encoding,
+            compressed,
+            gzip,
+            tolerate_read_errors: options.tolerate_read_errors,
         };
 
-        if let Some(merge_key) = merge_file.next() {
-            merge_file.beginning_merge_key = merge_key;
+        if merge_file.advance()? {
+            merge_file.beginning_merge_key = merge_file.current_merge_key.clone();
             Ok(merge_file)
         } else {
-            Err(Error::new(ErrorKind::Other, format!("Error performing initial iteration over {:?}", filepath)))
+            // Empty files have nothing to merge, callers can choose to skip them based on the error kind
+            Err(Error::new(ErrorKind::UnexpectedEof, format!("Unable to read a first line from {:?}, is it empty?", filepath)))
         }
     }
 
-    pub fn fast_forward(&mut self, merge_start: &str) -> Result<&'static str,&'static str> {
+    /// Moves the decompression and line splitting onto a background thread, which reads ahead of the merge
+    /// a bounded number of lines at a time. The lines (and their order) are the same either way.
+    pub fn read_ahead(&mut self) {
+        self.reader = match mem::replace(&mut self.reader, LineReader::Detached) {
+            LineReader::Inline(reader) => LineReader::ReadAhead(ReadAhead::spawn(reader, self.filename.clone())),
+            reader => reader,
+        };
+    }
+
+    /// Keeps `line_number` counting from the start of the file, by not letting `fast_forward` binary search.
+    pub fn track_line_numbers(&mut self) {
+        self.binary_search = false;
+    }
+
+    /// Records a `Checkpoint` at every `lines`th line from here on, including the current one.
+    pub fn record_checkpoints(&mut self, lines: u64) {
+        self.checkpoint_every = Some(lines);
+        self.checkpoints.clear();
+
+        if self.line_number > 0 && self.line_number.is_multiple_of(lines) {
+            self.push_checkpoint();
+        }
+    }
+
+    fn push_checkpoint(&mut self) {
+        self.checkpoints.push(Checkpoint {
+            key: escape_key(&self.current_merge_key.to_bytes()),
+            line_number: self.line_number,
+            offset: self.line_offset,
+        });
+    }
+
+    /// Moves onto the first line with a merge key of at least `merge_start`, returning false if we hit EOF first.
+    /// With `checkpoints` it skips straight to the last one before `merge_start` rather than reading every line up to it.
+    ///
+    /// BGZF files with a `BlockIndex` then start decompressing from the last block before `merge_start`.
+    ///
+    /// Uncompressed files are then binary searched for `merge_start`, unless told to `track_line_numbers`.
+    /// As the lines skipped over aren't counted `line_number` counts on from 1 at the line the search lands on.
+    pub fn fast_forward(&mut self, merge_start: &str) -> io::Result<bool> {
         debug!("MergeFile<{}>: Fastforwarding -> {}", self.filename, merge_start);
-        let merge_start = merge_start.parse::<T>().unwrap();
+        let merge_start = merge_start.parse::<T>().map_err(|error| {
+            Error::new(ErrorKind::InvalidData, format!("{}: Invalid key to fast forward to {:?}: {:?}", self.filename, merge_start, error))
+        })?;
+
+        if self.current_merge_key < merge_start && !(self.skip_to_checkpoint(&merge_start)?
+                                                     && self.skip_to_block(&merge_start)?
+                                                     && self.binary_search_to(&merge_start)?) {
+            return Ok(false);
+        }
+
         while self.current_merge_key < merge_start {
-            if self.next().is_none() {
-                debug!("MergeFile<{}>: Fast forward hit EOF or failed to read, bailing", self.filename);
-                return Err("Hit EOF or failed to read");
+            if !self.advance()? {
+                debug!("MergeFile<{}>: Fast forward hit EOF, bailing", self.filename);
+                return Ok(false);
             }
         }
         debug!("MergeFile<{}>: Fastforwarded correctly!", self.filename);
-        Ok("Fastwarded correctly")
+        Ok(true)
     }
 
-    pub fn fast_forward_to_end(&mut self) {
-        while self.next().is_some() {
-            continue;
+    /// Moves onto the line of the last checkpoint with a merge key below `merge_start`, if it's past the current line.
+    /// Uncompressed files seek straight to it, anything else is read up to it without being split into lines.
+    /// Returns false if we hit EOF first.
+    fn skip_to_checkpoint(&mut self, merge_start: &T) -> io::Result<bool> {
+        let checkpoint = match self.checkpoints.iter()
+                                               .take_while(|checkpoint| parse_escaped_key::<T>(&checkpoint.key).is_some_and(|key| key < *merge_start))
+                                               .last() {
+            Some(checkpoint) if checkpoint.offset > self.line_offset => checkpoint.clone(),
+            _ => return Ok(true),
+        };
+
+        debug!("MergeFile<{}>: Skipping ahead to line {} (byte {})", self.filename, checkpoint.line_number, checkpoint.offset);
+        let line_offset = self.bytes_read;
+
+        let skipped = match self.reader {
+            LineReader::Inline(_) if self.seekable() => self.seek_to(checkpoint.offset),
+            LineReader::Inline(ref mut reader) => {
+                let skip = checkpoint.offset - self.bytes_read;
+                io::copy(&mut reader.by_ref().take(skip), &mut io::sink()).and_then(|skipped| match skipped {
+                    skipped if skipped < skip => Err(Error::new(ErrorKind::UnexpectedEof, "the file is shorter than its checkpoints say")),
+                    _ => Ok(()),
+                })
+            },
+            // The lines are already on their way from another thread
+            _ => return Ok(true),
+        };
+
+        if let Err(error) = skipped {
+            return self.read_failed(error, line_offset);
         }
+
+        self.bytes_read = checkpoint.offset;
+        self.line_number = checkpoint.line_number - 1;
+        self.advance()
     }
-}
 
-impl<T: Mergeable> Iterator for MergeFile<T> where T::Err: fmt::Debug {
-    type Item = T;
+    /// Moves onto the row the file's `BlockIndex` has for the last block starting before `merge_start`, if it's past the current line.
+    /// The file is reopened at the block and only the part of the block before the row is decompressed and skipped.
+    /// Returns false if we hit EOF first.
+    fn skip_to_block(&mut self, merge_start: &T) -> io::Result<bool> {
+        // Block indexes are only written for delimited outputs
+        let blocked = match self.reader {
+            LineReader::Inline(_) => self.gzip && self.encoding == InputEncoding::Bytes && self.format == InputFormat::Delimited,
+            _ => false,
+        };
+        if !blocked {
+            return Ok(true);
+        }
 
-    // This is just a thin wrapper around Lines
-    // It saves the line, extracts the merge_key and passes them upstream
-    fn next(&mut self) -> Option<T> {
-        match self.lines.next() {
-            Some(Ok(line)) => {
-                // Clone all required parts and return the new merge key and the line
-                self.line = line.clone();
+        let index = match BlockIndex::load_for(Path::new(&self.filename), KeyColumn { delimiter: self.delimiter, key_index: self.key_index }) {
+            Some(index) => index,
+            None => return Ok(true),
+        };
 
-                let new_merge_key = line.splitn(self.key_index + 2, self.delimiter).next().unwrap();
+        let entry = match index.entries.iter()
+                                       .take_while(|entry| parse_escaped_key::<T>(&entry.key).is_some_and(|key| key < *merge_start))
+                                       .last() {
+            Some(entry) if entry.offset > self.line_offset => entry.clone(),
+            _ => return Ok(true),
+        };
 
-                self.current_merge_key = new_merge_key.parse::<T>().unwrap();
-                Some(self.current_merge_key.clone())
-            },
-            Some(Err(_)) => {
-                // Problems reading the file
-                debug!("Problem reading the next line for {}", self.filename);
-                None
-            },
-            None => {
+        debug!("MergeFile<{}>: Skipping ahead to line {} (byte {}) in the block at byte {}",
+               self.filename, entry.line_number, entry.offset, entry.block_offset);
+        let line_offset = self.bytes_read;
+
+        let skipped = File::open(&self.filename).and_then(|mut file| {
+            file.seek(SeekFrom::Start(entry.block_offset))?;
+            let decompressor: Box<dyn Read + Send> = Box::new(MultiGzDecoder::new(file)?);
+            let mut reader = BufReader::new(decompressor);
+
+            match io::copy(&mut reader.by_ref().take(entry.block_position), &mut io::sink())? {
+                skipped if skipped < entry.block_position => Err(Error::new(ErrorKind::UnexpectedEof, "the block is shorter than its index says")),
+                _ => Ok(reader),
+            }
+        });
+
+        match skipped {
+            Ok(reader) => self.reader = LineReader::Inline(reader),
+            Err(error) => return self.read_failed(error, line_offset),
+        }
+
+        self.bytes_read = entry.offset;
+        self.line_number = entry.line_number - 1;
+        self.advance()
+    }
+
+    /// Binary searches the rest of an uncompressed file for the last line before `merge_start`,
+    /// moving onto it (or leaving the file where it is if the search doesn't get any further).
+    ///
+    /// Each probe seeks into the file, skips to the start of the next line and reads its key. Returns false if we hit EOF.
+    fn binary_search_to(&mut self, merge_start: &T) -> io::Result<bool> {
+        if !self.binary_search || !self.seekable() {
+            return Ok(true);
+        }
+
+        let mut file = BufReader::new(File::open(&self.filename)?);
+
+        // The current line is before merge_start, the first line at or after it starts somewhere up to the end
+        let mut low = self.line_offset;
+        let mut high = file.get_ref().metadata()?.len();
+
+        let mut delimiter = [0; 4];
+        let delimiter = self.delimiter.encode_utf8(&mut delimiter).as_bytes();
+        let mut key = self.current_merge_key.clone();
+        let mut line = Vec::new();
+
+        while high - low > BINARY_SEARCH_LINEAR_BYTES {
+            let middle = low + (high - low) / 2;
+
+            // Resync on the first line starting at or after the middle
+            file.seek(SeekFrom::Start(middle - 1))?;
+            line.clear();
+            let line_start = middle - 1 + file.read_until(b'\n', &mut line)? as u64;
+
+            line.clear();
+            if line_start >= high || file.read_until(b'\n', &mut line)? == 0 {
+                high = middle;
+                continue;
+            }
+
+            while line.last() == Some(&b'\n') || line.last() == Some(&b'\r') {
+                line.pop();
+            }
+
+            if !key.parse_from(&self.format.column(&line, delimiter, self.key_index).unwrap_or_default()) {
+                // Leave it to the line by line read to complain about
+                debug!("MergeFile<{}>: Invalid merge key at byte {}, giving up on the binary search", self.filename, line_start);
+                break;
+            }
+
+            if key < *merge_start {
+                low = line_start;
+            } else {
+                high = line_start;
+            }
+        }
+
+        if low <= self.line_offset {
+            return Ok(true);
+        }
+
+        debug!("MergeFile<{}>: Binary searched ahead to byte {}", self.filename, low);
+        if let Err(error) = self.seek_to(low) {
+            let line_offset = self.bytes_read;
+            return self.read_failed(error, line_offset);
+        }
+
+        self.bytes_read = low;
+        self.line_number = 0;
+        self.advance()
+    }
+
+    /// Whether the lines can be read from any offset into the file, rather than only in order.
+    fn seekable(&self) -> bool {
+        match self.reader {
+            LineReader::Inline(_) => !self.compressed && self.encoding == InputEncoding::Bytes,
+            _ => false,
+        }
+    }
+
+    /// Reopens a `seekable` file, reading on from `offset`.
+    fn seek_to(&mut self, offset: u64) -> io::Result<()> {
+        let mut file = File::open(&self.filename)?;
+        file.seek(SeekFrom::Start(offset))?;
+
+        let file: Box<dyn Read + Send> = Box::new(file);
+        self.reader = LineReader::Inline(BufReader::new(file));
+        Ok(())
+    }
+
+    pub fn fast_forward_to_end(&mut self) -> io::Result<()> {
+        while self.advance()? {
+            continue;
+        }
+        Ok(())
+    }
+
+    /// Moves onto the next line, returning false at EOF.
+    ///
+    /// A failed read is returned as an error naming the file and the byte offset it failed at
+    /// (or with `InputOptions::tolerate_read_errors`, counted and treated as EOF). Either way the file reads no further.
+    /// A line whose merge key doesn't parse is an `InvalidData` error naming the file and line.
+    ///
+    /// The line is read into a reusable buffer and the merge key parsed straight out of it,
+    /// so once the buffers have grown to fit the longest line nothing is allocated per line.
+    pub fn advance(&mut self) -> io::Result<bool> {
+        let line_offset = self.bytes_read;
+        self.buffer.clear();
+
+        let read_result = match self.reader {
+            LineReader::Inline(ref mut reader) => reader.read_until(b'\n', &mut self.buffer),
+            LineReader::ReadAhead(ref mut read_ahead) => read_ahead.read_line(&mut self.buffer),
+            LineReader::Detached => Ok(0),
+        };
+
+        match read_result {
+            Ok(0) => {
                 // We've reached the end of the file, save it's merge_key
                 debug!("Reached EOF for {}", self.filename);
-                self.ending_merge_key = self.current_merge_key.clone();
-                None
+                self.ending_merge_key.clone_from(&self.current_merge_key);
+                self.line_count = Some(self.line_number);
+                self.byte_count = Some(self.bytes_read);
+                Ok(false)
+            },
+            Ok(bytes) => {
+                // Keep the previous line's allocation around for the next read
+                mem::swap(&mut self.line, &mut self.buffer);
+                self.bytes_read += bytes as u64;
+                self.line_offset = line_offset;
+                self.line_number += 1;
+
+                // Strip the line ending the same way BufRead::lines does
+                if self.line.last() == Some(&b'\n') {
+                    self.line.pop();
+                    if self.line.last() == Some(&b'\r') {
+                        self.line.pop();
+                    }
+                }
+
+                let mut delimiter = [0; 4];
+                let delimiter = self.delimiter.encode_utf8(&mut delimiter).as_bytes();
+                let key = self.format.column(&self.line, delimiter, self.key_index).unwrap_or_default();
+
+                if !self.current_merge_key.parse_from(&key) {
+                    return Err(Error::new(ErrorKind::InvalidData, format!("{}: Line {} (byte {}) has an invalid merge key {:?}",
+                                                                          self.filename, self.line_number, line_offset, String::from_utf8_lossy(&key))));
+                }
+
+                if let Some(every) = self.checkpoint_every {
+                    if self.line_number.is_multiple_of(every) {
+                        self.push_checkpoint();
+                    }
+                }
+
+                Ok(true)
             },
+            Err(error) => self.read_failed(error, line_offset),
+        }
+    }
+
+    /// Stops reading after a failed read at `line_offset`, see `advance`.
+    fn read_failed(&mut self, error: Error, line_offset: u64) -> io::Result<bool> {
+        // Whatever state the reader was left in, don't read any further
+        self.reader = LineReader::Detached;
+        let message = format!("{}: Unable to read past line {} (byte {}): {}", self.filename, self.line_number, line_offset, error);
+
+        if self.tolerate_read_errors {
+            warn!("{}, treating it as the end of the file", message);
+            TOLERATED_READ_ERRORS.fetch_add(1, Ordering::SeqCst);
+            self.ending_merge_key.clone_from(&self.current_merge_key);
+            Ok(false)
+        } else {
+            Err(Error::new(error.kind(), message))
+        }
+    }
+}
+
+impl<T> MergeFile<T> {
+    /// Returns the value of the column at `index` of the current line, split up as the file's `InputFormat` says.
+    pub fn column(&self, index: usize) -> Option<Cow<'_, [u8]>> {
+        let mut delimiter = [0; 4];
+        let delimiter = self.delimiter.encode_utf8(&mut delimiter).as_bytes();
+        self.format.column(&self.line, delimiter, index)
+    }
+}
+
+impl<T: Mergeable> Iterator for MergeFile<T> where T::Err: fmt::Debug {
+    type Item = T;
+
+    // A thin wrapper around advance, for when a copy of the merge key is wanted.
+    // Iterators can't return errors, so a failed read panics.
+    fn next(&mut self) -> Option<T> {
+        match self.advance() {
+            Ok(true) => Some(self.current_merge_key.clone()),
+            Ok(false) => None,
+            Err(error) => panic!("{}", error),
         }
     }
 }
@@ -162,25 +792,16 @@ impl<T: fmt::Display> fmt::Display for MergeFile<T> {
     }
 }
 
+// Compare the merge keys once, this is called for every merged line
 impl<T: cmp::Ord + fmt::Display> cmp::Ord for MergeFile<T> {
     fn cmp(&self, other: &Self) -> cmp::Ordering {
-        if self.current_merge_key > other.current_merge_key {
-            return cmp::Ordering::Greater;
-        } else if self.current_merge_key < other.current_merge_key {
-            return cmp::Ordering::Less;
-        }
-        cmp::Ordering::Equal
+        self.current_merge_key.cmp(&other.current_merge_key)
     }
 }
 
 impl<T: cmp::PartialOrd + fmt::Display> cmp::PartialOrd for MergeFile<T> {
     fn partial_cmp(&self, other: &Self) -> Option<cmp::Ordering> {
-        if self.current_merge_key > other.current_merge_key {
-            return Some(cmp::Ordering::Greater);
-        } else if self.current_merge_key < other.current_merge_key {
-            return Some(cmp::Ordering::Less);
-        }
-        Some(cmp::Ordering::Equal)
+        self.current_merge_key.partial_cmp(&other.current_merge_key)
     }
 }
 
@@ -198,25 +819,25 @@ impl<T: cmp::PartialEq> cmp::PartialEq for MergeFile<T> {
 #[cfg(test)]
 mod tests {
     use std::io::prelude::*;
-    use std::io::BufWriter;
+    use std::io::ErrorKind;
     use std::path::Path;
     use std::fs::File;
     use std::fs;
 
-    use super::MergeFile;
-    use settings::KeyType;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
 
-    fn create_file(filename: &str, contents: String) {
-        let mut temp_file = BufWriter::new(File::create(Path::new(filename)).unwrap());
-        temp_file.write(contents.as_ref()).unwrap();
-        let _ = temp_file.flush();
-    }
+    use super::{escape_key, parse_escaped_key, tolerated_read_errors, unescape_key, ByteString, Checkpoint, InputOptions, MergeFile};
+    use bgzf::{BgzfWriter, BlockIndex, KeyColumn};
+    use encoding::InputEncoding;
+    use test_helpers::{create_file, TempDir};
 
     #[test]
     fn new() {
+        let dir = TempDir::new("new");
+
         // Set up the test data
-        // TODO: Add the PID of the process into the filename
-        let test_filename_1 = "/tmp/test_new.file1.tsv";
+        let test_filename_1: &str = &dir.join("file1.tsv");
         let test_contents_1 = format!("{}\t{}\t{}\n\
                                        {}\t{}\t{}\n\
                                        {}\t{}\t{}\n",
@@ -227,32 +848,31 @@ mod tests {
         create_file(test_filename_1, test_contents_1);
 
         // Add the first file and sanity check
-        let result = MergeFile::new(&test_filename_1, '\t', 0, "0".to_string(), KeyType::String);
+        let result = MergeFile::new(test_filename_1, '\t', 0, "0".to_string());
         assert!(result.is_ok());
 
         let mergefile = result.unwrap();
         assert_eq!(mergefile.filename, test_filename_1);
 
-        let test_file_1 = File::open(&test_filename_1).unwrap();
+        let test_file_1 = File::open(test_filename_1).unwrap();
         let test_filesize_1 = test_file_1.metadata().unwrap().len();
         assert_eq!(mergefile.filesize, test_filesize_1);
 
         assert_eq!(mergefile.delimiter, '\t');
         assert_eq!(mergefile.key_index, 0);
 
-        assert_eq!(mergefile.line, "123\tbbb\t999");
+        assert_eq!(mergefile.line, b"123\tbbb\t999");
         assert_eq!(mergefile.beginning_merge_key, "123");
         assert_eq!(mergefile.current_merge_key, "123");
         assert_eq!(mergefile.ending_merge_key, "0");
-
-        let _ = fs::remove_file(test_filename_1);
     }
 
     #[test]
     fn fast_forward() {
+        let dir = TempDir::new("fast_forward");
+
         // Set up the test data
-        // TODO: Add the PID of the process into the filename
-        let test_filename_1 = "/tmp/test_fast_forward.file1.tsv";
+        let test_filename_1: &str = &dir.join("file1.tsv");
         let test_contents_1 = format!("{}\t{}\t{}\n\
                                        {}\t{}\t{}\n\
                                        {}\t{}\t{}\n",
@@ -263,31 +883,30 @@ mod tests {
         create_file(test_filename_1, test_contents_1);
 
         // Add the first file and sanity check
-        let mut mergefile = MergeFile::new(&test_filename_1, '\t', 0, "0".to_string(), KeyType::String).unwrap();
+        let mut mergefile = MergeFile::new(test_filename_1, '\t', 0, "0".to_string()).unwrap();
 
         // Test a fast forward to the middle of the file
-        assert!(mergefile.fast_forward(&"124".to_string()).is_ok());
-        assert_eq!(mergefile.line, "124\tbbb\t999");
+        assert!(mergefile.fast_forward("124").is_ok());
+        assert_eq!(mergefile.line, b"124\tbbb\t999");
         assert_eq!(mergefile.beginning_merge_key, "123");
         assert_eq!(mergefile.current_merge_key, "124");
         assert_eq!(mergefile.ending_merge_key, "0");
 
         // Test a fast forward past the end of the file
-        assert!(mergefile.fast_forward(&"126".to_string()).is_err());
-        assert_eq!(mergefile.line, "125\tbbb\t999");
+        assert!(!mergefile.fast_forward("126").unwrap());
+        assert_eq!(mergefile.line, b"125\tbbb\t999");
         assert_eq!(mergefile.beginning_merge_key, "123");
         assert_eq!(mergefile.current_merge_key, "125");
         assert_eq!(mergefile.ending_merge_key, "125");
-
-        let _ = fs::remove_file(test_filename_1);
     }
 
 
     #[test]
     fn fast_forward_to_end() {
+        let dir = TempDir::new("fast_forward_to_end");
+
         // Set up the test data
-        // TODO: Add the PID of the process into the filename
-        let test_filename_1 = "/tmp/test_fast_forward_to_end.file1.tsv";
+        let test_filename_1: &str = &dir.join("file1.tsv");
         let test_contents_1 = format!("{}\t{}\t{}\n\
                                        {}\t{}\t{}\n\
                                        {}\t{}\t{}\n",
@@ -298,27 +917,26 @@ mod tests {
         create_file(test_filename_1, test_contents_1);
 
         // Add the first file and sanity check
-        let result = MergeFile::new(&test_filename_1, '\t', 0, "0".to_string(), KeyType::String);
+        let result = MergeFile::new(test_filename_1, '\t', 0, "0".to_string());
         assert!(result.is_ok());
 
         let mut mergefile = result.unwrap();
 
         // Ensure the current line is the last one in the above contents
-        mergefile.fast_forward_to_end();
-        assert_eq!(mergefile.line, "125\tbbb\t999");
+        mergefile.fast_forward_to_end().unwrap();
+        assert_eq!(mergefile.line, b"125\tbbb\t999");
         assert_eq!(mergefile.beginning_merge_key, "123");
         assert_eq!(mergefile.current_merge_key, "125");
         assert_eq!(mergefile.ending_merge_key, "125");
-
-        let _ = fs::remove_file(test_filename_1);
     }
 
     #[test]
     fn impl_iterator() {
+        let dir = TempDir::new("impl_iterator");
+
         // Set up the test data
-        // TODO: Add the PID of the process into the filename
         // TODO: Can we create temporary files?
-        let test_filename_1 = "/tmp/test_impl_formatting.file1.tsv";
+        let test_filename_1: &str = &dir.join("file1.tsv");
         let test_contents_1 = format!("{}\t{}\t{}\n\
                                        {}\t{}\t{}\n\
                                        {}\t{}\t{}\n",
@@ -329,22 +947,27 @@ mod tests {
         create_file(test_filename_1, test_contents_1);
 
         // Add the first file and sanity check
-        let result = MergeFile::new(&test_filename_1, '\t', 0, "0".to_string(), KeyType::String);
+        let result = MergeFile::new(test_filename_1, '\t', 0, "0".to_string());
         assert!(result.is_ok());
 
         let mut mergefile = result.unwrap();
 
         // Test line 1
-        assert_eq!(mergefile.line, "123\tbbb\t999");
+        assert_eq!(mergefile.line, b"123\tbbb\t999");
         assert_eq!(mergefile.beginning_merge_key, "123");
         assert_eq!(mergefile.current_merge_key, "123");
         assert_eq!(mergefile.ending_merge_key, "0");
 
+        assert_eq!(mergefile.line_number, 1);
+        assert_eq!(mergefile.line_offset, 0);
+
         // Test line 2
         let result = mergefile.next();
         assert_eq!(result, Some("124".to_string()));
+        assert_eq!(mergefile.line_number, 2);
+        assert_eq!(mergefile.line_offset, 12);
 
-        assert_eq!(mergefile.line, "124\tbbb\t999");
+        assert_eq!(mergefile.line, b"124\tbbb\t999");
         assert_eq!(mergefile.beginning_merge_key, "123");
         assert_eq!(mergefile.current_merge_key, "124");
         assert_eq!(mergefile.ending_merge_key, "0");
@@ -353,7 +976,7 @@ mod tests {
         let result = mergefile.next();
         assert_eq!(result, Some("125".to_string()));
 
-        assert_eq!(mergefile.line, "125\tbbb\t999");
+        assert_eq!(mergefile.line, b"125\tbbb\t999");
         assert_eq!(mergefile.beginning_merge_key, "123");
         assert_eq!(mergefile.current_merge_key, "125");
         assert_eq!(mergefile.ending_merge_key, "0");
@@ -362,17 +985,286 @@ mod tests {
         let result = mergefile.next();
         assert_eq!(result, None);
 
-        assert_eq!(mergefile.line, "125\tbbb\t999");
+        assert_eq!(mergefile.line, b"125\tbbb\t999");
         assert_eq!(mergefile.beginning_merge_key, "123");
         assert_eq!(mergefile.current_merge_key, "125");
         assert_eq!(mergefile.ending_merge_key, "125");
     }
 
+    #[test]
+    fn read_ahead() {
+        let dir = TempDir::new("read_ahead");
+
+        // Enough lines to span several batches, with a few CRLF endings thrown in
+        let test_filename_1: &str = &dir.join("file1.tsv.gz");
+        let mut encoder = GzEncoder::new(File::create(test_filename_1).unwrap(), Compression::Default);
+        for line in 0..3000 {
+            let ending = if line % 7 == 0 { "\r\n" } else { "\n" };
+            write!(encoder, "{:05}\tvalue{}{}", line, line, ending).unwrap();
+        }
+        encoder.finish().unwrap();
+
+        let read_all = |read_ahead: bool| {
+            let mut mergefile = MergeFile::new(test_filename_1, '\t', 0, 0u32).unwrap();
+            if read_ahead {
+                mergefile.read_ahead();
+            }
+
+            let mut lines = vec![(mergefile.current_merge_key, mergefile.line.clone(), mergefile.line_number, mergefile.line_offset)];
+            while let Some(merge_key) = mergefile.next() {
+                lines.push((merge_key, mergefile.line.clone(), mergefile.line_number, mergefile.line_offset));
+            }
+
+            assert_eq!(mergefile.ending_merge_key, 2999);
+            lines
+        };
+
+        let inline_lines = read_all(false);
+        assert_eq!(inline_lines.len(), 3000);
+        assert_eq!(read_all(true), inline_lines);
+    }
+
+    #[test]
+    fn latin1_bytes() {
+        let dir = TempDir::new("latin1_bytes");
+
+        // "café" and "caff" in Latin-1, neither line is valid UTF-8
+        let test_filename_1: &str = &dir.join("file1.tsv");
+        fs::write(test_filename_1, b"caf\xe9\tna\xefve\ncaff\t\xff\n").unwrap();
+
+        let mut mergefile = MergeFile::new(test_filename_1, '\t', 0, ByteString::default()).unwrap();
+        assert_eq!(mergefile.line, b"caf\xe9\tna\xefve");
+        assert_eq!(mergefile.current_merge_key, ByteString(b"caf\xe9".to_vec()));
+        assert_eq!(mergefile.column(1).as_deref(), Some(&b"na\xefve"[..]));
+
+        // Keys compare byte by byte, so 0xe9 sorts after 'f'
+        assert!(mergefile.advance().unwrap());
+        assert_eq!(mergefile.line, b"caff\t\xff");
+        assert!(mergefile.current_merge_key < ByteString(b"caf\xe9".to_vec()));
+        assert!(!mergefile.advance().unwrap());
+
+        // Transcoded to UTF-8 as it's read
+        let latin1 = InputOptions { encoding: InputEncoding::Latin1, ..InputOptions::default() };
+        let mut mergefile = MergeFile::open(test_filename_1, '\t', 0, "0".to_string(), latin1).unwrap();
+        assert_eq!(mergefile.line, "café\tnaïve".as_bytes());
+        assert_eq!(mergefile.current_merge_key, "café");
+        assert_eq!(mergefile.next(), Some("caff".to_string()));
+        assert_eq!(mergefile.line, "caff\tÿ".as_bytes());
+    }
+
+    #[test]
+    fn read_errors() {
+        let dir = TempDir::new("read_errors");
+
+        let test_filename_1: &str = &dir.join("file1.tsv");
+        fs::write(test_filename_1, b"1\tok\n2\tcaf\xe9\n3\tok\n").unwrap();
+
+        // Rather than stopping early as if the file ended after the first line
+        let utf8 = InputOptions { encoding: InputEncoding::Utf8, ..InputOptions::default() };
+        let mut mergefile = MergeFile::open(test_filename_1, '\t', 0, 0u32, utf8).unwrap();
+        let error = mergefile.fast_forward_to_end().unwrap_err();
+        assert_eq!(error.to_string(), format!("{}: Unable to read past line 1 (byte 5): Invalid UTF-8 at byte 10 of the Utf8 input", test_filename_1));
+
+        // Nothing more is read once it has failed
+        assert!(!mergefile.advance().unwrap());
+
+        // Unless told to tolerate it, where it's counted and the file ends early
+        let tolerant = InputOptions { tolerate_read_errors: true, ..utf8 };
+        let mut mergefile = MergeFile::open(test_filename_1, '\t', 0, 0u32, tolerant).unwrap();
+        let tolerated = tolerated_read_errors();
+        mergefile.fast_forward_to_end().unwrap();
+        assert_eq!(mergefile.ending_merge_key, 1);
+        assert!(tolerated_read_errors() > tolerated);
+    }
+
+    #[test]
+    fn invalid_keys() {
+        let dir = TempDir::new("invalid_keys");
+
+        let test_filename_1: &str = &dir.join("file1.tsv");
+        create_file(test_filename_1, "1\tok\n2\tok\nabc\tok\n4\tok\n");
+
+        let mut mergefile = MergeFile::new(test_filename_1, '\t', 0, 0u32).unwrap();
+        assert!(mergefile.advance().unwrap());
+        let error = mergefile.advance().unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+        assert_eq!(error.to_string(), format!("{}: Line 3 (byte 10) has an invalid merge key \"abc\"", test_filename_1));
+
+        let mut mergefile = MergeFile::new(test_filename_1, '\t', 0, 0u32).unwrap();
+        assert_eq!(mergefile.fast_forward("-1").unwrap_err().kind(), ErrorKind::InvalidData);
+        assert_eq!(mergefile.fast_forward("3").unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn read_ahead_errors() {
+        let dir = TempDir::new("read_ahead_errors");
+
+        let test_filename_1: &str = &dir.join("file1.tsv");
+        fs::write(test_filename_1, b"1\tok\n2\tok\n3\tok\n4\tok\n5\tcaf\xe9\n6\tok\n").unwrap();
+
+        let utf8 = InputOptions { encoding: InputEncoding::Utf8, ..InputOptions::default() };
+        let read_all = |options: InputOptions, read_ahead: bool| {
+            let mut mergefile = MergeFile::open(test_filename_1, '\t', 0, 0u32, options).unwrap();
+            if read_ahead {
+                mergefile.read_ahead();
+            }
+
+            let mut keys = vec![mergefile.current_merge_key];
+            let result = loop {
+                match mergefile.advance() {
+                    Ok(true) => keys.push(mergefile.current_merge_key),
+                    Ok(false) => break Ok(()),
+                    Err(error) => break Err(error.to_string()),
+                }
+            };
+            (keys, result)
+        };
+
+        // The lines read before the error are kept, and it's reported against the same line as reading inline
+        let inline = read_all(utf8, false);
+        assert_eq!(inline.0, vec![1, 2, 3, 4]);
+        assert!(inline.1.as_ref().unwrap_err().contains("Unable to read past line 4 (byte 20)"));
+        assert_eq!(read_all(utf8, true), inline);
+
+        let tolerant = InputOptions { tolerate_read_errors: true, ..utf8 };
+        assert_eq!(read_all(tolerant, false), (vec![1, 2, 3, 4], Ok(())));
+        assert_eq!(read_all(tolerant, true), (vec![1, 2, 3, 4], Ok(())));
+    }
+
+    #[test]
+    fn checkpoints() {
+        let dir = TempDir::new("checkpoints");
+
+        let contents = (1..11).map(|key| format!("{}\tline {}\n", key * 10, key)).collect::<String>();
+
+        let test_filename_1: &str = &dir.join("file1.tsv");
+        create_file(test_filename_1, contents.clone());
+
+        let test_filename_2: &str = &dir.join("file2.tsv.gz");
+        let mut encoder = GzEncoder::new(File::create(test_filename_2).unwrap(), Compression::Default);
+        encoder.write_all(contents.as_bytes()).unwrap();
+        encoder.finish().unwrap();
+
+        // Every third line's key and where it starts, along with the totals once read to the end
+        let mut mergefile = MergeFile::new(test_filename_1, '\t', 0, 0u32).unwrap();
+        mergefile.record_checkpoints(3);
+        mergefile.fast_forward_to_end().unwrap();
+        assert_eq!(mergefile.line_count, Some(10));
+        assert_eq!(mergefile.byte_count, Some(contents.len() as u64));
+        assert_eq!(mergefile.checkpoints, vec![
+            Checkpoint { key: "30".to_string(), line_number: 3, offset: 20 },
+            Checkpoint { key: "60".to_string(), line_number: 6, offset: 50 },
+            Checkpoint { key: "90".to_string(), line_number: 9, offset: 80 },
+        ]);
+
+        // Seeked to in the plain file and skipped to in the gzip, ending up on the same line either way
+        for test_filename in &[test_filename_1, test_filename_2] {
+            let mut skipping = MergeFile::new(test_filename, '\t', 0, 0u32).unwrap();
+            skipping.checkpoints = mergefile.checkpoints.clone();
+
+            assert!(skipping.fast_forward("75").unwrap());
+            assert_eq!(skipping.current_merge_key, 80);
+            assert_eq!(skipping.line, b"80\tline 8");
+            assert_eq!((skipping.line_number, skipping.line_offset), (8, 70));
+
+            // A checkpoint on the key itself can't be skipped to, earlier lines may share it
+            let mut skipping = MergeFile::new(test_filename, '\t', 0, 0u32).unwrap();
+            skipping.checkpoints = mergefile.checkpoints.clone();
+            assert!(skipping.fast_forward("60").unwrap());
+            assert_eq!((skipping.line_number, skipping.line_offset), (6, 50));
+
+            skipping.fast_forward_to_end().unwrap();
+            assert_eq!(skipping.ending_merge_key, 100);
+        }
+    }
+
+    #[test]
+    fn escaped_keys() {
+        let key = b"a\\b\tc\xc3\xa9\xff:";
+        assert_eq!(escape_key(key), "a\\\\b\\x09c\u{e9}\\xff:");
+        assert_eq!(unescape_key(&escape_key(key)), Some(key.to_vec()));
+        assert_eq!(parse_escaped_key::<ByteString>(&escape_key(key)), Some(ByteString(key.to_vec())));
+        assert_eq!(parse_escaped_key::<u32>("123"), Some(123));
+        assert_eq!(parse_escaped_key::<u32>("\\xff"), None);
+
+        // Anything escape_key wouldn't write is rejected
+        assert_eq!(unescape_key("a\\b"), None);
+        assert_eq!(unescape_key("a\\x1"), None);
+        assert_eq!(unescape_key("a\\"), None);
+    }
+
+    #[test]
+    fn binary_search() {
+        let dir = TempDir::new("binary_search");
+
+        // Even keys, several repeated across lines, over enough bytes to search
+        let contents = (0..40000).map(|line| format!("{}\tline {}\n", (line / 4) * 2, line)).collect::<String>();
+        let offset_of = |line: usize| contents.lines().take(line).map(|line| line.len() as u64 + 1).sum::<u64>();
+
+        let test_filename_1: &str = &dir.join("file1.tsv");
+        create_file(test_filename_1, contents.clone());
+
+        for &(merge_start, line) in &[("5000", 10000), ("5001", 10004), ("2", 4), ("19998", 39996)] {
+            let mut mergefile = MergeFile::new(test_filename_1, '\t', 0, 0u32).unwrap();
+            assert!(mergefile.fast_forward(merge_start).unwrap());
+            assert_eq!(mergefile.line, format!("{}\tline {}", (line / 4) * 2, line).into_bytes());
+            assert_eq!(mergefile.line_offset, offset_of(line));
+
+            // The same line, counted from the start
+            let mut counting = MergeFile::new(test_filename_1, '\t', 0, 0u32).unwrap();
+            counting.track_line_numbers();
+            assert!(counting.fast_forward(merge_start).unwrap());
+            assert_eq!(counting.line_offset, offset_of(line));
+            assert_eq!(counting.line_number, line as u64 + 1);
+        }
+
+        let mut mergefile = MergeFile::new(test_filename_1, '\t', 0, 0u32).unwrap();
+        assert!(!mergefile.fast_forward("20000").unwrap());
+        assert_eq!(mergefile.ending_merge_key, 19998);
+    }
+
+    #[test]
+    fn block_index() {
+        let dir = TempDir::new("block_index");
+
+        let contents = (0..40000).map(|line| format!("{}\tline {}\n", (line / 4) * 2, line)).collect::<String>();
+        let offset_of = |line: usize| contents.lines().take(line).map(|line| line.len() as u64 + 1).sum::<u64>();
+
+        let test_filename_1: &str = &dir.join("file1.tsv.bgz");
+        let mut writer = BgzfWriter::indexed(File::create(test_filename_1).unwrap(), KeyColumn { delimiter: '\t', key_index: 0 });
+        for (line, row) in contents.lines().enumerate() {
+            writer.index_row(&((line as u32 / 4) * 2));
+            writer.write_all(row.as_bytes()).unwrap();
+            writer.write_all(b"\n").unwrap();
+        }
+        writer.try_finish().unwrap();
+        writer.index().unwrap().write_for(Path::new(test_filename_1)).unwrap();
+        assert!(writer.index().unwrap().entries.len() > 5);
+
+        // Lands on the same line with or without the index, counting lines from the start either way
+        for &(merge_start, line) in &[("5000", 10000), ("5001", 10004), ("2", 4), ("19998", 39996)] {
+            let mut mergefile = MergeFile::new(test_filename_1, '\t', 0, 0u32).unwrap();
+            assert!(mergefile.fast_forward(merge_start).unwrap());
+            assert_eq!(mergefile.line, format!("{}\tline {}", (line / 4) * 2, line).into_bytes());
+            assert_eq!((mergefile.line_number, mergefile.line_offset), (line as u64 + 1, offset_of(line)));
+
+            mergefile.fast_forward_to_end().unwrap();
+            assert_eq!(mergefile.line_count, Some(40000));
+            assert_eq!(mergefile.byte_count, Some(contents.len() as u64));
+        }
+
+        let _ = fs::remove_file(BlockIndex::path_for(Path::new(test_filename_1)));
+        let mut mergefile = MergeFile::new(test_filename_1, '\t', 0, 0u32).unwrap();
+        assert!(mergefile.fast_forward("5001").unwrap());
+        assert_eq!((mergefile.line_number, mergefile.line_offset), (10005, offset_of(10004)));
+    }
+
     #[test]
     fn impl_formatting() {
+        let dir = TempDir::new("impl_formatting");
+
         // Set up the test data
-        // TODO: Add the PID of the process into the filename
-        let test_filename_1 = "/tmp/test_impl_formatting.file1.tsv";
+        let test_filename_1: &str = &dir.join("file1.tsv");
         let test_contents_1 = format!("{}\t{}\t{}\n\
                                        {}\t{}\t{}\n\
                                        {}\t{}\t{}\n",
@@ -383,21 +1275,20 @@ mod tests {
         create_file(test_filename_1, test_contents_1);
 
         // Add the first file and sanity check
-        let result = MergeFile::new(&test_filename_1, '\t', 0, "0".to_string(), KeyType::String);
+        let result = MergeFile::new(test_filename_1, '\t', 0, "0".to_string());
         assert!(result.is_ok());
 
         let mergefile = result.unwrap();
         assert_eq!(format!("{}", mergefile), test_filename_1); // Test fmt::Display
         assert_eq!(format!("{:?}", mergefile), test_filename_1); // Test fmt::Debug
-
-        let _ = fs::remove_file(test_filename_1);
     }
 
     #[test]
     fn impl_ordering_and_equality() {
+        let dir = TempDir::new("impl_ordering_and_equality");
+
         // Set up the test data
-        // TODO: Add the PID of the process into the filename
-        let test_filename_1 = "/tmp/test_impl_ordering.file1.tsv";
+        let test_filename_1: &str = &dir.join("file1.tsv");
         let test_contents_1 = format!("{}\t{}\t{}\n\
                                        {}\t{}\t{}\n\
                                        {}\t{}\t{}\n",
@@ -407,8 +1298,7 @@ mod tests {
 
         create_file(test_filename_1, test_contents_1);
 
-        // TODO: Add the PID of the process into the filename
-        let test_filename_2 = "/tmp/test_impl_ordering.file2.tsv";
+        let test_filename_2: &str = &dir.join("file2.tsv");
         let test_contents_2 = format!("{}\t{}\t{}\n\
                                        {}\t{}\t{}\n\
                                        {}\t{}\t{}\n",
@@ -419,19 +1309,19 @@ mod tests {
         create_file(test_filename_2, test_contents_2);
 
         // Create the first file and initialise it
-        let result = MergeFile::new(&test_filename_1, '\t', 0, "0".to_string(), KeyType::String);
+        let result = MergeFile::new(test_filename_1, '\t', 0, "0".to_string());
         assert!(result.is_ok());
 
         let mut mergefile_1 = result.unwrap();
-        let result = mergefile_1.fast_forward(&"123".to_string());
+        let result = mergefile_1.fast_forward("123");
         assert!(result.is_ok());
 
         // Create the second file and initialise it
-        let result = MergeFile::new(&test_filename_1, '\t', 0, "0".to_string(), KeyType::String);
+        let result = MergeFile::new(test_filename_1, '\t', 0, "0".to_string());
         assert!(result.is_ok());
 
         let mut mergefile_2 = result.unwrap();
-        let result = mergefile_2.fast_forward(&"124".to_string());
+        let result = mergefile_2.fast_forward("124");
         assert!(result.is_ok());
 
         assert!(mergefile_1 < mergefile_2); // File 1 (123) < File 2 (124)
@@ -450,8 +1340,5 @@ mod tests {
         let result = mergefile_2.next();
         assert!(result.is_some());
         assert!(mergefile_1 == mergefile_2); // File 1 (125) == File 2 (125)
-
-        let _ = fs::remove_file(test_filename_1);
-        let _ = fs::remove_file(test_filename_2);
     }
 }
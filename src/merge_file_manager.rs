@@ -1,16 +1,17 @@
-use std::collections::BinaryHeap;
 use std::io::{Error, ErrorKind};
-use std::collections::HashMap;
-use std::path::PathBuf;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
 use std::time;
-use std::fmt;
 use std::fs;
+use std::fmt;
 use std::io;
 use glob;
-use csv;
 
-use merge_file::MergeFile;
+use merge_file::{escape_key, parse_escaped_key, InputOptions, MergeFile};
 use merge_file::Mergeable;
+use loser_tree::LoserTree;
+use cache_file::{CacheEntry, CacheFile, CacheLock, CacheOptions, StaleCachePolicy};
+use merge_sink::MergeSink;
 use settings::KeyType;
 
 /// A `MergeFile` manager that maintains an internal cache and will perform the merge over all added files.
@@ -19,6 +20,59 @@ use settings::KeyType;
 /// the merge on. Then you either write a new cache file to be used later, or you perform the merge.
 pub struct MergeFileManager;
 
+/// How many cache entries `MergeFileManager::refresh_cache` kept, read again, added and dropped.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CacheRefresh {
+    pub kept: usize,
+    pub rescanned: usize,
+    pub added: usize,
+    pub dropped: usize,
+}
+
+impl fmt::Display for CacheRefresh {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Kept {} cache entries, rescanned {}, added {} and dropped {}", self.kept, self.rescanned, self.added, self.dropped)
+    }
+}
+
+/// What's in a cache, as reported by `file-merger cache stats`. The line count is only known if every file's is.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CacheStats<T> {
+    pub files: usize,
+    pub bytes: u64,
+    pub lines: Option<u64>,
+    /// The lowest beginning and highest ending merge key across all the files
+    pub key_range: Option<(T, T)>,
+}
+
+impl<T: Mergeable> CacheStats<T> {
+    pub fn of(merge_files: &[MergeFile<T>]) -> CacheStats<T> {
+        CacheStats {
+            files: merge_files.len(),
+            bytes: merge_files.iter().map(|merge_file| merge_file.filesize).sum(),
+            lines: merge_files.iter().map(|merge_file| merge_file.line_count).sum(),
+            key_range: match (merge_files.iter().map(|merge_file| &merge_file.beginning_merge_key).min(),
+                              merge_files.iter().map(|merge_file| &merge_file.ending_merge_key).max()) {
+                (Some(lowest), Some(highest)) => Some((lowest.clone(), highest.clone())),
+                _ => None,
+            },
+        }
+    }
+}
+
+impl<T: fmt::Display> fmt::Display for CacheStats<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} files, {} bytes", self.files, self.bytes)?;
+        if let Some(lines) = self.lines {
+            write!(f, ", {} lines", lines)?;
+        }
+        match self.key_range {
+            Some((ref lowest, ref highest)) => write!(f, ", merge keys {} to {}", lowest, highest),
+            None => write!(f, ", no merge keys"),
+        }
+    }
+}
+
 impl MergeFileManager {
     /// For the provided glob, we load all resolved files into an internal cache, returning the cache.
     ///
@@ -28,121 +82,277 @@ impl MergeFileManager {
     /// # Provide a cache specialised for MergeFile<i32>
     /// let cache = MergeFileManager::load_from_glob("/data/files/*.csv", ',', 0, 0i32);
     /// ```
-    pub fn retrieve_from_glob<T>(glob_choice: &str, delimiter: char, index: usize, default_key: T, key_type: KeyType) -> io::Result<HashMap<String, MergeFile<T>>>
+    pub fn retrieve_from_glob<T>(glob_choice: &str, delimiter: char, index: usize, default_key: T, options: InputOptions) -> io::Result<HashMap<String, MergeFile<T>>>
         where T: Mergeable, T::Err: fmt::Debug {
-        let mut cache: HashMap<String, MergeFile<T>> = HashMap::new();
-
-        let glob_result = glob::glob(glob_choice);
+        let filenames = MergeFileManager::glob_filenames(glob_choice)?;
+        MergeFileManager::retrieve_from_filenames(&filenames, delimiter, index, default_key, options)
+    }
 
-        if glob_result.is_err() {
-            return Err(Error::new(ErrorKind::Other, format!("Unable to perform glob over: {}",glob_choice)));
-        }
+    /// Resolves the glob into the filenames it matches, without opening any of them.
+    pub fn glob_filenames(glob_choice: &str) -> io::Result<Vec<String>> {
+        let glob_result = match glob::glob(glob_choice) {
+            Ok(glob_result) => glob_result,
+            Err(_) => return Err(io::Error::other(format!("Unable to perform glob over: {}", glob_choice))),
+        };
 
-        let mut glob_result = glob_result.unwrap();
+        let mut filenames = Vec::new();
 
-        while let Some(Ok(path)) = glob_result.next() {
-            debug!("Attempting to load path: {}", path.display());
+        for path in glob_result {
+            let path = path.map_err(|error| io::Error::other(format!("Unable to perform glob over: {}: {}", glob_choice, error)))?;
 
             if let Some(path) = path.to_str() {
-                if let Ok(merge_file) = MergeFile::new(path, delimiter, index, default_key.clone(), key_type.clone()) {
-                    cache.insert(path.to_string(), merge_file);
-                    debug!("Added {} to the cache successfully!", path);
-                } else {
-                    error!("We failed to load {} into the cache!", path);
-                }
+                filenames.push(path.to_string());
             } else {
                 error!("Unable to convert path into unicode?");
             }
         }
 
+        Ok(filenames)
+    }
+
+    /// Opens each file into an internal cache, returning the cache.
+    /// Empty files are skipped, any other failure to open a file is returned.
+    pub fn retrieve_from_filenames<T>(filenames: &[String], delimiter: char, index: usize, default_key: T, options: InputOptions) -> io::Result<HashMap<String, MergeFile<T>>>
+        where T: Mergeable, T::Err: fmt::Debug {
+        let mut cache: HashMap<String, MergeFile<T>> = HashMap::new();
+
+        for filename in filenames {
+            debug!("Attempting to load path: {}", filename);
+
+            match MergeFile::open(filename, delimiter, index, default_key.clone(), options) {
+                Ok(merge_file) => {
+                    cache.insert(filename.clone(), merge_file);
+                    debug!("Added {} to the cache successfully!", filename);
+                },
+                Err(ref error) if error.kind() == ErrorKind::UnexpectedEof => {
+                    warn!("Skipping {} as it has no lines to merge", filename);
+                },
+                Err(error) => {
+                    return Err(Error::new(error.kind(), format!("We failed to load {} into the cache: {}", filename, error)));
+                },
+            }
+        }
+
         Ok(cache)
     }
 
+    /// Returns the filenames listed in a cache file, without opening any of them.
+    pub fn cache_filenames(filename: &Path, cache_options: &CacheOptions) -> io::Result<Vec<String>> {
+        let _lock = CacheLock::shared(filename, cache_options.lock_policy)?;
+        let cache_file = CacheFile::read(filename, cache_options.base_dir.as_deref())?;
+        Ok(cache_file.entries.into_iter().map(|entry| entry.filename).collect())
+    }
+
     /// Loads a bunch of files into an internal cache that are returned from a pregenerated
     /// cache file from a previous invocation of this program. Returns the number of files
     /// the cache file loaded successfully.
     ///
+    /// Fails if the cache can't be read (see `CacheFile::read`) or was built with a different key type.
+    /// Files that changed since the cache was written are read afresh or fail the load, depending on the `stale_policy`,
+    /// as are files that no longer exist left out of it or fail the load.
+    /// A cache being written by another file-merger is waited for or fails the load, depending on the `lock_policy`.
+    ///
     /// # Examples
     ///
     /// ```
     /// let mut merge_manager = MergeFileManager::new();
     /// merge_manager.load_from_cache("/data/cache/file.cache", ',', 0);
     /// ```
-    pub fn retrieve_from_cache<T>(filename: &PathBuf, default_key: T, key_type: KeyType) -> io::Result<HashMap<String, MergeFile<T>>>
+    pub fn retrieve_from_cache<T>(filename: &Path, default_key: T, key_type: KeyType, options: InputOptions, cache_options: &CacheOptions)
+        -> io::Result<HashMap<String, MergeFile<T>>>
         where T: Mergeable, T::Err: fmt::Debug {
         let mut cache: HashMap<String, MergeFile<T>> = HashMap::new();
 
-        // Attempt to read the cache file
-        let mut cache_reader = csv::Reader::from_file(filename).unwrap().has_headers(false);
-        debug!("Opened cache file: {}", filename.display());
-
-        #[derive(RustcDecodable,Debug)]
-        struct CacheFileLine {
-            filename: String,
-            beginning_merge_key: String,
-            ending_merge_key: String,
-            delimiter: String,
-            key_index: String,
-            filesize: String,
-        }
-
-        // Iterate over cache file reading in and creating new CacheFileEntry instances
-        for record in cache_reader.decode() {
-            let record: CacheFileLine = record.unwrap();
-            debug!("CacheFileLine Record: {:?}", record);
+        // Attempt to read the cache file, the files it lists are opened after letting go of it
+        let cache_file = {
+            let _lock = CacheLock::shared(filename, cache_options.lock_policy)?;
+            CacheFile::read(filename, cache_options.base_dir.as_deref())?
+        };
+        cache_file.check_key_type(&key_type, filename)?;
+        debug!("Opened version {} cache file: {}", cache_file.header.version, filename.display());
 
+        for entry in cache_file.entries {
             // Check if the file is already in the cache
-            if cache.get(&record.filename).is_some() {
-                let metadata = try!(fs::metadata(filename));
-                if metadata.len() == record.filesize.parse::<u64>().unwrap() {
-                    // File is already in cache and filesize is the same, skip it
-                    continue;
-                }
+            if cache.contains_key(&entry.filename) {
+                warn!("{} is listed more than once in {}, using its first entry", entry.filename, filename.display());
+                continue;
             }
 
-            let delimiter = match record.delimiter.as_ref() {
-                "tsv" => '\t',
-                "csv" => ',',
-                "psv" => '|',
-                // Assume it's a single character
-                _ => {
-                    warn!("Assuming delimiter is the first character in the field");
-                    record.delimiter.chars().next().unwrap()
+            // Only trust what the cache says about files that haven't changed since, a file that's gone is as stale as they come
+            let changes = match entry.changes() {
+                Ok(changes) => changes,
+                Err(ref error) if error.kind() == ErrorKind::NotFound => match cache_options.stale_policy {
+                    StaleCachePolicy::Rescan => {
+                        warn!("{} no longer exists, leaving it out of the files {} lists", entry.filename, filename.display());
+                        continue;
+                    },
+                    StaleCachePolicy::Reject => Some("it no longer exists".to_string()),
                 },
+                Err(error) => return Err(Error::new(error.kind(), format!(
+                    "{}: Unable to check {} for changes: {}", filename.display(), entry.filename, error))),
             };
 
-            // Add it into the cache if it isn't
-            if let Ok(mut merge_file) = MergeFile::new(&record.filename,
-                                                   delimiter,
-                                                   record.key_index.parse::<usize>().unwrap(),
-                                                   default_key.clone(),
-                                                   key_type.clone()) {
-
-                // Because the cache knows the ending_merge_key, set it as well
-                // this will help if we're writing a new cache, as we can skip the fastforward
-                merge_file.ending_merge_key = record.ending_merge_key.parse::<T>().unwrap();
-                cache.insert(record.filename.clone(), merge_file);
-                debug!("Added {} to the cache successfully!", record.filename);
-            } else {
-                error!("We failed to load {} into the cache!", record.filename);
+            if let Some(ref change) = changes {
+                match cache_options.stale_policy {
+                    StaleCachePolicy::Rescan => info!("{} changed since {} was written ({}), rescanning it", entry.filename, filename.display(), change),
+                    StaleCachePolicy::Reject => return Err(Error::new(ErrorKind::InvalidData, format!(
+                        "{}: {} changed since the cache was written ({}), rebuild the cache or load it with --stale-cache rescan",
+                        filename.display(), entry.filename, change))),
+                }
             }
+
+            // Add it into the cache, failing the whole load rather than merging only some of the files
+            let mut merge_file = MergeFile::open(&entry.filename,
+                                                 entry.delimiter,
+                                                 entry.key_index,
+                                                 default_key.clone(),
+                                                 options).map_err(|error| {
+                Error::new(error.kind(), format!("{}: Unable to load {}: {}", filename.display(), entry.filename, error))
+            })?;
+
+            // Because the cache knows the ending_merge_key, set it as well
+            // this will help if we're writing a new cache, as we can skip the fastforward
+            if changes.is_none() && !entry.ending_merge_key.is_empty() {
+                merge_file.ending_merge_key = parse_escaped_key::<T>(&entry.ending_merge_key).ok_or_else(|| {
+                    Error::new(ErrorKind::InvalidData, format!("{}: Invalid ending merge key for {}: {}", filename.display(), entry.filename, entry.ending_merge_key))
+                })?;
+                merge_file.line_count = entry.line_count;
+                merge_file.byte_count = entry.byte_count;
+            }
+
+            // The checkpoints let a fast forward skip ahead, but only while the file is as they describe
+            if changes.is_none() {
+                merge_file.checkpoints = entry.checkpoints;
+            }
+            cache.insert(entry.filename.clone(), merge_file);
+            debug!("Added {} to the cache successfully!", entry.filename);
         }
 
         Ok(cache)
     }
 
+    /// Loads a cache file (see `retrieve_from_cache`) to look at rather than merge, sorted by filename.
+    /// Like `write_cache` it reads any file the cache doesn't know the final merge key of to its end to find it.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let merge_files = MergeFileManager::inspect_cache(&PathBuf::from("/data/caches/data.cache"), "0".to_string(), KeyType::String,
+    ///                                                   InputOptions::default(), &CacheOptions::default())?;
+    /// println!("{}", CacheStats::of(&merge_files));
+    /// ```
+    pub fn inspect_cache<T>(filename: &Path, default_key: T, key_type: KeyType, options: InputOptions, cache_options: &CacheOptions)
+        -> io::Result<Vec<MergeFile<T>>>
+        where T: Mergeable, T::Err: fmt::Debug {
+        let cache = MergeFileManager::retrieve_from_cache(filename, default_key.clone(), key_type, options, cache_options)?;
+
+        let mut merge_files = MergeFileManager::cache_to_vec(cache);
+        merge_files.sort_by(|a, b| a.filename.cmp(&b.filename));
+
+        for merge_file in &mut merge_files {
+            if merge_file.ending_merge_key == default_key {
+                merge_file.fast_forward_to_end()?;
+            }
+        }
+
+        Ok(merge_files)
+    }
+
+    /// Keeps the files a merge from `key_start` up to (but not including) `key_end` would read lines from,
+    /// those with a merge key in that range.
+    pub fn query_cache<T>(merge_files: Vec<MergeFile<T>>, key_start: Option<&str>, key_end: Option<&str>) -> io::Result<Vec<MergeFile<T>>>
+        where T: Mergeable, T::Err: fmt::Debug {
+        let parse = |key: Option<&str>| match key {
+            Some(key) => key.parse::<T>().map(Some).map_err(|error| {
+                Error::new(ErrorKind::InvalidInput, format!("Invalid merge key '{}': {:?}", key, error))
+            }),
+            None => Ok(None),
+        };
+        let key_start = parse(key_start)?;
+        let key_end = parse(key_end)?;
+
+        Ok(merge_files.into_iter()
+                      .filter(|merge_file| key_start.as_ref().is_none_or(|key_start| merge_file.ending_merge_key >= *key_start))
+                      .filter(|merge_file| key_end.as_ref().is_none_or(|key_end| merge_file.beginning_merge_key < *key_end))
+                      .collect())
+    }
+
+    /// Lays the files out as a table, one row per file, for `file-merger cache show`.
+    pub fn show_cache<T>(merge_files: &[MergeFile<T>]) -> String
+        where T: Mergeable, T::Err: fmt::Debug {
+        let mut rows = vec![
+            ["filename", "bytes", "lines", "delimiter", "key_index", "checkpoints", "beginning_merge_key", "ending_merge_key"].iter()
+                                                                                                                                 .map(|column| column.to_string())
+                                                                                                                                 .collect::<Vec<String>>(),
+        ];
+
+        for merge_file in merge_files {
+            rows.push(vec![
+                merge_file.filename.clone(),
+                merge_file.filesize.to_string(),
+                merge_file.line_count.map(|lines| lines.to_string()).unwrap_or_else(|| "-".to_string()),
+                MergeFileManager::pretty_delimiter(merge_file.delimiter),
+                merge_file.key_index.to_string(),
+                merge_file.checkpoints.len().to_string(),
+                merge_file.beginning_merge_key.to_string(),
+                merge_file.ending_merge_key.to_string(),
+            ]);
+        }
+
+        let widths = (0..rows[0].len()).map(|column| rows.iter().map(|row| row[column].chars().count()).max().unwrap_or(0))
+                                       .collect::<Vec<usize>>();
+
+        rows.iter().map(|row| {
+            let cells = row.iter().zip(&widths).map(|(cell, width)| format!("{:<width$}", cell, width = width)).collect::<Vec<String>>();
+            format!("{}\n", cells.join("  ").trim_end())
+        }).collect()
+    }
+
+    /// Returns the name we record a delimiter under in cache files, eg. '\t' -> tsv
+    pub fn pretty_delimiter(delimiter: char) -> String {
+        match delimiter {
+            '\t' => "tsv".to_string(),
+            ',' => "csv".to_string(),
+            '|' => "psv".to_string(),
+            _   => delimiter.to_string(),
+        }
+    }
+
+    /// Reads back a delimiter recorded by `pretty_delimiter`, eg. tsv -> '\t'
+    pub fn parse_delimiter(delimiter: &str) -> Option<char> {
+        match delimiter {
+            "tsv" => Some('\t'),
+            "csv" => Some(','),
+            "psv" => Some('|'),
+            // Assume it's a single character
+            _     => delimiter.chars().next(),
+        }
+    }
+
     /// Consumes a HashMap<K,V> turning it into a Vec<V>
     pub fn cache_to_vec<T>(mut hashmap: HashMap<String, MergeFile<T>>) -> Vec<MergeFile<T>> {
         hashmap.drain().map(|(_, v)| v).collect()
     }
 
+    /// Reads up to `max_threads` of the files ahead on their own threads (see `MergeFile::read_ahead`),
+    /// the largest first as they have the most to decompress. The rest are read on the merge thread.
+    pub fn read_ahead<T>(cache: &mut HashMap<String, MergeFile<T>>, max_threads: usize)
+        where T: Mergeable, T::Err: fmt::Debug {
+        let mut merge_files = cache.values_mut().collect::<Vec<&mut MergeFile<T>>>();
+        merge_files.sort_by(|a, b| b.filesize.cmp(&a.filesize).then_with(|| a.filename.cmp(&b.filename)));
+
+        for merge_file in merge_files.into_iter().take(max_threads) {
+            merge_file.read_ahead();
+        }
+    }
+
     /// Consumes a HashMap<K, MergeFile> and returns one with only existing MergeFile(s)
-    pub fn fast_forward_cache<T>(mut cache: HashMap<String, MergeFile<T>>, merge_start: String) -> HashMap<String, MergeFile<T>>
+    pub fn fast_forward_cache<T>(mut cache: HashMap<String, MergeFile<T>>, merge_start: String) -> io::Result<HashMap<String, MergeFile<T>>>
         where T: Mergeable, T::Err: fmt::Debug {
         let mut files_to_delete: Vec<String> = vec!();
 
         for merge_file in cache.values_mut() {
-            if merge_file.fast_forward(&merge_start).is_err() {
+            if !merge_file.fast_forward(&merge_start)? {
                 files_to_delete.push(merge_file.filename.clone());
             }
         }
@@ -152,80 +362,93 @@ impl MergeFileManager {
             cache.remove(&filename);
         }
 
-        cache
+        Ok(cache)
     }
 
     /// Starts the k-way merge on the cache in its current state.
-    /// It will forward all files to the start of the merge,
+    /// Each file is expected to already be positioned on its first line to merge (see `fast_forward_cache`),
+    /// every merged line is handed to the sink in merge key order until all files hit EOF or `merge_end`.
+    /// A failed read stops the merge with that error (see `InputOptions::tolerate_read_errors`).
     ///
     /// # Examples
     ///
     /// ```
-    /// let mut merge_manager = MergeFileManager::new();
-    /// merge_manager.load_from_glob("/data/*.tsv", '\t', 0);
-    /// merge_manager.begin_merge("zzz");
+    /// let cache = MergeFileManager::retrieve_from_glob("/data/*.tsv", '\t', 0, "0".to_string(), InputOptions::default())?;
+    /// let mut sink = MergeWriter::new(io::stdout());
+    /// MergeFileManager::begin_merge(cache, Some("zzz".to_string()), &mut sink);
     /// ```
-    pub fn begin_merge<T>(cache: HashMap<String, MergeFile<T>>, merge_end: Option<String>, print_merge_output: bool) -> Vec<MergeFile<T>>
+    pub fn begin_merge<T>(cache: HashMap<String, MergeFile<T>>, merge_end: Option<String>, sink: &mut dyn MergeSink<T>) -> io::Result<Vec<MergeFile<T>>>
         where T: Mergeable, T::Err: fmt::Debug {
-        let mut heap = BinaryHeap::from(MergeFileManager::cache_to_vec(cache));
+        // The tournament always has the file with the lowest merge key as its winner
+        let mut tree = LoserTree::new(MergeFileManager::cache_to_vec(cache));
         let mut discarded = Vec::new();
         let mut lines_emitted = 0;
         let mut lines_emitted_since_last_checkpoint;
         let mut checkpoint;
 
-        let merge_end_set = merge_end.is_some();
-        let merge_end_key;
-
-        if merge_end_set {
-            merge_end_key = merge_end.unwrap().parse::<T>().unwrap();
-            info!("Beginning merge -> {}", merge_end_key);
-        } else {
-            merge_end_key = "".parse::<T>().unwrap();
-            info!("Beginning merge -> EOF");
-        }
-
-        while let Some(mut next_file) = heap.pop() {
-            // Report on the line or EOF the file and add it to the discarded pile
-            if let Some(result) = next_file.next() {
-                // Check if the line has exceeded the merge_end key
-                if merge_end_set && result > merge_end_key {
-                    info!("MergeFile<{}> has hit end bound ({}>{}), discarding from cache", next_file.filename, result, merge_end_key);
-                    discarded.push(next_file);
+        let merge_end_key = match merge_end {
+            Some(merge_end) => {
+                let merge_end_key = merge_end.parse::<T>().map_err(|error| {
+                    Error::new(ErrorKind::InvalidData, format!("Invalid key to merge up to {:?}: {:?}", merge_end, error))
+                })?;
+                info!("Beginning merge -> {}", merge_end_key);
+                Some(merge_end_key)
+            },
+            None => {
+                info!("Beginning merge -> EOF");
+                None
+            },
+        };
+
+        while let Some(next_file) = tree.winner() {
+            // Check if the line has reached the merge_end key
+            if let Some(ref merge_end_key) = merge_end_key {
+                if next_file.current_merge_key >= *merge_end_key {
+                    info!("MergeFile<{}> has hit end bound ({}>={}), discarding from cache", next_file.filename, next_file.current_merge_key, merge_end_key);
+                    discarded.extend(tree.remove_winner());
                     continue
                 }
+            }
 
-                // Print the line (if required) then push the MergeFile back into the heap
-                if print_merge_output {
-                    println!("{}", next_file.line);
-                }
+            sink.write_line(next_file)?;
+
+            lines_emitted += 1;
+            if lines_emitted % 10000 == 0 {
+                let now = time::Instant::now();
+                checkpoint = now;
+                lines_emitted_since_last_checkpoint = lines_emitted;
 
-                heap.push(next_file);
+                let mut duration = now.duration_since(checkpoint).as_secs();
+                if duration < 1 {
+                    duration = 1
+                }
 
-                lines_emitted += 1;
-                if lines_emitted % 10000 == 0 {
-                    let now = time::Instant::now();
-                    checkpoint = now;
-                    lines_emitted_since_last_checkpoint = lines_emitted;
+                info!("Processed {} lines @ {}/s", lines_emitted, lines_emitted_since_last_checkpoint / duration);
+            }
 
-                    let mut duration = now.duration_since(checkpoint).as_secs();
-                    if duration < 1 {
-                        duration = 1
-                    }
+            // Move onto the next line or EOF the file and add it to the discarded pile, replaying the tree once either way
+            let mut advanced = Ok(true);
+            let finished_file = tree.update_winner(|next_file| {
+                advanced = next_file.advance();
+                matches!(advanced, Ok(true))
+            });
+            advanced?;
 
-                    info!("Processed {} lines @ {}/s", lines_emitted, lines_emitted_since_last_checkpoint / duration);
-                }
-            } else {
+            if let Some(next_file) = finished_file {
                 info!("We hit EOF for {} with a final merge key of {}", next_file.filename, next_file.ending_merge_key);
                 discarded.push(next_file);
             }
         }
 
-        discarded
+        sink.finish()?;
+
+        Ok(discarded)
     }
 
     /// Consumes the cache, turning it into a sorted vector.
-    /// It then fast forwards each file and writes it out into the cache file.
-    /// The cache file layout is: file_name, mergekey_start, mergekey_end, file_size
+    /// It then fast forwards each file and writes it out into the cache file (see `CacheFile` for the layout),
+    /// recording a checkpoint every `checkpoint_lines` lines of the files it reads.
+    /// The cache is only locked (see `CacheLock`) while it's replaced, as it's written from scratch.
     ///
     /// # Examples
     ///
@@ -234,48 +457,164 @@ impl MergeFileManager {
     /// let cache = merge_manager.load_from_glob("/data/*.tsv", '\t', 0);
     /// merge_manager.write_cache("/data/caches/data.cache".to_string(), cache);
     /// ```
-    pub fn write_cache<T>(filename: &PathBuf, cache: HashMap<String, MergeFile<T>>, default_key: T) -> Result<String, String>
+    pub fn write_cache<T>(filename: &Path, cache: HashMap<String, MergeFile<T>>, default_key: T, key_type: KeyType, checkpoint_lines: Option<u64>,
+                          cache_options: &CacheOptions) -> Result<String, String>
         where T: Mergeable, T::Err: fmt::Debug {
         info!("Writing out cache to disk => {}!", filename.display());
 
-        // Open the file
-        let mut cache_writer = csv::Writer::from_file(filename)
-                                            .unwrap()
-                                            .delimiter(b',');
-
         // Drain the cache into a vec, sort it, then write its contents out to disk
         let mut merge_files = MergeFileManager::cache_to_vec(cache);
         merge_files.sort();
 
-        for mut merge_file in merge_files {
-            if merge_file.ending_merge_key == default_key {
+        let mut entries = Vec::new();
+        for merge_file in merge_files {
+            let fast_forward = merge_file.ending_merge_key == default_key;
+            if fast_forward {
                 info!("MergeFile {} was loaded from glob, fastwarding to EOF", &merge_file);
-                merge_file.fast_forward_to_end();
             } else {
                 info!("MergeFile {} was loaded from cache, skipping fastforward", &merge_file);
             }
 
-            let pretty_delimiter = match merge_file.delimiter {
-                '\t' => "tsv".to_string(),
-                ',' => "csv".to_string(),
-                '|' => "psv".to_string(),
-                _   => merge_file.delimiter.to_string(),
+            let filename = merge_file.filename.clone();
+            match MergeFileManager::cache_entry(merge_file, fast_forward, checkpoint_lines) {
+                Ok(entry) => entries.push(entry),
+                Err(error) => return Err(format!("Unable to find the final merge key of {}: {}", filename, error)),
+            }
+        }
+
+        let written = CacheLock::exclusive(filename, cache_options.lock_policy).and_then(|_lock| CacheFile::new(key_type, entries).write(filename));
+        match written {
+            Ok(()) => Ok("Written cache out to disk.".to_string()),
+            Err(error) => Err(format!("Unable to write the cache out to disk: {}", error)),
+        }
+    }
+
+    /// Describes the merge file as a cache entry, reading it to its end first to find its final merge key if asked to
+    /// (recording a checkpoint every `checkpoint_lines` lines as it goes).
+    fn cache_entry<T>(mut merge_file: MergeFile<T>, fast_forward: bool, checkpoint_lines: Option<u64>) -> io::Result<CacheEntry>
+        where T: Mergeable, T::Err: fmt::Debug {
+        // Taken before reading the file, so anything appended while we read it shows up as a change
+        let mut entry = CacheEntry {
+            filename: merge_file.filename.clone(),
+            delimiter: merge_file.delimiter,
+            key_index: merge_file.key_index,
+            ..CacheEntry::default()
+        };
+        entry.record_file_state()?;
+
+        if fast_forward {
+            if let Some(checkpoint_lines) = checkpoint_lines {
+                merge_file.record_checkpoints(checkpoint_lines);
+            }
+            merge_file.fast_forward_to_end()?;
+        }
+
+        entry.beginning_merge_key = escape_key(&merge_file.beginning_merge_key.to_bytes());
+        entry.ending_merge_key = escape_key(&merge_file.ending_merge_key.to_bytes());
+        entry.line_count = merge_file.line_count;
+        entry.byte_count = merge_file.byte_count;
+        entry.checkpoints = merge_file.checkpoints;
+        Ok(entry)
+    }
+
+    /// Brings the cache file up to date without reading every file again.
+    /// Entries for unchanged files are kept as they are, files that changed (or an older cache has no state recorded
+    /// for) are read again, entries for files that no longer exist are dropped and any file the globs match that isn't
+    /// in the cache yet is added. The cache is created if it doesn't exist, and replaced atomically if it does.
+    /// With `checkpoint_lines`, entries without checkpoints are read again to record them.
+    ///
+    /// The cache stays locked (see `CacheLock`) from reading it to replacing it, so concurrent refreshes don't lose each other's changes.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let refresh = MergeFileManager::refresh_cache(&PathBuf::from("/data/caches/data.cache"), &["/data/*.tsv".to_string()],
+    ///                                               '\t', 0, "0".to_string(), KeyType::String, InputOptions::default(),
+    ///                                               &CacheOptions::default(), None)?;
+    /// ```
+    #[allow(clippy::too_many_arguments)]
+    pub fn refresh_cache<T>(filename: &Path, glob_choices: &[String], delimiter: char, index: usize, default_key: T, key_type: KeyType,
+                            options: InputOptions, cache_options: &CacheOptions, checkpoint_lines: Option<u64>) -> io::Result<CacheRefresh>
+        where T: Mergeable, T::Err: fmt::Debug {
+        let _lock = CacheLock::exclusive(filename, cache_options.lock_policy)?;
+        let cache_file = if filename.exists() {
+            CacheFile::read(filename, cache_options.base_dir.as_deref())?
+        } else {
+            CacheFile::new(key_type.clone(), Vec::new())
+        };
+        cache_file.check_key_type(&key_type, filename)?;
+
+        let mut refresh = CacheRefresh::default();
+        let mut entries = Vec::new();
+        // Cached filenames are resolved against the cache while globs are as given, so compare where they lead
+        let mut seen = HashSet::new();
+        let canonical = |data_filename: &str| fs::canonicalize(data_filename).unwrap_or_else(|_| PathBuf::from(data_filename));
+
+        let open = |data_filename: &str, delimiter: char, index: usize| {
+            match MergeFile::open(data_filename, delimiter, index, default_key.clone(), options) {
+                Ok(merge_file) => Ok(Some(merge_file)),
+                Err(ref error) if error.kind() == ErrorKind::UnexpectedEof => {
+                    warn!("Skipping {} as it has no lines to merge", data_filename);
+                    Ok(None)
+                },
+                Err(error) => Err(Error::new(error.kind(), format!("We failed to load {} into the cache: {}", data_filename, error))),
+            }
+        };
+
+        for entry in cache_file.entries {
+            if !seen.insert(canonical(&entry.filename)) {
+                warn!("{} is listed more than once in {}, keeping its first entry", entry.filename, filename.display());
+                continue;
+            }
+
+            let changes = match entry.changes() {
+                Ok(changes) => changes,
+                Err(ref error) if error.kind() == ErrorKind::NotFound => {
+                    info!("{} no longer exists, dropping it from the cache", entry.filename);
+                    refresh.dropped += 1;
+                    continue;
+                },
+                Err(error) => return Err(Error::new(error.kind(), format!("Unable to check {} for changes: {}", entry.filename, error))),
             };
 
-            let cache_line = [
-                merge_file.filename,
-                merge_file.beginning_merge_key.to_string(),
-                merge_file.ending_merge_key.to_string(),
-                pretty_delimiter,
-                merge_file.key_index.to_string(),
-                merge_file.filesize.to_string()
-            ];
+            let missing_checkpoints = match (checkpoint_lines, entry.line_count) {
+                (_, None) => true,
+                (Some(checkpoint_lines), Some(line_count)) => entry.checkpoints.is_empty() && line_count >= checkpoint_lines,
+                (None, Some(_)) => false,
+            };
+
+            if changes.is_none() && entry.mtime_ns.is_some() && !entry.ending_merge_key.is_empty() && !missing_checkpoints {
+                refresh.kept += 1;
+                entries.push(entry);
+                continue;
+            }
+
+            info!("Rescanning {} ({})", entry.filename, changes.unwrap_or_else(|| "nothing recorded to compare against".to_string()));
+            match open(&entry.filename, entry.delimiter, entry.key_index)? {
+                Some(merge_file) => {
+                    entries.push(MergeFileManager::cache_entry(merge_file, true, checkpoint_lines)?);
+                    refresh.rescanned += 1;
+                },
+                None => refresh.dropped += 1,
+            }
+        }
+
+        for glob_choice in glob_choices {
+            for data_filename in MergeFileManager::glob_filenames(glob_choice)? {
+                if !seen.insert(canonical(&data_filename)) {
+                    continue;
+                }
 
-            cache_writer.write(cache_line.iter()).unwrap();
+                info!("Adding {} to the cache", data_filename);
+                if let Some(merge_file) = open(&data_filename, delimiter, index)? {
+                    entries.push(MergeFileManager::cache_entry(merge_file, true, checkpoint_lines)?);
+                    refresh.added += 1;
+                }
+            }
         }
 
-        cache_writer.flush().unwrap();
-        Ok("Written cache out to disk.".to_string())
+        CacheFile::new(key_type, entries).write(filename)?;
+        Ok(refresh)
     }
 }
 
@@ -283,26 +622,27 @@ impl MergeFileManager {
 #[cfg(test)]
 mod tests {
     use std::io::prelude::*;
-    use std::io::BufWriter;
+    use std::os::unix::fs::symlink;
     use std::path::PathBuf;
-    use std::fs::File;
     use std::fs;
 
-    use super::MergeFileManager;
-    use merge_file::MergeFile;
-    use settings::KeyType;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
 
-    fn create_file(filename: &str, contents: String) {
-        let mut temp_file = BufWriter::new(File::create(PathBuf::from(filename)).unwrap());
-        temp_file.write(contents.as_ref()).unwrap();
-        let _ = temp_file.flush();
-    }
+    use super::{CacheRefresh, CacheStats, MergeFileManager};
+    use cache_file::CacheFile;
+    use merge_file::{ByteString, MergeFile};
+    use settings::KeyType;
+    use merge_file::InputOptions;
+    use cache_file::{CacheOptions, StaleCachePolicy};
+    use test_helpers::{create_file, TempDir};
 
     #[test]
     fn new_merge_file() {
+        let dir = TempDir::new("new_merge_file");
+
         // Set up the test data
-        // TODO: Add the PID of the process into the filename
-        let test_filename_1 = "/tmp/test_add_file.file1.tsv";
+        let test_filename_1: &str = &dir.join("file1.tsv");
         let test_contents_1 = format!("{}\t{}\t{}\n\
                                        {}\t{}\t{}\n\
                                        {}\t{}\t{}\n",
@@ -312,7 +652,7 @@ mod tests {
 
         create_file(test_filename_1, test_contents_1);
 
-        let test_filename_2 = "/tmp/test_add_file.file2.csv";
+        let test_filename_2: &str = &dir.join("file2.csv");
         let test_contents_2 = format!("{},{},{}\n\
                                        {},{},{}\n\
                                        {},{},{}\n",
@@ -323,7 +663,7 @@ mod tests {
         create_file(test_filename_2, test_contents_2);
 
         // Add the first file and sanity check
-        let result = MergeFile::new(&test_filename_1, '\t', 0, "0".to_string(), KeyType::String);
+        let result = MergeFile::new(test_filename_1, '\t', 0, "0".to_string());
         assert!(result.is_ok());
 
         let mergefile = result.unwrap();
@@ -331,21 +671,19 @@ mod tests {
         assert_eq!(mergefile.current_merge_key, "123");
 
         // Add the second file and sanity check
-        let result = MergeFile::new(&test_filename_2, ',', 0, "0".to_string(), KeyType::String);
+        let result = MergeFile::new(test_filename_2, ',', 0, "0".to_string());
         assert!(result.is_ok());
 
         let mergefile = result.unwrap();
         assert_eq!(mergefile.filename, test_filename_2);
         assert_eq!(mergefile.current_merge_key, "123");
-
-        let _ = fs::remove_file(test_filename_1);
-        let _ = fs::remove_file(test_filename_2);
-
     }
 
     #[test]
     fn retrieve_from_glob() {
-        let test_filename_1 = "/tmp/test_retrieve_from_glob.file1.tsv";
+        let dir = TempDir::new("retrieve_from_glob");
+
+        let test_filename_1: &str = &dir.join("file1.tsv");
         let test_contents_1 = format!("{}\t{}\t{}\n
                                        {}\t{}\t{}\n
                                        {}\t{}\t{}\n",
@@ -355,7 +693,7 @@ mod tests {
 
         create_file(test_filename_1, test_contents_1);
 
-        let test_filename_2 = "/tmp/test_retrieve_from_glob.file2.tsv";
+        let test_filename_2: &str = &dir.join("file2.tsv");
         let test_contents_2 = format!("{}\t{}\t{}\n\
                                        {}\t{}\t{}\n\
                                        {}\t{}\t{}\n",
@@ -366,7 +704,7 @@ mod tests {
         create_file(test_filename_2, test_contents_2);
 
         // Load a glob with a single file into the cache
-        let result = MergeFileManager::retrieve_from_glob("/tmp/test_retrieve_from_glob.file1.tsv", '\t', 0, "0".to_string(), KeyType::String);
+        let result = MergeFileManager::retrieve_from_glob(&dir.join("file1.tsv"), '\t', 0, "0".to_string(), InputOptions::default());
         assert!(result.is_ok());
 
         let merge_files = result.unwrap();
@@ -374,7 +712,7 @@ mod tests {
         assert!(merge_files.values().any(|x|x.filename == test_filename_1));
 
         // Load a glob with a single file into the cache
-        let result = MergeFileManager::retrieve_from_glob("/tmp/test_retrieve_from_glob.file?.tsv", '\t', 0, "0".to_string(), KeyType::String);
+        let result = MergeFileManager::retrieve_from_glob(&dir.join("file?.tsv"), '\t', 0, "0".to_string(), InputOptions::default());
         assert!(result.is_ok());
 
         let merge_files = result.unwrap();
@@ -382,13 +720,27 @@ mod tests {
         assert!(merge_files.values().any(|x|x.filename == test_filename_1));
         assert!(merge_files.values().any(|x|x.filename == test_filename_2));
 
-        let _ = fs::remove_file(test_filename_1);
-        let _ = fs::remove_file(test_filename_2);
+        // Empty files are skipped
+        let test_filename_3: &str = &dir.join("file3.tsv");
+        create_file(test_filename_3, String::new());
+
+        let result = MergeFileManager::retrieve_from_glob(&dir.join("file?.tsv"), '\t', 0, "0".to_string(), InputOptions::default());
+        assert_eq!(result.unwrap().len(), 2);
+
+        // But a file we can't open fails the whole glob rather than silently dropping it
+        let test_filename_4: &str = &dir.join("file4.tsv");
+        let _ = fs::remove_file(test_filename_4);
+        symlink(dir.join("missing.tsv"), test_filename_4).unwrap();
+
+        let result = MergeFileManager::retrieve_from_glob(&dir.join("file?.tsv"), '\t', 0, "0".to_string(), InputOptions::default());
+        assert!(result.unwrap_err().to_string().contains(test_filename_4));
     }
 
     #[test]
     fn retrieve_from_cache() {
-        let test_filename_1 = "/tmp/test_retrieve_from_cache.file1.tsv";
+        let dir = TempDir::new("retrieve_from_cache");
+
+        let test_filename_1: &str = &dir.join("file1.tsv");
         let test_contents_1 = format!("{key_1}\t{foo}\t{bar}\n\
                                        {key_2}\t{foo}\t{bar}\n\
                                        {key_3}\t{foo}\t{bar}\n",
@@ -396,7 +748,7 @@ mod tests {
 
         create_file(test_filename_1, test_contents_1);
 
-        let test_filename_2 = "/tmp/test_retrieve_from_cache.file2.tsv";
+        let test_filename_2: &str = &dir.join("file2.tsv");
         let test_contents_2 = format!("{key_1}\t{foo}\t{bar}\n\
                                        {key_2}\t{foo}\t{bar}\n\
                                        {key_3}\t{foo}\t{bar}\n",
@@ -404,7 +756,7 @@ mod tests {
 
         create_file(test_filename_2, test_contents_2);
 
-        let cache_filename = "/tmp/test_retrieve_from_cache.cache";
+        let cache_filename = &dir.join("cache");
         let cache_contents = format!(
             "{},{},{},{},{},{}\n\
              {},{},{},{},{},{}\n",
@@ -412,26 +764,24 @@ mod tests {
             test_filename_2, "", "", '\t', 0, ""
         );
 
-        create_file(&cache_filename, cache_contents);
+        create_file(cache_filename, cache_contents);
 
         let cache_path = PathBuf::from(&cache_filename);
-        let result = MergeFileManager::retrieve_from_cache(&cache_path, "0".to_string(), KeyType::String);
+        let result = MergeFileManager::retrieve_from_cache(&cache_path, "0".to_string(), KeyType::String, InputOptions::default(), &CacheOptions::default());
         assert!(result.is_ok());
 
         let merge_files = result.unwrap();
         assert_eq!(merge_files.len(), 2);
         assert!(merge_files.values().any(|x|x.filename == test_filename_1));
         assert!(merge_files.values().any(|x|x.filename == test_filename_2));
-
-        let _ = fs::remove_file(test_filename_1);
-        let _ = fs::remove_file(test_filename_2);
-        let _ = fs::remove_file(cache_filename);
     }
 
     #[test]
     fn cache_to_vec() {
+        let dir = TempDir::new("cache_to_vec");
+
         // Build up a cache
-        let test_filename_1 = "/tmp/test_cache_to_vec.file1.tsv";
+        let test_filename_1: &str = &dir.join("file1.tsv");
         let test_contents_1 = format!("{}\t{}\t{}\n\
                                        {}\t{}\t{}\n\
                                        {}\t{}\t{}\n",
@@ -441,7 +791,7 @@ mod tests {
 
         create_file(test_filename_1, test_contents_1);
 
-        let test_filename_2 = "/tmp/test_cache_to_vec.file2.tsv";
+        let test_filename_2: &str = &dir.join("file2.tsv");
         let test_contents_2 = format!("{}\t{}\t{}\n\
                                        {}\t{}\t{}\n\
                                        {}\t{}\t{}\n",
@@ -451,7 +801,7 @@ mod tests {
 
         create_file(test_filename_2, test_contents_2);
 
-        let result = MergeFileManager::retrieve_from_glob("/tmp/test_cache_to_vec.file?.tsv", '\t', 0, "0".to_string(), KeyType::String);
+        let result = MergeFileManager::retrieve_from_glob(&dir.join("file?.tsv"), '\t', 0, "0".to_string(), InputOptions::default());
         assert!(result.is_ok());
         let cache = result.unwrap();
 
@@ -461,15 +811,14 @@ mod tests {
         assert_eq!(test_vec.len(), 2);
         assert!(test_vec.iter().any(|x|x.filename == test_filename_1));
         assert!(test_vec.iter().any(|x|x.filename == test_filename_2));
-
-        let _ = fs::remove_file(test_filename_1);
-        let _ = fs::remove_file(test_filename_2);
     }
 
     #[test]
     fn begin_merge() {
+        let dir = TempDir::new("begin_merge");
+
         //pub fn begin_merge(mut cache: HashMap<String, MergeFile>, merge_start: &String, merge_end: &String, print_merge_output: bool) {
-        let test_filename_1 = "/tmp/test_begin_merge.file1.tsv";
+        let test_filename_1: &str = &dir.join("file1.tsv");
         let test_contents_1 = format!("{}\t{}\t{}\n\
                                        {}\t{}\t{}\n\
                                        {}\t{}\t{}\n",
@@ -479,7 +828,7 @@ mod tests {
 
         create_file(test_filename_1, test_contents_1);
 
-        let test_filename_2 = "/tmp/test_begin_merge.file2.tsv";
+        let test_filename_2: &str = &dir.join("file2.tsv");
         let test_contents_2 = format!("{}\t{}\t{}\n\
                                        {}\t{}\t{}\n\
                                        {}\t{}\t{}\n",
@@ -490,7 +839,7 @@ mod tests {
         create_file(test_filename_2, test_contents_2);
 
         // Load a glob with a single file into the cache
-        let result = MergeFileManager::retrieve_from_glob("/tmp/test_begin_merge.file?.tsv", '\t', 0, "0".to_string(), KeyType::String);
+        let result = MergeFileManager::retrieve_from_glob(&dir.join("file?.tsv"), '\t', 0, "0".to_string(), InputOptions::default());
         assert!(result.is_ok());
         let cache = result.unwrap();
 
@@ -500,21 +849,114 @@ mod tests {
         let merge_start = "124".to_string();
         let merge_end = "126".to_string();
 
-        let cache = MergeFileManager::fast_forward_cache(cache, merge_start);
-        let discarded = MergeFileManager::begin_merge(cache, Some(merge_end.clone()), false);
+        let cache = MergeFileManager::fast_forward_cache(cache, merge_start).unwrap();
+        let mut merged_lines: Vec<String> = Vec::new();
+        let discarded = MergeFileManager::begin_merge(cache, Some(merge_end.clone()), &mut merged_lines).unwrap();
+
+        // Only lines within [merge_start, merge_end) should be emitted, in merge key order
+        assert_eq!(merged_lines.len(), 3);
+        assert!(merged_lines[0].starts_with("124\t"));
+        assert!(merged_lines[1].starts_with("124\t"));
+        assert_eq!(merged_lines[2], "125\tbbb\t999");
 
         // Both original files should exist and have correct final merge keys
         assert_eq!(initial_cache_len, discarded.len());
         assert!(discarded.iter().any(|x|x.filename == test_filename_1 && x.ending_merge_key <= merge_end));
         assert!(discarded.iter().any(|x|x.filename == test_filename_2 && x.ending_merge_key <= merge_end));
+    }
+
+    #[test]
+    fn read_ahead() {
+        let dir = TempDir::new("read_ahead");
+
+        // Enough lines for the read ahead threads to recycle a few batches of them
+        for file in 0..3 {
+            create_file(dir.join(&format!("file{}.tsv", file)),
+                        (0..5000).map(|line| format!("{:06}\tfile{}\n", line * 3 + file, file)).collect::<String>());
+        }
+
+        let merge = |reader_threads: usize| {
+            let mut cache = MergeFileManager::retrieve_from_glob(&dir.join("file?.tsv"), '\t', 0, 0u32, InputOptions::default()).unwrap();
+            MergeFileManager::read_ahead(&mut cache, reader_threads);
+            let mut merged_lines: Vec<String> = Vec::new();
+            MergeFileManager::begin_merge(cache, None, &mut merged_lines).unwrap();
+            merged_lines
+        };
+
+        // Whichever files are read ahead, the merge is the same
+        let merged_lines = merge(0);
+        assert_eq!(merged_lines.len(), 15000);
+        assert_eq!(merged_lines[14999], "014999\tfile2");
+        assert_eq!(merge(1), merged_lines);
+        assert_eq!(merge(3), merged_lines);
+    }
+
+    #[test]
+    fn read_ahead_after_fast_forward() {
+        let dir = TempDir::new("read_ahead_after_fast_forward");
+
+        // Big enough for the fast forward to binary search
+        for file in 0..2 {
+            create_file(dir.join(&format!("file{}.tsv", file)),
+                        (0..20000).map(|line| format!("{:06}	file{}
+", line * 2 + file, file)).collect::<String>());
+        }
+
+        // Fast forwarding first, as begin_merge does, the files still seek before being handed to the read ahead threads
+        let cache = MergeFileManager::retrieve_from_glob(&dir.join("file?.tsv"), '\t', 0, 0u32, InputOptions::default()).unwrap();
+        let mut cache = MergeFileManager::fast_forward_cache(cache, "030000".to_string()).unwrap();
+        MergeFileManager::read_ahead(&mut cache, 2);
+
+        // A binary search loses count of the lines it skipped, reading through them all would count up to line 15001
+        for merge_file in cache.values() {
+            assert!(merge_file.line_number < 15001);
+        }
 
-        let _ = fs::remove_file(test_filename_1);
-        let _ = fs::remove_file(test_filename_2);
+        let mut merged_lines: Vec<String> = Vec::new();
+        MergeFileManager::begin_merge(cache, None, &mut merged_lines).unwrap();
+        assert_eq!(merged_lines.len(), 10000);
+        assert_eq!(merged_lines[0], "030000\tfile0");
+        assert_eq!(merged_lines[9999], "039999\tfile1");
+    }
+
+    #[test]
+    fn begin_merge_read_error() {
+        let dir = TempDir::new("begin_merge_read_error");
+
+        // A gzip cut off part way through, after a few thousand lines
+        let test_filename_1: &str = &dir.join("file1.tsv.gz");
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::Default);
+        for line in 0..20000 {
+            writeln!(encoder, "{:06}\t{}", line * 2, line).unwrap();
+        }
+        let compressed = encoder.finish().unwrap();
+        fs::write(test_filename_1, &compressed[..compressed.len() / 2]).unwrap();
+
+        let test_filename_2: &str = &dir.join("file2.tsv");
+        create_file(test_filename_2, (0..100).map(|line| format!("{:06}\tintact\n", line * 2 + 1)).collect::<String>());
+
+        let merge = |options: InputOptions| {
+            let cache = MergeFileManager::retrieve_from_glob(&dir.join("file?.tsv*"), '\t', 0, 0u32, options).unwrap();
+            let mut merged_lines: Vec<String> = Vec::new();
+            MergeFileManager::begin_merge(cache, None, &mut merged_lines).map(|_| merged_lines)
+        };
+
+        // The truncated file fails the merge, naming the file and where it failed
+        let error = merge(InputOptions::default()).unwrap_err();
+        assert!(error.to_string().starts_with(&format!("{}: Unable to read past line ", test_filename_1)));
+        assert!(error.to_string().contains(" (byte "));
+
+        // Tolerated, it's merged up to where it failed
+        let merged_lines = merge(InputOptions { tolerate_read_errors: true, ..InputOptions::default() }).unwrap();
+        assert!(merged_lines.len() > 100 && merged_lines.len() < 20100);
+        assert_eq!(merged_lines.iter().filter(|line| line.ends_with("\tintact")).count(), 100);
     }
 
     #[test]
     fn write_cache() {
-        let test_filename_1 = "/tmp/test_write_cache.file1.tsv";
+        let dir = TempDir::new("write_cache");
+
+        let test_filename_1: &str = &dir.join("file1.tsv");
         let test_contents_1 = format!("{}\t{}\t{}\n\
                                        {}\t{}\t{}\n\
                                        {}\t{}\t{}\n",
@@ -524,7 +966,7 @@ mod tests {
 
         create_file(test_filename_1, test_contents_1);
 
-        let test_filename_2 = "/tmp/test_write_cache.file2.tsv";
+        let test_filename_2: &str = &dir.join("file2.tsv");
         let test_contents_2 = format!("{}\t{}\t{}\n\
                                        {}\t{}\t{}\n\
                                        {}\t{}\t{}\n",
@@ -535,25 +977,162 @@ mod tests {
         create_file(test_filename_2, test_contents_2);
 
         // Load a glob with a single file into the cache
-        let result = MergeFileManager::retrieve_from_glob("/tmp/test_write_cache.file?.tsv", '\t', 0, "0".to_string(), KeyType::String);
+        let result = MergeFileManager::retrieve_from_glob(&dir.join("file?.tsv"), '\t', 0, "0".to_string(), InputOptions::default());
         assert!(result.is_ok());
         let cache = result.unwrap();
 
         assert_eq!(cache.len(), 2);
 
-        let test_cache_filename = "/tmp/test_cache.cache";
+        let test_cache_filename = &dir.join("cache");
         let test_cache_path = PathBuf::from(&test_cache_filename);
-        let result = MergeFileManager::write_cache(&test_cache_path, cache, "0".to_string());
+        let result = MergeFileManager::write_cache(&test_cache_path, cache, "0".to_string(), KeyType::String, Some(2), &CacheOptions::default());
         assert!(result.is_ok());
 
-        let result = MergeFileManager::retrieve_from_cache(&test_cache_path, "0".to_string(), KeyType::String);
+        let reject = CacheOptions { stale_policy: StaleCachePolicy::Reject, ..CacheOptions::default() };
+
+        let result = MergeFileManager::retrieve_from_cache(&test_cache_path, "0".to_string(), KeyType::String, InputOptions::default(), &reject);
         assert!(result.is_ok());
 
         let merge_files = result.unwrap();
         assert_eq!(merge_files.len(), 2);
+        assert_eq!(merge_files[test_filename_2].ending_merge_key, "127");
+        assert_eq!(merge_files[test_filename_2].line_count, Some(3));
+        assert_eq!(merge_files[test_filename_2].checkpoints.iter().map(|checkpoint| checkpoint.key.as_str()).collect::<Vec<&str>>(), vec!["124"]);
+
+        // A file rewritten since is either read afresh or fails the load
+        create_file(test_filename_2, "123\taaa\t888\n128\taaa\t888\n");
+
+        let result = MergeFileManager::retrieve_from_cache(&test_cache_path, "0".to_string(), KeyType::String, InputOptions::default(), &CacheOptions::default());
+        let merge_files = result.unwrap();
+        assert_eq!(merge_files[test_filename_1].ending_merge_key, "125");
+        assert_eq!(merge_files[test_filename_2].ending_merge_key, "0");
+
+        let result = MergeFileManager::retrieve_from_cache(&test_cache_path, "0".to_string(), KeyType::String, InputOptions::default(), &reject);
+        let error = result.unwrap_err().to_string();
+        assert!(error.contains(&format!("{} changed since the cache was written (its size went from 36 to 24 bytes)", test_filename_2)));
+
+        // A file that's gone is left out when rescanning, or fails the load
+        fs::remove_file(test_filename_2).unwrap();
+
+        let result = MergeFileManager::retrieve_from_cache(&test_cache_path, "0".to_string(), KeyType::String, InputOptions::default(), &reject);
+        let error = result.unwrap_err().to_string();
+        assert!(error.contains(&format!("{} changed since the cache was written (it no longer exists)", test_filename_2)));
+
+        let result = MergeFileManager::retrieve_from_cache(&test_cache_path, "0".to_string(), KeyType::String, InputOptions::default(), &CacheOptions::default());
+        let merge_files = result.unwrap();
+        assert_eq!(merge_files.len(), 1);
+        assert_eq!(merge_files[test_filename_1].ending_merge_key, "125");
+    }
+
+    #[test]
+    fn non_utf8_cache_keys() {
+        let dir = TempDir::new("non_utf8_cache_keys");
+
+        // Latin-1 keys, which would all look alike if they went through a String
+        let test_filename_1: &str = &dir.join("file1.tsv");
+        fs::write(test_filename_1, b"a\xe9\t1\nb\xe9\t2\nc\xe9\t3\nd\xe9\t4\n").unwrap();
+
+        let cache = MergeFileManager::retrieve_from_glob(test_filename_1, '\t', 0, ByteString::default(), InputOptions::default()).unwrap();
+        let cache_path = dir.path().join("cache");
+        MergeFileManager::write_cache(&cache_path, cache, ByteString::default(), KeyType::String, Some(1), &CacheOptions::default()).unwrap();
+
+        let mut merge_files = MergeFileManager::retrieve_from_cache(&cache_path, ByteString::default(), KeyType::String, InputOptions::default(),
+                                                                    &CacheOptions::default()).unwrap();
+        let merge_file = merge_files.get_mut(test_filename_1).unwrap();
+        assert_eq!(merge_file.ending_merge_key, ByteString(b"d\xe9".to_vec()));
+        assert_eq!(merge_file.checkpoints.iter().map(|checkpoint| checkpoint.key.as_str()).collect::<Vec<&str>>(),
+                   vec!["a\\xe9", "b\\xe9", "c\\xe9", "d\\xe9"]);
+
+        // The checkpoints are skipped to by their real keys, landing on the first line from the start key on
+        assert!(merge_file.fast_forward("c").unwrap());
+        assert_eq!(merge_file.line, b"c\xe9\t3");
+        assert_eq!(merge_file.line_number, 3);
+    }
+
+    #[test]
+    fn refresh_cache() {
+        let dir = TempDir::new("refresh_cache");
+
+        let test_filenames = (1..5).map(|n| dir.join(&format!("file{}.tsv", n))).collect::<Vec<String>>();
+        create_file(&test_filenames[0], "123\taaa\n125\taaa\n");
+        create_file(&test_filenames[1], "123\tbbb\n126\tbbb\n");
+        create_file(&test_filenames[2], "123\tccc\n127\tccc\n");
+
+        let glob_choices = vec![dir.join("file?.tsv")];
+        let test_cache_path = dir.path().join("test.cache");
+
+        // Refreshing a cache that doesn't exist yet builds it
+        let refresh = MergeFileManager::refresh_cache(&test_cache_path, &glob_choices, '\t', 0, "0".to_string(), KeyType::String, InputOptions::default(), &CacheOptions::default(), None);
+        assert_eq!(refresh.unwrap(), CacheRefresh { kept: 0, rescanned: 0, added: 3, dropped: 0 });
+
+        // One file changes, one is deleted and one is new
+        create_file(&test_filenames[1], "123\tbbb\n126\tbbb\n128\tbbb\n");
+        let _ = fs::remove_file(&test_filenames[2]);
+        create_file(&test_filenames[3], "122\tddd\n129\tddd\n");
+
+        let refresh = MergeFileManager::refresh_cache(&test_cache_path, &glob_choices, '\t', 0, "0".to_string(), KeyType::String, InputOptions::default(), &CacheOptions::default(), None);
+        assert_eq!(refresh.unwrap(), CacheRefresh { kept: 1, rescanned: 1, added: 1, dropped: 1 });
+
+        let entries = CacheFile::read(&test_cache_path, None).unwrap().entries;
+        let keys = entries.iter().map(|entry| (entry.filename.as_str(), entry.beginning_merge_key.as_str(), entry.ending_merge_key.as_str()))
+                                 .collect::<Vec<(&str, &str, &str)>>();
+        assert_eq!(keys, vec![(test_filenames[0].as_str(), "123", "125"),
+                              (test_filenames[1].as_str(), "123", "128"),
+                              (test_filenames[3].as_str(), "122", "129")]);
+
+        // Nothing changed since, nothing is read again
+        let refresh = MergeFileManager::refresh_cache(&test_cache_path, &glob_choices, '\t', 0, "0".to_string(), KeyType::String, InputOptions::default(), &CacheOptions::default(), None);
+        assert_eq!(refresh.unwrap(), CacheRefresh { kept: 3, rescanned: 0, added: 0, dropped: 0 });
+
+        // Nor are files globbed by another name for them
+        let linked_dir = dir.path().join("linked");
+        symlink(dir.path(), &linked_dir).unwrap();
+        let linked_glob_choices = vec![linked_dir.join("file?.tsv").to_string_lossy().into_owned()];
+        let refresh = MergeFileManager::refresh_cache(&test_cache_path, &linked_glob_choices, '\t', 0, "0".to_string(), KeyType::String, InputOptions::default(), &CacheOptions::default(), None);
+        assert_eq!(refresh.unwrap(), CacheRefresh { kept: 3, rescanned: 0, added: 0, dropped: 0 });
+        assert_eq!(CacheFile::read(&test_cache_path, None).unwrap().entries.len(), 3);
+    }
+
+    #[test]
+    fn inspect_cache() {
+        let dir = TempDir::new("inspect_cache");
+
+        let test_filenames = (1..4).map(|number| dir.join(&format!("file{}.tsv", number))).collect::<Vec<String>>();
+        create_file(&test_filenames[0], "120\taaa\n125\taaa\n");
+        create_file(&test_filenames[1], "123\tbbb\n124\tbbb\n128\tbbb\n");
+        create_file(&test_filenames[2], "130\tccc\n");
+
+        let test_cache_path = dir.path().join("test.cache");
+        let cache = MergeFileManager::retrieve_from_glob(&dir.join("file?.tsv"), '\t', 0, 0u32, InputOptions::default()).unwrap();
+        MergeFileManager::write_cache(&test_cache_path, cache, 0u32, KeyType::Unsigned32Integer, None, &CacheOptions::default()).unwrap();
+
+        // A changed file is read to its end to find its ending key
+        create_file(&test_filenames[2], "130\tccc\n131\tccc\n");
+
+        let inspect = || MergeFileManager::inspect_cache(&test_cache_path, 0u32, KeyType::Unsigned32Integer, InputOptions::default(), &CacheOptions::default()).unwrap();
+        let merge_files = inspect();
+        assert_eq!(merge_files.iter().map(|merge_file| merge_file.filename.clone()).collect::<Vec<String>>(), test_filenames);
+
+        let stats = CacheStats::of(&merge_files);
+        assert_eq!(stats, CacheStats { files: 3, bytes: 16 + 24 + 16, lines: Some(7), key_range: Some((120, 131)) });
+        assert_eq!(stats.to_string(), "3 files, 56 bytes, 7 lines, merge keys 120 to 131");
+
+        let table = MergeFileManager::show_cache(&merge_files);
+        let lines = table.lines().collect::<Vec<&str>>();
+        assert_eq!(lines.len(), 4);
+        assert!(lines[0].starts_with(&format!("{:<1$}  bytes  lines  delimiter", "filename", test_filenames[1].len())));
+        assert!(lines[2].starts_with(&format!("{}  24     3      tsv", test_filenames[1])));
+        assert!(lines[2].ends_with("123                  128"));
+
+        // Files with lines in [start, end)
+        let query = |key_start: Option<&str>, key_end: Option<&str>| {
+            MergeFileManager::query_cache(inspect(), key_start, key_end).unwrap().into_iter().map(|merge_file| merge_file.filename).collect::<Vec<String>>()
+        };
+        assert_eq!(query(None, None), test_filenames);
+        assert_eq!(query(Some("126"), None), vec![test_filenames[1].clone(), test_filenames[2].clone()]);
+        assert_eq!(query(Some("121"), Some("123")), vec![test_filenames[0].clone()]);
+        assert_eq!(query(Some("129"), Some("130")), Vec::<String>::new());
+        assert!(MergeFileManager::query_cache(inspect(), Some("abc"), None).is_err());
 
-        let _ = fs::remove_file(test_filename_1);
-        let _ = fs::remove_file(test_filename_2);
-        let _ = fs::remove_file(test_cache_filename);
     }
 }
@@ -0,0 +1,374 @@
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+use std::io::BufWriter;
+use std::fs::File;
+use std::fmt;
+use std::fs;
+use std::io;
+
+// Optional compressors for output files
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use bzip2::write::BzEncoder;
+use bzip2;
+
+use merge_file::{escape_key, Mergeable};
+use bgzf::{BgzfWriter, KeyColumn};
+use cache_file::{CacheEntry, CacheFile};
+use settings::KeyType;
+
+/// Where the rows produced by a `MergeSink` end up.
+///
+/// Sinks call `start_row` with the row's merge key before writing the row itself, which lets an
+/// output decide which file the row belongs in (eg. rolling over to a new shard) before any of it is written.
+pub trait MergeOutput<T>: Write {
+    /// Called before each row is written.
+    fn start_row(&mut self, key: &T) -> io::Result<()>;
+
+    /// Called once after the final row, flushing and closing off anything still open.
+    fn finish(&mut self) -> io::Result<()> {
+        self.flush()
+    }
+}
+
+impl<T, W: Write> MergeOutput<T> for BufWriter<W> {
+    fn start_row(&mut self, _key: &T) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<T> MergeOutput<T> for Vec<u8> {
+    fn start_row(&mut self, _key: &T) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<T, O: MergeOutput<T> + ?Sized> MergeOutput<T> for Box<O> {
+    fn start_row(&mut self, key: &T) -> io::Result<()> {
+        (**self).start_row(key)
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        (**self).finish()
+    }
+}
+
+/// An output file, compressed based on its extension the same way `MergeFile` picks a decompressor.
+pub enum OutputFile {
+    Uncompressed(BufWriter<File>),
+    Gzip(GzEncoder<BufWriter<File>>),
+    Bzip2(BzEncoder<BufWriter<File>>),
+    /// BGZF, with a `BlockIndex` written next to it on `finish` if it's indexed
+    Bgzf(BgzfWriter<BufWriter<File>>, PathBuf),
+}
+
+impl OutputFile {
+    /// `key_column` says where the merge key is in the rows written, for BGZF outputs to index them by it.
+    /// Without it (eg. the rows don't include the key) BGZF outputs go unindexed.
+    pub fn create(path: &Path, key_column: Option<KeyColumn>) -> io::Result<OutputFile> {
+        if let Some(parent) = path.parent() {
+            if !parent.as_os_str().is_empty() {
+                fs::create_dir_all(parent)?;
+            }
+        }
+
+        let file = BufWriter::new(File::create(path)?);
+
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("gz") => {
+                debug!("Using GzEncoder as the output compressor for {}", path.display());
+                Ok(OutputFile::Gzip(GzEncoder::new(file, Compression::Default)))
+            },
+            Some("bz2") => {
+                debug!("Using BzEncoder as the output compressor for {}", path.display());
+                Ok(OutputFile::Bzip2(BzEncoder::new(file, bzip2::Compression::default())))
+            },
+            Some("bgz") => {
+                debug!("Using BgzfWriter as the output compressor for {}", path.display());
+                match key_column {
+                    Some(key_column) => Ok(OutputFile::Bgzf(BgzfWriter::indexed(file, key_column), path.to_path_buf())),
+                    None => {
+                        info!("Not indexing {} as its rows don't include the merge key", path.display());
+                        Ok(OutputFile::Bgzf(BgzfWriter::new(file), path.to_path_buf()))
+                    },
+                }
+            },
+            _ => Ok(OutputFile::Uncompressed(file)),
+        }
+    }
+}
+
+impl Write for OutputFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match *self {
+            OutputFile::Uncompressed(ref mut file) => file.write(buf),
+            OutputFile::Gzip(ref mut file) => file.write(buf),
+            OutputFile::Bzip2(ref mut file) => file.write(buf),
+            OutputFile::Bgzf(ref mut file, _) => file.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            OutputFile::Uncompressed(ref mut file) => file.flush(),
+            OutputFile::Gzip(ref mut file) => file.flush(),
+            OutputFile::Bzip2(ref mut file) => file.flush(),
+            OutputFile::Bgzf(ref mut file, _) => file.flush(),
+        }
+    }
+}
+
+impl<T: Mergeable> MergeOutput<T> for OutputFile {
+    fn start_row(&mut self, key: &T) -> io::Result<()> {
+        if let OutputFile::Bgzf(ref mut file, _) = *self {
+            file.index_row(key);
+        }
+        Ok(())
+    }
+
+    /// Writes out the compression trailer (if any) and flushes the file, along with a BGZF file's index.
+    fn finish(&mut self) -> io::Result<()> {
+        match *self {
+            OutputFile::Uncompressed(ref mut file) => file.flush(),
+            OutputFile::Gzip(ref mut file) => {
+                file.try_finish()?;
+                file.get_mut().flush()
+            },
+            OutputFile::Bzip2(ref mut file) => {
+                file.try_finish()?;
+                file.get_mut().flush()
+            },
+            OutputFile::Bgzf(ref mut file, ref path) => {
+                file.try_finish()?;
+                file.get_mut().flush()?;
+                match file.index() {
+                    Some(index) => index.write_for(path),
+                    None => Ok(()),
+                }
+            },
+        }
+    }
+}
+
+/// Returns `path` with `.<number>` inserted before its extension, eg. out.gz -> out.00001.gz
+pub fn numbered_path(path: &Path, number: usize) -> PathBuf {
+    let stem = path.file_stem().map(|stem| stem.to_string_lossy().into_owned()).unwrap_or_default();
+
+    let filename = match path.extension() {
+        Some(extension) => format!("{}.{:05}.{}", stem, number, extension.to_string_lossy()),
+        None => format!("{}.{:05}", stem, number),
+    };
+
+    path.with_file_name(filename)
+}
+
+/// Splits the output across numbered shards, rolling over once a shard reaches a byte or row limit.
+///
+/// The byte limit applies to the uncompressed rows. Once all shards are written a manifest
+/// (`<output>.manifest`) lists each shard with its first and last merge key. It's a cache file, so the shards
+/// can be merged again straight from it with `--cache-file`. That needs the key in the rows written, so there's
+/// no manifest without a `key_column`.
+pub struct ShardedOutput<T> {
+    path: PathBuf,
+    max_bytes: Option<u64>,
+    max_rows: Option<u64>,
+    whole_keys: bool,
+    key_column: Option<KeyColumn>,
+    key_type: KeyType,
+    shard: Option<OutputFile>,
+    shard_path: PathBuf,
+    shard_count: usize,
+    shard_bytes: u64,
+    shard_rows: u64,
+    first_key: Option<T>,
+    last_key: Option<T>,
+    manifest: Vec<CacheEntry>,
+}
+
+impl<T: Mergeable> ShardedOutput<T> where T::Err: fmt::Debug {
+    /// With `whole_keys` set a run of equal merge keys is never split across two shards,
+    /// so shards can overshoot their limits by up to one run.
+    pub fn new(path: PathBuf, max_bytes: Option<u64>, max_rows: Option<u64>, whole_keys: bool, key_column: Option<KeyColumn>,
+               key_type: KeyType) -> ShardedOutput<T> {
+        ShardedOutput {
+            path,
+            max_bytes,
+            max_rows,
+            whole_keys,
+            key_column,
+            key_type,
+            shard: None,
+            shard_path: PathBuf::new(),
+            shard_count: 0,
+            shard_bytes: 0,
+            shard_rows: 0,
+            first_key: None,
+            last_key: None,
+            manifest: Vec::new(),
+        }
+    }
+
+    fn is_full(&self) -> bool {
+        self.max_bytes.is_some_and(|max_bytes| self.shard_bytes >= max_bytes) ||
+            self.max_rows.is_some_and(|max_rows| self.shard_rows >= max_rows)
+    }
+
+    fn close_shard(&mut self) -> io::Result<()> {
+        if let Some(mut shard) = self.shard.take() {
+            MergeOutput::<T>::finish(&mut shard)?;
+
+            info!("Finished shard {} ({} rows)", self.shard_path.display(), self.shard_rows);
+
+            let key_column = match self.key_column {
+                Some(key_column) => key_column,
+                None => return Ok(()),
+            };
+
+            let mut entry = CacheEntry {
+                filename: self.shard_path.to_string_lossy().into_owned(),
+                beginning_merge_key: self.first_key.take().map(|key| escape_key(&key.to_bytes())).unwrap_or_default(),
+                ending_merge_key: self.last_key.take().map(|key| escape_key(&key.to_bytes())).unwrap_or_default(),
+                delimiter: key_column.delimiter,
+                key_index: key_column.key_index,
+                line_count: Some(self.shard_rows),
+                byte_count: Some(self.shard_bytes),
+                ..CacheEntry::default()
+            };
+            entry.record_file_state()?;
+            self.manifest.push(entry);
+        }
+
+        Ok(())
+    }
+
+    fn write_manifest(&self) -> io::Result<()> {
+        let manifest_path = PathBuf::from(format!("{}.manifest", self.path.display()));
+        if self.key_column.is_none() {
+            info!("Not writing {} as the shards' rows don't include the merge key", manifest_path.display());
+            return Ok(());
+        }
+
+        CacheFile::new(self.key_type.clone(), self.manifest.clone()).write(&manifest_path)?;
+        info!("Written shard manifest to {}", manifest_path.display());
+        Ok(())
+    }
+}
+
+impl<T: Mergeable> Write for ShardedOutput<T> where T::Err: fmt::Debug {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.shard {
+            Some(ref mut shard) => {
+                let written = shard.write(buf)?;
+                self.shard_bytes += written as u64;
+                Ok(written)
+            },
+            None => Err(io::Error::other("Row written to a sharded output without calling start_row")),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.shard {
+            Some(ref mut shard) => shard.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl<T: Mergeable> MergeOutput<T> for ShardedOutput<T> where T::Err: fmt::Debug {
+    fn start_row(&mut self, key: &T) -> io::Result<()> {
+        if self.shard.is_some() && self.is_full() && !(self.whole_keys && self.last_key.as_ref() == Some(key)) {
+            self.close_shard()?;
+        }
+
+        if self.shard.is_none() {
+            self.shard_path = numbered_path(&self.path, self.shard_count);
+            self.shard = Some(OutputFile::create(&self.shard_path, self.key_column)?);
+            self.shard_count += 1;
+            self.shard_bytes = 0;
+            self.shard_rows = 0;
+            self.first_key = Some(key.clone());
+            debug!("Started shard {}", self.shard_path.display());
+        }
+
+        self.shard_rows += 1;
+
+        // Reuse the last key's allocation where we can, this is called for every row
+        match self.last_key {
+            Some(ref mut last_key) => last_key.clone_from(key),
+            None => self.last_key = Some(key.clone()),
+        }
+
+        Ok(())
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        self.close_shard()?;
+        self.write_manifest()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::prelude::*;
+    use std::path::{Path, PathBuf};
+    use std::fs::File;
+
+    use super::{numbered_path, MergeOutput, ShardedOutput};
+    use bgzf::KeyColumn;
+    use cache_file::CacheFile;
+    use settings::KeyType;
+    use test_helpers::TempDir;
+
+    #[test]
+    fn numbered_paths() {
+        assert_eq!(numbered_path(Path::new("/data/out.gz"), 1), PathBuf::from("/data/out.00001.gz"));
+        assert_eq!(numbered_path(Path::new("out.tsv"), 12), PathBuf::from("out.00012.tsv"));
+        assert_eq!(numbered_path(Path::new("out"), 0), PathBuf::from("out.00000"));
+    }
+
+    #[test]
+    fn sharded_output() {
+        let dir = TempDir::new("sharded_output");
+
+        let output_path = dir.path().join("output.tsv");
+
+        let mut output = ShardedOutput::new(output_path.clone(), None, Some(2), true, Some(KeyColumn { delimiter: '\t', key_index: 0 }),
+                                            KeyType::String);
+        for key in &["1", "2", "2", "2", "3", "4"] {
+            let key = key.to_string();
+            output.start_row(&key).unwrap();
+            writeln!(output, "{}\tvalue", key).unwrap();
+        }
+        MergeOutput::<String>::finish(&mut output).unwrap();
+
+        // The run of 2's is kept whole, so the first shard overshoots by two rows
+        let mut contents = String::new();
+        File::open(dir.path().join("output.00000.tsv")).unwrap().read_to_string(&mut contents).unwrap();
+        assert_eq!(contents, "1\tvalue\n2\tvalue\n2\tvalue\n2\tvalue\n");
+
+        // The manifest is a cache of the shards, recording enough to notice them changing
+        let manifest = CacheFile::read(&dir.path().join("output.tsv.manifest"), None).unwrap();
+        assert_eq!(manifest.header.key_type, Some(KeyType::String));
+        assert_eq!(manifest.entries.len(), 2);
+
+        let shards = manifest.entries.iter()
+                                     .map(|entry| (entry.filename.as_str(), entry.beginning_merge_key.as_str(), entry.ending_merge_key.as_str(), entry.line_count))
+                                     .collect::<Vec<_>>();
+        assert_eq!(shards, vec![(&*dir.join("output.00000.tsv"), "1", "2", Some(4)), (&*dir.join("output.00001.tsv"), "3", "4", Some(2))]);
+        assert_eq!(manifest.entries[1].filesize, Some(16));
+        assert_eq!(manifest.entries[1].byte_count, Some(16));
+        assert_eq!(manifest.entries[1].changes().unwrap(), None);
+
+        // Without the key in the rows there's nothing to list the shards by
+        let output_path = dir.path().join("values.tsv");
+        let mut output = ShardedOutput::new(output_path.clone(), None, Some(2), true, None, KeyType::String);
+        for key in &["1", "2", "3"] {
+            output.start_row(&key.to_string()).unwrap();
+            writeln!(output, "value").unwrap();
+        }
+        MergeOutput::<String>::finish(&mut output).unwrap();
+
+        assert!(dir.path().join("values.00001.tsv").exists());
+        assert!(!dir.path().join("values.tsv.manifest").exists());
+    }
+}
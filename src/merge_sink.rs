@@ -0,0 +1,259 @@
+use std::io::prelude::*;
+use std::str::FromStr;
+use std::io;
+
+use merge_file::MergeFile;
+use input_format::InputFormat;
+use merge_output::MergeOutput;
+
+/// Receives every line emitted by the k-way merge, in merge key order.
+///
+/// The merge hands over the `MergeFile` positioned on the emitted line, so a sink has access to the
+/// line itself, its merge key and the file it came from.
+pub trait MergeSink<T> {
+    /// Called once for each line the merge emits.
+    fn write_line(&mut self, merge_file: &MergeFile<T>) -> io::Result<()>;
+
+    /// Called once after the final line has been emitted.
+    fn finish(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<T, S: MergeSink<T> + ?Sized> MergeSink<T> for Box<S> {
+    fn write_line(&mut self, merge_file: &MergeFile<T>) -> io::Result<()> {
+        (**self).write_line(merge_file)
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        (**self).finish()
+    }
+}
+
+/// An extra output column describing where a merged line came from.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Annotation {
+    Filename,
+    LineNumber,
+    Offset,
+}
+
+impl FromStr for Annotation {
+    type Err = String;
+
+    fn from_str(annotation: &str) -> Result<Annotation, String> {
+        match annotation.trim() {
+            "filename" => Ok(Annotation::Filename),
+            "lineno"   => Ok(Annotation::LineNumber),
+            "offset"   => Ok(Annotation::Offset),
+            _          => Err(format!("Unknown annotation '{}', expected filename, lineno or offset", annotation)),
+        }
+    }
+}
+
+/// Writes each merged line out, optionally projected down to some columns and annotated with where it came from.
+pub struct MergeWriter<W: Write> {
+    output: W,
+    annotations: Vec<Annotation>,
+    prepend_annotations: bool,
+    columns: Option<Vec<usize>>,
+    field_ranges: Vec<(usize, usize)>,
+}
+
+impl<W: Write> MergeWriter<W> {
+    pub fn new(output: W) -> MergeWriter<W> {
+        MergeWriter {
+            output,
+            annotations: Vec::new(),
+            prepend_annotations: false,
+            columns: None,
+            field_ranges: Vec::new(),
+        }
+    }
+
+    /// Only writes out these columns of each line, in the order given. Missing columns are left empty.
+    pub fn project(mut self, columns: Option<Vec<usize>>) -> MergeWriter<W> {
+        self.columns = columns;
+        self
+    }
+
+    fn write_merged_line<T>(&mut self, merge_file: &MergeFile<T>) -> io::Result<()> {
+        let columns = match self.columns {
+            Some(ref columns) => columns,
+            None => return self.output.write_all(&merge_file.line),
+        };
+
+        let mut delimiter = [0; 4];
+        let delimiter = merge_file.delimiter.encode_utf8(&mut delimiter).as_bytes();
+
+        // Find where each field starts and ends once, so columns can be written in any order.
+        // Fields are written as they are in the line, so quoted CSV columns stay quoted.
+        let line = &merge_file.line;
+        self.field_ranges.clear();
+        for field in merge_file.format.fields(line, delimiter) {
+            let start = field.as_ptr() as usize - line.as_ptr() as usize;
+            self.field_ranges.push((start, start + field.len()));
+        }
+
+        if merge_file.format == InputFormat::JsonLines {
+            // The columns are the object's members, ones it doesn't have are left out
+            let field_ranges = &self.field_ranges;
+            let mut members = columns.iter().filter_map(|column| field_ranges.get(*column));
+
+            self.output.write_all(b"{")?;
+            if let Some(&(start, end)) = members.next() {
+                self.output.write_all(&line[start..end])?;
+            }
+            for &(start, end) in members {
+                self.output.write_all(b",")?;
+                self.output.write_all(&line[start..end])?;
+            }
+            return self.output.write_all(b"}");
+        }
+
+        for (i, column) in columns.iter().enumerate() {
+            if i > 0 {
+                self.output.write_all(delimiter)?;
+            }
+            if let Some(&(start, end)) = self.field_ranges.get(*column) {
+                self.output.write_all(&line[start..end])?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Adds the annotations as extra columns before or after each line, using the line's delimiter.
+    pub fn annotate(mut self, annotations: Vec<Annotation>, prepend: bool) -> MergeWriter<W> {
+        self.annotations = annotations;
+        self.prepend_annotations = prepend;
+        self
+    }
+}
+
+fn write_annotation<T, W: Write>(output: &mut W, annotation: &Annotation, merge_file: &MergeFile<T>) -> io::Result<()> {
+    match *annotation {
+        Annotation::Filename => write!(output, "{}", merge_file.filename),
+        Annotation::LineNumber => write!(output, "{}", merge_file.line_number),
+        Annotation::Offset => write!(output, "{}", merge_file.line_offset),
+    }
+}
+
+impl<T, W: MergeOutput<T>> MergeSink<T> for MergeWriter<W> {
+    fn write_line(&mut self, merge_file: &MergeFile<T>) -> io::Result<()> {
+        self.output.start_row(&merge_file.current_merge_key)?;
+
+        if self.prepend_annotations {
+            for annotation in &self.annotations {
+                write_annotation(&mut self.output, annotation, merge_file)?;
+                write!(self.output, "{}", merge_file.delimiter)?;
+            }
+            self.write_merged_line(merge_file)?;
+        } else {
+            self.write_merged_line(merge_file)?;
+            for annotation in &self.annotations {
+                write!(self.output, "{}", merge_file.delimiter)?;
+                write_annotation(&mut self.output, annotation, merge_file)?;
+            }
+        }
+
+        writeln!(self.output)
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        self.output.finish()
+    }
+}
+
+/// Collects the merged lines in memory, mostly useful for testing.
+impl<T> MergeSink<T> for Vec<String> {
+    fn write_line(&mut self, merge_file: &MergeFile<T>) -> io::Result<()> {
+        self.push(String::from_utf8_lossy(&merge_file.line).into_owned());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Annotation, MergeSink, MergeWriter};
+    use merge_file::MergeFile;
+    use input_format::InputFormat;
+    use test_helpers::{create_file, TempDir};
+
+    #[test]
+    fn annotate() {
+        let dir = TempDir::new("annotate");
+
+        let test_filename_1: &str = &dir.join("file1.psv");
+        let test_contents_1 = format!("{}|{}\n\
+                                       {}|{}\n",
+                                        "123", "aaa",
+                                        "124", "bbb");
+
+        create_file(test_filename_1, test_contents_1);
+
+        let mut merge_file = MergeFile::new(test_filename_1, '|', 0, "0".to_string()).unwrap();
+        merge_file.next();
+
+        let annotations = vec![Annotation::Filename, Annotation::LineNumber, Annotation::Offset];
+
+        let mut writer = MergeWriter::new(Vec::new()).annotate(annotations.clone(), false);
+        writer.write_line(&merge_file).unwrap();
+        assert_eq!(String::from_utf8(writer.output).unwrap(), format!("124|bbb|{}|2|8\n", test_filename_1));
+
+        let mut writer = MergeWriter::new(Vec::new()).annotate(annotations, true);
+        writer.write_line(&merge_file).unwrap();
+        assert_eq!(String::from_utf8(writer.output).unwrap(), format!("{}|2|8|124|bbb\n", test_filename_1));
+    }
+
+    #[test]
+    fn project() {
+        let dir = TempDir::new("project");
+
+        let test_filename_1: &str = &dir.join("file1.csv");
+        let test_contents_1 = format!("{},{},{},{}\n", "123", "aaa", "bbb", "ccc");
+
+        create_file(test_filename_1, test_contents_1);
+
+        let merge_file = MergeFile::new(test_filename_1, ',', 0, "0".to_string()).unwrap();
+
+        let mut writer = MergeWriter::new(Vec::new()).project(Some(vec![3, 0, 5, 1]));
+        writer.write_line(&merge_file).unwrap();
+        assert_eq!(String::from_utf8(writer.output).unwrap(), "ccc,123,,aaa\n");
+
+        let mut writer = MergeWriter::new(Vec::new()).project(Some(vec![2]))
+                                                     .annotate(vec![Annotation::LineNumber], false);
+        writer.write_line(&merge_file).unwrap();
+        assert_eq!(String::from_utf8(writer.output).unwrap(), "bbb,1\n");
+    }
+
+    #[test]
+    fn project_csv() {
+        let dir = TempDir::new("project_csv");
+
+        let test_filename_1: &str = &dir.join("file1.csv");
+        create_file(test_filename_1, "123,\"aaa,bbb\",\"say \"\"ccc\"\"\"\n");
+
+        let mut merge_file = MergeFile::new(test_filename_1, ',', 0, "0".to_string()).unwrap();
+        merge_file.format = InputFormat::Csv;
+
+        let mut writer = MergeWriter::new(Vec::new()).project(Some(vec![2, 1, 0]));
+        writer.write_line(&merge_file).unwrap();
+        assert_eq!(String::from_utf8(writer.output).unwrap(), "\"say \"\"ccc\"\"\",\"aaa,bbb\",123\n");
+    }
+
+    #[test]
+    fn project_json_lines() {
+        let dir = TempDir::new("project_json_lines");
+
+        let test_filename_1: &str = &dir.join("file1.jsonl");
+        create_file(test_filename_1, "{\"id\": 123, \"tags\": [\"a\", \"b\"], \"name\": \"x,}\"}\n");
+
+        let mut merge_file = MergeFile::new(test_filename_1, ',', 0, "0".to_string()).unwrap();
+        merge_file.format = InputFormat::JsonLines;
+
+        let mut writer = MergeWriter::new(Vec::new()).project(Some(vec![2, 5, 0]));
+        writer.write_line(&merge_file).unwrap();
+        assert_eq!(String::from_utf8(writer.output).unwrap(), "{\"name\": \"x,}\",\"id\": 123}\n");
+    }
+}
@@ -0,0 +1,509 @@
+use std::collections::HashSet;
+use std::io::prelude::*;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::fmt;
+use std::io;
+
+use bgzf::KeyColumn;
+use merge_file::Mergeable;
+use merge_output::{numbered_path, MergeOutput, OutputFile};
+
+/// How merge keys are grouped into output buckets.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Partitioning {
+    /// Timestamp keys (epoch seconds or ISO-8601) bucketed per hour, eg. 2017-03-01T13
+    Hour,
+    /// Timestamp keys (epoch seconds or ISO-8601) bucketed per day, eg. 2017-03-01
+    Day,
+    /// Keys bucketed by the greatest boundary they are greater than or equal to
+    Boundaries(Vec<String>),
+}
+
+impl FromStr for Partitioning {
+    type Err = String;
+
+    /// Parses `hour`, `day` or `boundaries:<b1>,<b2>,...`
+    fn from_str(partitioning: &str) -> Result<Partitioning, String> {
+        match partitioning.trim() {
+            "hour" => Ok(Partitioning::Hour),
+            "day" => Ok(Partitioning::Day),
+            x if x.starts_with("boundaries:") => {
+                let boundaries = x["boundaries:".len()..].split(',')
+                                                         .map(|boundary| boundary.to_string())
+                                                         .collect::<Vec<String>>();
+                if boundaries.iter().any(|boundary| boundary.is_empty()) {
+                    return Err(format!("Empty partition boundary in '{}'", partitioning));
+                }
+                Ok(Partitioning::Boundaries(boundaries))
+            },
+            _ => Err(format!("Unknown partitioning '{}', expected hour, day or boundaries:<b1>,<b2>,...", partitioning)),
+        }
+    }
+}
+
+/// A point in time broken down far enough to name hourly and daily buckets.
+#[derive(Clone, Debug, PartialEq)]
+struct Timestamp {
+    year: i64,
+    month: u32,
+    day: u32,
+    hour: u32,
+}
+
+impl Timestamp {
+    /// Parses either epoch seconds or the start of an ISO-8601 timestamp (YYYY-MM-DD[T ]HH...)
+    fn parse(key: &str) -> Option<Timestamp> {
+        if let Ok(seconds) = key.parse::<i64>() {
+            return Some(Timestamp::from_epoch_seconds(seconds));
+        }
+
+        let bytes = key.as_bytes();
+        let digits = |start: usize, end: usize| -> Option<u32> {
+            key.get(start..end).and_then(|digits| if digits.bytes().all(|b| b.is_ascii_digit()) { digits.parse::<u32>().ok() } else { None })
+        };
+
+        if bytes.len() < 10 || bytes[4] != b'-' || bytes[7] != b'-' {
+            return None;
+        }
+
+        let hour = if bytes.len() >= 13 && (bytes[10] == b'T' || bytes[10] == b' ') {
+            digits(11, 13)?
+        } else {
+            0
+        };
+
+        Some(Timestamp {
+            year: digits(0, 4)? as i64,
+            month: digits(5, 7)?,
+            day: digits(8, 10)?,
+            hour,
+        })
+    }
+
+    /// Converts epoch seconds to a UTC date, see http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    fn from_epoch_seconds(seconds: i64) -> Timestamp {
+        let days = seconds.div_euclid(86400);
+        let hour = (seconds.rem_euclid(86400) / 3600) as u32;
+
+        let z = days + 719468;
+        let era = z.div_euclid(146097);
+        let day_of_era = z.rem_euclid(146097);
+        let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let shifted_month = (5 * day_of_year + 2) / 153;
+        let day = (day_of_year - (153 * shifted_month + 2) / 5 + 1) as u32;
+        let month = (if shifted_month < 10 { shifted_month + 3 } else { shifted_month - 9 }) as u32;
+        let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+        Timestamp {
+            year,
+            month,
+            day,
+            hour,
+        }
+    }
+}
+
+/// Routes each row into the output file for its key's bucket.
+///
+/// The output path is a template, `{bucket}` is replaced with the bucket name and for time based
+/// partitioning `{year}`, `{month}`, `{day}` and `{hour}` are available as well.
+/// As the merge emits keys in order, only the current bucket's file is ever open.
+pub struct PartitionedOutput<T> {
+    template: String,
+    partitioning: Partitioning,
+    boundaries: Vec<T>,
+    last_key: Option<T>,
+    bucket: Option<String>,
+    bucket_file: Option<OutputFile>,
+    finished_buckets: HashSet<String>,
+    key_column: Option<KeyColumn>,
+}
+
+impl<T: Mergeable> PartitionedOutput<T> where T::Err: fmt::Debug {
+    /// `key_column` is where the merge key is in the rows written, see `OutputFile::create`.
+    pub fn new(template: String, partitioning: Partitioning, key_column: Option<KeyColumn>) -> Result<PartitionedOutput<T>, String> {
+        let mut boundaries = Vec::new();
+
+        if let Partitioning::Boundaries(ref raw_boundaries) = partitioning {
+            for boundary in raw_boundaries {
+                match boundary.parse::<T>() {
+                    Ok(boundary) => boundaries.push(boundary),
+                    Err(error) => return Err(format!("Partition boundary '{}' isn't a valid key: {:?}", boundary, error)),
+                }
+            }
+
+            if boundaries.windows(2).any(|pair| pair[0] >= pair[1]) {
+                return Err("Partition boundaries need to be in ascending order".to_string());
+            }
+        }
+
+        // Every bucket needs its own path, otherwise each bucket would truncate the file the one before it wrote
+        let placeholders: &[&str] = match partitioning {
+            Partitioning::Hour => &["{year}", "{month}", "{day}", "{hour}"],
+            Partitioning::Day => &["{year}", "{month}", "{day}"],
+            Partitioning::Boundaries(_) => &[],
+        };
+        if !template.contains("{bucket}") && (placeholders.is_empty() || !placeholders.iter().all(|placeholder| template.contains(placeholder))) {
+            return Err(if placeholders.is_empty() {
+                format!("Partitioned output path '{}' needs a {{bucket}} placeholder", template)
+            } else {
+                format!("Partitioned output path '{}' needs a {{bucket}} placeholder, or all of {}", template, placeholders.join(" "))
+            });
+        }
+
+        Ok(PartitionedOutput {
+            template,
+            partitioning,
+            boundaries,
+            last_key: None,
+            bucket: None,
+            bucket_file: None,
+            finished_buckets: HashSet::new(),
+            key_column,
+        })
+    }
+
+    /// Returns the bucket name and output path for a key
+    fn bucket_for(&self, key: &T) -> io::Result<(String, PathBuf)> {
+        let key_string = key.to_string();
+
+        let timestamp_bucket = |format: fn(&Timestamp) -> String| -> io::Result<(String, PathBuf)> {
+            let timestamp = match Timestamp::parse(&key_string) {
+                Some(timestamp) => timestamp,
+                None => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Merge key '{}' isn't a timestamp", key_string))),
+            };
+
+            let bucket = format(&timestamp);
+            let path = self.template.replace("{bucket}", &bucket)
+                                    .replace("{year}", &format!("{:04}", timestamp.year))
+                                    .replace("{month}", &format!("{:02}", timestamp.month))
+                                    .replace("{day}", &format!("{:02}", timestamp.day))
+                                    .replace("{hour}", &format!("{:02}", timestamp.hour));
+            Ok((bucket, PathBuf::from(path)))
+        };
+
+        match self.partitioning {
+            Partitioning::Hour => timestamp_bucket(|t| format!("{:04}-{:02}-{:02}T{:02}", t.year, t.month, t.day, t.hour)),
+            Partitioning::Day => timestamp_bucket(|t| format!("{:04}-{:02}-{:02}", t.year, t.month, t.day)),
+            Partitioning::Boundaries(_) => {
+                let bucket = match self.boundaries.iter().rposition(|boundary| boundary <= key) {
+                    Some(index) => self.boundaries[index].to_string(),
+                    None => format!("below-{}", self.boundaries[0]),
+                };
+                let path = self.template.replace("{bucket}", &bucket);
+                Ok((bucket, PathBuf::from(path)))
+            },
+        }
+    }
+
+    fn close_bucket(&mut self) -> io::Result<()> {
+        if let Some(mut bucket_file) = self.bucket_file.take() {
+            MergeOutput::<T>::finish(&mut bucket_file)?;
+        }
+        if let Some(bucket) = self.bucket.take() {
+            info!("Finished partition bucket {}", bucket);
+            self.finished_buckets.insert(bucket);
+        }
+        Ok(())
+    }
+}
+
+impl<T: Mergeable> Write for PartitionedOutput<T> where T::Err: fmt::Debug {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.bucket_file {
+            Some(ref mut bucket_file) => bucket_file.write(buf),
+            None => Err(io::Error::other("Row written to a partitioned output without calling start_row")),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.bucket_file {
+            Some(ref mut bucket_file) => bucket_file.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl<T: Mergeable> MergeOutput<T> for PartitionedOutput<T> where T::Err: fmt::Debug {
+    fn start_row(&mut self, key: &T) -> io::Result<()> {
+        // Rows with the same key always land in the same bucket
+        if self.bucket_file.is_some() && self.last_key.as_ref() == Some(key) {
+            return Ok(());
+        }
+
+        let (bucket, path) = self.bucket_for(key)?;
+
+        if self.bucket.as_ref() != Some(&bucket) {
+            self.close_bucket()?;
+
+            if self.finished_buckets.contains(&bucket) {
+                return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                          format!("Bucket {} came up again after it was closed, are the inputs sorted?", bucket)));
+            }
+
+            debug!("Starting partition bucket {} -> {}", bucket, path.display());
+            self.bucket_file = Some(OutputFile::create(&path, self.key_column)?);
+            self.bucket = Some(bucket);
+        }
+
+        match self.last_key {
+            Some(ref mut last_key) => last_key.clone_from(key),
+            None => self.last_key = Some(key.clone()),
+        }
+
+        Ok(())
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        self.close_bucket()
+    }
+}
+
+/// The `stable_hash` of nothing, to start hashing from a piece at a time with `stable_hash_extend`.
+pub const STABLE_HASH_START: u64 = 0xcbf29ce484222325;
+
+/// 64 bit FNV-1a, unlike `DefaultHasher` it's stable across runs, machines and Rust versions.
+pub fn stable_hash(bytes: &[u8]) -> u64 {
+    stable_hash_extend(STABLE_HASH_START, bytes)
+}
+
+/// Carries on a `stable_hash` with more bytes, hashing in pieces gives the same hash as hashing them all at once.
+pub fn stable_hash_extend(mut hash: u64, bytes: &[u8]) -> u64 {
+    for byte in bytes {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+/// Spreads rows across a fixed number of output files by the hash of their merge key.
+///
+/// All rows with the same key land in the same partition and, as the merge emits keys in order,
+/// each partition is itself sorted. The output path may contain a `{partition}` placeholder,
+/// otherwise the partition number is inserted before its extension (eg. out.00003.gz).
+pub struct HashPartitionedOutput<T> {
+    partitions: Vec<OutputFile>,
+    last_key: Option<T>,
+    partition: usize,
+    /// The raw bytes of the last key, reused between keys
+    key_bytes: Vec<u8>,
+}
+
+impl<T: Mergeable> HashPartitionedOutput<T> where T::Err: fmt::Debug {
+    /// `key_column` is where the merge key is in the rows written, see `OutputFile::create`.
+    pub fn new(path: PathBuf, partition_count: usize, key_column: Option<KeyColumn>) -> io::Result<HashPartitionedOutput<T>> {
+        let template = path.to_string_lossy().into_owned();
+        let mut partitions = Vec::with_capacity(partition_count);
+
+        for partition in 0..partition_count {
+            let partition_path = if template.contains("{partition}") {
+                PathBuf::from(template.replace("{partition}", &format!("{:05}", partition)))
+            } else {
+                numbered_path(&path, partition)
+            };
+
+            partitions.push(OutputFile::create(&partition_path, key_column)?);
+        }
+
+        Ok(HashPartitionedOutput {
+            partitions,
+            last_key: None,
+            partition: 0,
+            key_bytes: Vec::new(),
+        })
+    }
+}
+
+impl<T: Mergeable> Write for HashPartitionedOutput<T> where T::Err: fmt::Debug {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.partitions[self.partition].write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        for partition in self.partitions.iter_mut() {
+            partition.flush()?;
+        }
+        Ok(())
+    }
+}
+
+impl<T: Mergeable> MergeOutput<T> for HashPartitionedOutput<T> where T::Err: fmt::Debug {
+    fn start_row(&mut self, key: &T) -> io::Result<()> {
+        // Only hash each distinct key once, hashing the key as it was read so keys that aren't UTF-8 don't collide
+        if self.last_key.as_ref() != Some(key) {
+            self.key_bytes.clear();
+            key.write_to(&mut self.key_bytes)?;
+            self.partition = (stable_hash(&self.key_bytes) % self.partitions.len() as u64) as usize;
+
+            match self.last_key {
+                Some(ref mut last_key) => last_key.clone_from(key),
+                None => self.last_key = Some(key.clone()),
+            }
+        }
+
+        Ok(())
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        for partition in self.partitions.iter_mut() {
+            MergeOutput::<T>::finish(partition)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::io::prelude::*;
+    use std::fs::File;
+
+    use super::{stable_hash, stable_hash_extend, HashPartitionedOutput, Partitioning, PartitionedOutput, Timestamp};
+    use std::path::PathBuf;
+    use merge_file::ByteString;
+    use merge_output::MergeOutput;
+    use test_helpers::TempDir;
+
+    fn read_file(filename: &str) -> String {
+        let mut contents = String::new();
+        File::open(filename).unwrap().read_to_string(&mut contents).unwrap();
+        contents
+    }
+
+    #[test]
+    fn parse_timestamps() {
+        assert_eq!(Timestamp::parse("0"), Some(Timestamp { year: 1970, month: 1, day: 1, hour: 0 }));
+        assert_eq!(Timestamp::parse("951782400"), Some(Timestamp { year: 2000, month: 2, day: 29, hour: 0 }));
+        assert_eq!(Timestamp::parse("1488376800"), Some(Timestamp { year: 2017, month: 3, day: 1, hour: 14 }));
+        assert_eq!(Timestamp::parse("2017-03-01T14:59:59Z"), Some(Timestamp { year: 2017, month: 3, day: 1, hour: 14 }));
+        assert_eq!(Timestamp::parse("2017-03-01 09:00"), Some(Timestamp { year: 2017, month: 3, day: 1, hour: 9 }));
+        assert_eq!(Timestamp::parse("2017-03-01"), Some(Timestamp { year: 2017, month: 3, day: 1, hour: 0 }));
+        assert_eq!(Timestamp::parse("abcd"), None);
+    }
+
+    #[test]
+    fn parse_partitioning() {
+        assert_eq!("hour".parse::<Partitioning>(), Ok(Partitioning::Hour));
+        assert_eq!("boundaries:10,20".parse::<Partitioning>(), Ok(Partitioning::Boundaries(vec!["10".to_string(), "20".to_string()])));
+        assert!("boundaries:10,,20".parse::<Partitioning>().is_err());
+        assert!("minute".parse::<Partitioning>().is_err());
+    }
+
+    #[test]
+    fn hourly_partitions() {
+        let dir = TempDir::new("hourly_partitions");
+
+        let mut output = PartitionedOutput::new(dir.join("{year}{month}{day}/{hour}.tsv"), Partitioning::Hour, None).unwrap();
+
+        for key in &[1488376800u32, 1488376801, 1488380400] {
+            output.start_row(key).unwrap();
+            writeln!(output, "{}", key).unwrap();
+        }
+        output.finish().unwrap();
+
+        assert_eq!(read_file(&dir.join("20170301/14.tsv")), "1488376800\n1488376801\n");
+        assert_eq!(read_file(&dir.join("20170301/15.tsv")), "1488380400\n");
+
+        // Going back to an earlier bucket means the input wasn't sorted
+        let mut output = PartitionedOutput::new(dir.join("{bucket}.tsv"), Partitioning::Hour, None).unwrap();
+        assert!(output.start_row(&1488380400u32).is_ok());
+        assert!(output.start_row(&1488376800u32).is_ok());
+        assert!(output.start_row(&1488380400u32).is_err());
+
+        // Paths coarser than an hour would be reused, and truncated, by each hour's bucket
+        assert!(PartitionedOutput::<u32>::new(dir.join("{year}-{month}.tsv"), Partitioning::Hour, None).is_err());
+        assert!(PartitionedOutput::<u32>::new(dir.join("{year}-{month}-{day}.tsv"), Partitioning::Hour, None).is_err());
+        assert!(PartitionedOutput::<u32>::new(dir.join("{month}{day}/{hour}.tsv"), Partitioning::Hour, None).is_err());
+        assert!(PartitionedOutput::<u32>::new(dir.join("{year}-{month}-{day}.tsv"), Partitioning::Day, None).is_ok());
+        assert!(PartitionedOutput::<u32>::new(dir.join("{year}.tsv"), Partitioning::Boundaries(vec!["10".to_string()]), None).is_err());
+    }
+
+    #[test]
+    fn boundary_partitions() {
+        let dir = TempDir::new("boundary_partitions");
+
+        let boundaries = "boundaries:b,d".parse::<Partitioning>().unwrap();
+        let mut output = PartitionedOutput::new(dir.join("{bucket}.tsv"), boundaries, None).unwrap();
+
+        for key in &["a", "b", "c", "e"] {
+            let key = key.to_string();
+            output.start_row(&key).unwrap();
+            writeln!(output, "{}", key).unwrap();
+        }
+        output.finish().unwrap();
+
+        assert_eq!(read_file(&dir.join("below-b.tsv")), "a\n");
+        assert_eq!(read_file(&dir.join("b.tsv")), "b\nc\n");
+        assert_eq!(read_file(&dir.join("d.tsv")), "e\n");
+    }
+
+    #[test]
+    fn hash_partitions() {
+        let dir = TempDir::new("hash_partitions");
+
+        assert_eq!(stable_hash(b""), 0xcbf29ce484222325);
+        assert_eq!(stable_hash(b"a"), 0xaf63dc4c8601ec8c);
+        assert_eq!(stable_hash_extend(stable_hash(b"ab"), b"cd"), stable_hash(b"abcd"));
+
+        let mut output = HashPartitionedOutput::new(PathBuf::from(dir.join("{partition}.tsv")), 3, None).unwrap();
+
+        let keys = (100..130).map(|key| key.to_string()).collect::<Vec<String>>();
+        for key in &keys {
+            output.start_row(key).unwrap();
+            writeln!(output, "{}", key).unwrap();
+            output.start_row(key).unwrap();
+            writeln!(output, "{}", key).unwrap();
+        }
+        output.finish().unwrap();
+
+        // Every key appears twice in exactly one partition, and each partition stays sorted
+        let mut total_lines = 0;
+        for partition in 0..3 {
+            let contents = read_file(&dir.join(&format!("{:05}.tsv", partition)));
+            let lines = contents.lines().collect::<Vec<&str>>();
+
+            let mut sorted_lines = lines.clone();
+            sorted_lines.sort();
+            assert_eq!(lines, sorted_lines);
+
+            for key in &keys {
+                let count = lines.iter().filter(|line| *line == key).count();
+                assert!(count == 0 || count == 2);
+                if count == 2 {
+                    assert_eq!((stable_hash(key.as_bytes()) % 3) as usize, partition);
+                }
+            }
+
+            total_lines += lines.len();
+        }
+        assert_eq!(total_lines, 60);
+    }
+
+    #[test]
+    fn hash_partitions_raw_keys() {
+        let dir = TempDir::new("hash_partitions_raw_keys");
+
+        let mut output = HashPartitionedOutput::new(PathBuf::from(dir.join("{partition}.tsv")), 7, None).unwrap();
+
+        // These would all be the same replacement character if hashed as strings
+        let keys = (0x80..0x90).map(|byte| ByteString(vec![byte])).collect::<Vec<ByteString>>();
+        for key in &keys {
+            output.start_row(key).unwrap();
+            output.write_all(&key.0).unwrap();
+            output.write_all(b"\n").unwrap();
+        }
+        output.finish().unwrap();
+
+        for key in &keys {
+            let partition = (stable_hash(&key.0) % 7) as usize;
+            let mut contents = Vec::new();
+            File::open(dir.join(&format!("{:05}.tsv", partition))).unwrap().read_to_end(&mut contents).unwrap();
+            assert!(contents.split(|byte| *byte == b'\n').any(|line| line == &key.0[..]));
+        }
+
+        let partitions = keys.iter().map(|key| stable_hash(&key.0) % 7).collect::<HashSet<u64>>();
+        assert!(partitions.len() > 1);
+    }
+}
@@ -0,0 +1,212 @@
+use std::collections::HashMap;
+use std::io::prelude::*;
+use std::str::FromStr;
+use std::fmt;
+use std::io;
+
+use merge_file::{MergeFile, Mergeable};
+use merge_sink::MergeSink;
+use merge_output::MergeOutput;
+
+/// Which merge keys to keep, based on which input files contain them.
+#[derive(Clone, Debug, PartialEq)]
+pub enum SetOperation {
+    /// Every key (the regular merge)
+    Union,
+    /// Keys found in every input file
+    Intersect,
+    /// Keys found in the first input file (ordered by filename) and no other
+    Except,
+    /// Keys found in exactly one input file
+    Xor,
+}
+
+impl FromStr for SetOperation {
+    type Err = String;
+
+    fn from_str(operation: &str) -> Result<SetOperation, String> {
+        match operation.trim() {
+            "union"     => Ok(SetOperation::Union),
+            "intersect" => Ok(SetOperation::Intersect),
+            "except"    => Ok(SetOperation::Except),
+            "xor"       => Ok(SetOperation::Xor),
+            _           => Err(format!("Unknown set operation '{}', expected union, intersect, except or xor", operation)),
+        }
+    }
+}
+
+/// A `MergeSink` that only emits the runs of equal merge keys matching a `SetOperation`.
+///
+/// Each run is buffered until the merge key changes, at which point we know every input file
+/// the key appeared in. Either the run's lines or just its merge key are then written out.
+pub struct SetOperator<T, W: Write> {
+    operation: SetOperation,
+    keys_only: bool,
+    output: W,
+    file_indexes: HashMap<String, usize>,
+    first_file: usize,
+    current_key: Option<T>,
+    run: u64,
+    last_seen_run: Vec<u64>,
+    run_files: Vec<usize>,
+    run_lines: Vec<u8>,
+    run_line_ends: Vec<usize>,
+}
+
+impl<T: Mergeable, W: MergeOutput<T>> SetOperator<T, W> where T::Err: fmt::Debug {
+    /// `filenames` must hold every input file taking part in the merge, even those with no lines in range.
+    pub fn new(operation: SetOperation, filenames: Vec<String>, keys_only: bool, output: W) -> SetOperator<T, W> {
+        let mut filenames = filenames;
+        filenames.sort();
+        filenames.dedup();
+
+        let file_count = filenames.len();
+        let file_indexes = filenames.into_iter().enumerate().map(|(index, filename)| (filename, index)).collect();
+
+        SetOperator {
+            operation,
+            keys_only,
+            output,
+            file_indexes,
+            first_file: 0,
+            current_key: None,
+            run: 0,
+            last_seen_run: vec![0; file_count],
+            run_files: Vec::new(),
+            run_lines: Vec::new(),
+            run_line_ends: Vec::new(),
+        }
+    }
+
+    /// Returns true if the current run satisfies the set operation
+    fn run_matches(&self) -> bool {
+        match self.operation {
+            SetOperation::Union => true,
+            SetOperation::Intersect => self.run_files.len() == self.last_seen_run.len(),
+            SetOperation::Except => self.run_files == [self.first_file],
+            SetOperation::Xor => self.run_files.len() == 1,
+        }
+    }
+
+    /// Writes out the current run (if it matches) and starts a new one.
+    fn flush_run(&mut self) -> io::Result<()> {
+        if let Some(key) = self.current_key.take() {
+            if self.run_matches() {
+                if self.keys_only {
+                    self.output.start_row(&key)?;
+                    key.write_to(&mut self.output)?;
+                    self.output.write_all(b"\n")?;
+                } else {
+                    // The run's lines are stored back to back, each ending with a newline
+                    let mut line_start = 0;
+                    for &line_end in &self.run_line_ends {
+                        self.output.start_row(&key)?;
+                        self.output.write_all(&self.run_lines[line_start..line_end])?;
+                        line_start = line_end;
+                    }
+                }
+            }
+        }
+
+        self.run += 1;
+        self.run_files.clear();
+        self.run_lines.clear();
+        self.run_line_ends.clear();
+
+        Ok(())
+    }
+}
+
+impl<T: Mergeable, W: MergeOutput<T>> MergeSink<T> for SetOperator<T, W> where T::Err: fmt::Debug {
+    fn write_line(&mut self, merge_file: &MergeFile<T>) -> io::Result<()> {
+        if self.current_key.as_ref() != Some(&merge_file.current_merge_key) {
+            self.flush_run()?;
+            self.current_key = Some(merge_file.current_merge_key.clone());
+        }
+
+        // A union never needs to know which files the key is in, so don't buffer it
+        if self.operation == SetOperation::Union {
+            if !self.keys_only {
+                self.output.start_row(&merge_file.current_merge_key)?;
+                self.output.write_all(&merge_file.line)?;
+                self.output.write_all(b"\n")?;
+            }
+            return Ok(());
+        }
+
+        match self.file_indexes.get(&merge_file.filename) {
+            Some(&index) => {
+                if self.last_seen_run[index] != self.run {
+                    self.last_seen_run[index] = self.run;
+                    self.run_files.push(index);
+                }
+            },
+            None => warn!("MergeFile<{}> wasn't registered as a set operation input", merge_file.filename),
+        }
+
+        if !self.keys_only {
+            self.run_lines.extend_from_slice(&merge_file.line);
+            self.run_lines.push(b'\n');
+            self.run_line_ends.push(self.run_lines.len());
+        }
+
+        Ok(())
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        self.flush_run()?;
+        self.output.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{SetOperation, SetOperator};
+    use merge_file_manager::MergeFileManager;
+    use merge_file::InputOptions;
+    use test_helpers::{create_file, TempDir};
+
+    fn merge_with(dir: &TempDir, operation: SetOperation, keys_only: bool) -> String {
+        let cache = MergeFileManager::retrieve_from_glob(&dir.join("file?.tsv"), '\t', 0, "0".to_string(), InputOptions::default()).unwrap();
+        let filenames = cache.keys().cloned().collect();
+
+        let mut operator = SetOperator::new(operation, filenames, keys_only, Vec::new());
+        MergeFileManager::begin_merge(cache, None, &mut operator).unwrap();
+
+        String::from_utf8(operator.output).unwrap()
+    }
+
+    #[test]
+    fn set_operations() {
+        let dir = TempDir::new("set_operations");
+
+        let test_filename_1: &str = &dir.join("file1.tsv");
+        let test_contents_1 = format!("{}\t{}\n\
+                                       {}\t{}\n\
+                                       {}\t{}\n",
+                                        "123", "aaa",
+                                        "124", "aaa",
+                                        "125", "aaa");
+
+        create_file(test_filename_1, test_contents_1);
+
+        let test_filename_2: &str = &dir.join("file2.tsv");
+        let test_contents_2 = format!("{}\t{}\n\
+                                       {}\t{}\n\
+                                       {}\t{}\n",
+                                        "124", "bbb",
+                                        "124", "bbb",
+                                        "126", "bbb");
+
+        create_file(test_filename_2, test_contents_2);
+
+        assert_eq!(merge_with(&dir, SetOperation::Union, true), "123\n124\n125\n126\n");
+        assert_eq!(merge_with(&dir, SetOperation::Intersect, true), "124\n");
+        assert_eq!(merge_with(&dir, SetOperation::Except, true), "123\n125\n");
+        assert_eq!(merge_with(&dir, SetOperation::Xor, true), "123\n125\n126\n");
+
+        let intersection = merge_with(&dir, SetOperation::Intersect, false);
+        assert_eq!(intersection.lines().count(), 3);
+        assert!(intersection.lines().all(|line| line.starts_with("124\t")));
+    }
+}
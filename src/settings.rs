@@ -1,26 +1,127 @@
 extern crate env_logger;
 
 use std::path::PathBuf;
+use std::str::FromStr;
 use getopts::{Options, Matches};
 use std::process;
+use std::thread;
 use std::env;
 
-#[derive(Clone, Debug)]
+use set_operation::SetOperation;
+use aggregate::Aggregate;
+use merge_sink::Annotation;
+use filter::Predicate;
+use partition::Partitioning;
+use encoding::InputEncoding;
+use input_format::InputFormat;
+use cache_file::{CacheLockPolicy, StaleCachePolicy};
+
+#[derive(Clone, Debug, PartialEq)]
 pub enum KeyType {
     Unsigned32Integer,
     Signed32Integer,
     String,
 }
 
+impl FromStr for KeyType {
+    type Err = String;
+
+    fn from_str(key_type: &str) -> Result<KeyType, String> {
+        match key_type.trim() {
+            "Unsigned32Integer" => Ok(KeyType::Unsigned32Integer),
+            "Signed32Integer"   => Ok(KeyType::Signed32Integer),
+            "String"            => Ok(KeyType::String),
+            _                   => Err(format!("Unknown key type '{}', expected Unsigned32Integer, Signed32Integer or String", key_type)),
+        }
+    }
+}
+
+impl KeyType {
+    /// Checks a key given on the command line (eg. `--key-start`) can be compared against merge keys of this type.
+    pub fn check_key(&self, key: &str) -> Result<(), String> {
+        match *self {
+            KeyType::Unsigned32Integer => key.parse::<u32>().map(|_| ()).map_err(|error| error.to_string()),
+            KeyType::Signed32Integer => key.parse::<i32>().map(|_| ()).map_err(|error| error.to_string()),
+            KeyType::String => Ok(()),
+        }
+    }
+}
+
+/// The `cache` subcommands, eg. `file-merger cache refresh --glob '/data/*.gz' --cache-file data.cache`
+#[derive(Clone, Debug, PartialEq)]
+pub enum CacheCommand {
+    /// Bring the cache up to date with the files on disk, only reading new and changed files
+    Refresh,
+    /// Print a table of the cached files
+    Show,
+    /// Print the number of cached files, their total size and the range of merge keys across them
+    Stats,
+    /// List the cached files a merge between --key-start and --key-end would read from
+    Query,
+    /// Write the files of all the given caches into the cache, keeping the newest entry of any file in several
+    Union,
+    /// Write the files of the first given cache that aren't in the others (or changed since) into the cache
+    Subtract,
+    /// List the files added, removed and changed in the cache since the given one
+    Diff,
+}
+
+impl FromStr for CacheCommand {
+    type Err = String;
+
+    fn from_str(command: &str) -> Result<CacheCommand, String> {
+        match command {
+            "refresh" => Ok(CacheCommand::Refresh),
+            "show" => Ok(CacheCommand::Show),
+            "stats" => Ok(CacheCommand::Stats),
+            "query" => Ok(CacheCommand::Query),
+            "union" => Ok(CacheCommand::Union),
+            "subtract" => Ok(CacheCommand::Subtract),
+            "diff" => Ok(CacheCommand::Diff),
+            _ => Err(format!("Unknown cache command '{}', expected refresh, show, stats, query, union, subtract or diff", command)),
+        }
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct MergeSettings {
+    /// Only required to merge or build a cache, the other cache commands leave them at tab and 0
     pub delimiter: char,
     pub key_index: usize,
     pub key_start: Option<String>,
     pub key_end: Option<String>,
     pub key_type: KeyType,
+    pub input_encoding: InputEncoding,
+    pub input_format: InputFormat,
+    pub tolerate_corrupt_inputs: bool,
     pub cache_path: Option<PathBuf>,
+    pub cache_command: Option<CacheCommand>,
+    /// The caches given to `cache union`, `cache subtract` and `cache diff` to work on along with the --cache-file
+    pub other_caches: Vec<PathBuf>,
+    pub stale_cache_policy: StaleCachePolicy,
+    pub cache_base_dir: Option<PathBuf>,
+    pub cache_lock_policy: CacheLockPolicy,
+    pub cache_checkpoint_lines: Option<u64>,
     pub glob_choices: Option<Vec<String>>,
+    pub aggregates: Option<Vec<Aggregate>>,
+    pub set_operation: Option<SetOperation>,
+    pub keys_only: bool,
+    pub annotations: Vec<Annotation>,
+    pub prepend_annotations: bool,
+    pub output_columns: Option<Vec<usize>>,
+    pub filter: Option<Predicate>,
+    pub output_path: Option<PathBuf>,
+    pub split_bytes: Option<u64>,
+    pub split_rows: Option<u64>,
+    pub split_whole_keys: bool,
+    pub partitioning: Option<Partitioning>,
+    pub hash_partitions: Option<usize>,
+    pub sort: bool,
+    pub sort_memory: u64,
+    pub temp_dir: PathBuf,
+    pub max_open_files: Option<usize>,
+    /// How many inputs are read ahead on their own thread, none unless --parallel-readers is given
+    pub reader_threads: usize,
 }
 
 pub struct MergeSettingsParser {
@@ -35,13 +136,13 @@ impl MergeSettingsParser {
 
         let matches = match opts.parse(&args) {
             Ok(matches) => matches,
-            Err(failure) => panic!(failure.to_string()),
+            Err(failure) => panic!("{}", failure),
         };
 
         MergeSettingsParser {
             program: args[0].clone(),
-            opts: opts,
-            matches: matches,
+            opts,
+            matches,
         }
     }
 
@@ -53,10 +154,18 @@ impl MergeSettingsParser {
 
         self.init_logging();
 
-        let delimiter_char = try!(self.parse_delimiter());
-        let key_index = try!(self.parse_key_index());
-        let glob_choices = try!(self.parse_glob());
-        let cache_path = try!(self.parse_cache_file());
+        let glob_choices = self.parse_glob()?;
+        let cache_path = self.parse_cache_file()?;
+        let cache_command = self.parse_cache_command()?;
+
+        // Only merging and building (or adding to) a cache split lines up, the other cache commands go by what the cache recorded
+        let splits_lines = match cache_command {
+            None => true,
+            Some(CacheCommand::Refresh) => glob_choices.is_some(),
+            Some(_) => false,
+        };
+        let delimiter_char = if splits_lines || self.matches.opt_present("delimiter") { self.parse_delimiter()? } else { '\t' };
+        let key_index = if splits_lines || self.matches.opt_present("key-index") { self.parse_key_index()? } else { 0 };
 
         // Check that at least one required arg is present
         if glob_choices.is_none() && cache_path.is_none() {
@@ -65,19 +174,114 @@ impl MergeSettingsParser {
             self.error_usage_and_bail("No glob provided and the cache file doesn't exist? Nothing we can do here.");
         }
 
-        let key_start = try!(self.parse_key_generic("key-start"));
-        let key_end = try!(self.parse_key_generic("key-end"));
+        if cache_command.is_some() && cache_path.is_none() {
+            return Err("The cache commands need a --cache-file to work on".to_string());
+        }
+        let other_caches = self.parse_other_caches(&cache_command)?;
+
+        let key_start = self.parse_key_generic("key-start")?;
+        let key_end = self.parse_key_generic("key-end")?;
+
+        let key_type = self.parse_key_type()?;
+        for &(param, key) in &[("key-start", &key_start), ("key-end", &key_end)] {
+            if let Some(ref key) = *key {
+                key_type.check_key(key).map_err(|error| format!("Invalid --{} '{}' for the {:?} key type: {}", param, key, key_type, error))?;
+            }
+        }
+        let input_encoding = self.parse_input_encoding()?;
+        let input_format = self.parse_input_format()?;
+        let stale_cache_policy = self.parse_stale_cache_policy()?;
+        let cache_lock_policy = self.parse_cache_lock_policy()?;
+        let cache_checkpoint_lines = self.parse_cache_checkpoints()?;
+        let aggregates = self.parse_aggregates()?;
+        let set_operation = self.parse_set_operation()?;
+
+        let annotations = self.parse_annotations()?;
+        let prepend_annotations = self.parse_annotate_position()?;
+        let output_columns = self.parse_output_columns()?;
+        let filter = self.parse_filter()?;
+        let output_path = self.matches.opt_str("output").map(PathBuf::from);
+        let split_bytes = self.parse_split_bytes()?;
+        let split_rows = self.parse_split_rows()?;
+
+        let partitioning = self.parse_partitioning()?;
+        let hash_partitions = self.parse_hash_partitions()?;
+
+        let output_modes = [split_bytes.is_some() || split_rows.is_some(), partitioning.is_some(), hash_partitions.is_some()];
+        let output_mode_count = output_modes.iter().filter(|present| **present).count();
+
+        if output_path.is_none() && output_mode_count > 0 {
+            return Err("Splitting the output into shards or partitions needs an --output path".to_string());
+        }
+
+        if output_mode_count > 1 {
+            return Err("Only one of --split-bytes/--split-rows, --partition-by and --hash-partitions can be used at a time".to_string());
+        }
+
+        if input_format == InputFormat::JsonLines && !annotations.is_empty() {
+            return Err("--annotate adds delimited columns so can't be used with --input-format jsonl".to_string());
+        }
+
+        if aggregates.is_some() && set_operation.is_some() {
+            return Err("Only one of --aggregate and --set-op can be used at a time".to_string());
+        }
+
+        // Both write rows of their own rather than passing the merged lines through
+        if (aggregates.is_some() || set_operation.is_some()) && (output_columns.is_some() || !annotations.is_empty()) {
+            return Err("--output-columns and --annotate can't be used with --aggregate or --set-op".to_string());
+        }
 
-        let key_type = try!(self.parse_key_type());
+        let sort = self.matches.opt_present("sort");
+        let sort_memory = self.parse_sort_memory()?;
+        let temp_dir = self.matches.opt_str("temp-dir").map(PathBuf::from).unwrap_or_else(env::temp_dir);
+
+        let max_open_files = self.parse_max_open_files()?;
+        let reader_threads = self.parse_reader_threads()?;
+
+        if sort && (glob_choices.is_none() || cache_path.is_some()) {
+            return Err("--sort reads its inputs from --glob and can't be used with --cache-file".to_string());
+        }
+
+        // These merge intermediate runs rather than the original input files
+        if (sort || max_open_files.is_some()) && (set_operation.is_some() || !annotations.is_empty()) {
+            return Err("--set-op, --keys-only and --annotate need the original input files so can't be used with --sort or --max-open-files".to_string());
+        }
 
         Ok(MergeSettings {
-            cache_path: cache_path,
-            glob_choices: glob_choices,
+            cache_path,
+            cache_command,
+            other_caches,
+            stale_cache_policy,
+            cache_base_dir: self.matches.opt_str("cache-base-dir").map(PathBuf::from),
+            cache_lock_policy,
+            cache_checkpoint_lines,
+            glob_choices,
             delimiter: delimiter_char,
-            key_index: key_index,
-            key_start: key_start,
-            key_end: key_end,
-            key_type: key_type,
+            key_index,
+            key_start,
+            key_end,
+            key_type,
+            input_encoding,
+            input_format,
+            tolerate_corrupt_inputs: self.matches.opt_present("tolerate-corrupt-inputs"),
+            aggregates,
+            set_operation,
+            keys_only: self.matches.opt_present("keys-only"),
+            annotations,
+            prepend_annotations,
+            output_columns,
+            filter,
+            output_path,
+            split_bytes,
+            split_rows,
+            split_whole_keys: self.matches.opt_present("split-whole-keys"),
+            partitioning,
+            hash_partitions,
+            sort,
+            sort_memory,
+            temp_dir,
+            max_open_files,
+            reader_threads,
         })
     }
 
@@ -93,6 +297,10 @@ impl MergeSettingsParser {
         // * If both the glob and cache-file options are provided, we will cache the glob results
         opts.optmulti("", "glob", "File glob that will provide all required files", "/path/to/specific_*_files.*.gz");
         opts.optopt("", "cache-file", "Cache file containing files we could merge and their upper and lower merge keys", "/path/to/file.cache");
+        opts.optopt("", "cache-checkpoints", "Record the merge key and offset of every this many lines of each file in the cache, letting --key-start skip ahead to them", "100000");
+        opts.optopt("", "cache-base-dir", "Directory the relative filenames in --cache-file are relative to, instead of the one recorded in it (eg. after moving the data)", "/path/to/data");
+        opts.optopt("", "stale-cache", "What to do with --cache-file entries whose files changed since it was written, read them again or fail (default rescan)", "'rescan' || 'reject'");
+        opts.optopt("", "cache-lock", "What to do when another file-merger is writing --cache-file (or using it, when writing it), wait for it or fail (default wait)", "'wait' || 'fail'");
         opts.optopt("", "delimiter", "Raw character we split the line on", "'\t' || ',' || '|'");
 
         // Merge options (only required if merging)
@@ -100,17 +308,45 @@ impl MergeSettingsParser {
         opts.optopt("", "key-start", "Lower bound (starting from and including) merge key", "1");
         opts.optopt("", "key-end", "Upper bound (up to but not including) merge key", "10");
         opts.optopt("", "key-type", "The data type of the key used for optimization", "'Unsigned32Integer' || 'Signed32Integer' || 'String'");
+        opts.optopt("", "input-format", "How lines are split into columns, csv allows quoted columns and jsonl reads each line as a JSON object whose members are its columns (default csv for --delimiter csv, otherwise delimited)", "'delimited' || 'csv' || 'jsonl'");
+        opts.optopt("", "input-encoding", "The encoding of the input files, anything but bytes is transcoded to UTF-8 (default bytes, merged as is)", "'bytes' || 'utf-8' || 'latin-1' || 'utf-16' || 'utf-16le' || 'utf-16be'");
+        opts.optflag("", "tolerate-corrupt-inputs", "Treat an input that fails to read (eg. a truncated gzip) as ending there instead of failing the merge");
+        opts.optflag("", "sort", "The --glob files aren't sorted, sort them into temporary runs before merging");
+        opts.optopt("", "sort-memory", "Roughly how much memory --sort buffers lines in before spilling a sorted run (default 256M)", "1G");
+        opts.optopt("", "max-open-files", "Merge at most this many files at once, merging any more in passes through temporary runs", "1000");
+        opts.optflag("", "parallel-readers", "Decompress and split each input's lines ahead of the merge on its own thread");
+        opts.optopt("", "reader-threads", "At most this many --parallel-readers threads, the largest inputs get one and the rest are read on the merge thread (default the number of CPUs)", "8");
+        opts.optopt("", "temp-dir", "Where --sort and --max-open-files spill their runs (default the system temp dir)", "/path/to/tmp");
+        opts.optopt("", "filter", "Only merge lines matching this predicate over their columns (0 based)", "'col[4] == \"US\" && col[6] > 100'");
+
+        // Output options
+        opts.optopt("", "aggregate", "Emit one row per merge key with these aggregates instead of the merged lines", "count,sum:3,min:4,max:4");
+        opts.optopt("", "set-op", "Only emit merge keys based on which input files contain them", "'union' || 'intersect' || 'except' || 'xor'");
+        opts.optflag("", "keys-only", "Emit only the distinct merge keys instead of the merged lines");
+        opts.optopt("", "output-columns", "Only write out these columns (0 based) of each merged line, in this order", "0,3,7-9");
+        opts.optopt("", "annotate", "Add columns describing where each merged line came from", "filename,lineno,offset");
+        opts.optopt("", "output", "Write the merge out to this file instead of stdout (compressed based on its extension, .gz, .bgz or .bz2)", "/path/to/output.tsv.gz");
+        opts.optopt("", "split-bytes", "Roll over to a new numbered --output shard after this many (uncompressed) bytes", "1G");
+        opts.optopt("", "split-rows", "Roll over to a new numbered --output shard after this many rows", "1000000");
+        opts.optflag("", "split-whole-keys", "Never split a run of equal merge keys across two shards");
+        opts.optopt("", "partition-by", "Route rows into an --output file per key bucket, the --output path needs a {bucket} placeholder", "'hour' || 'day' || 'boundaries:100,200,300'");
+        opts.optopt("", "hash-partitions", "Spread rows across this many sorted --output files by the hash of their merge key", "16");
+        opts.optopt("", "annotate-position", "Whether the --annotate columns go before or after the line (default append)", "'prepend' || 'append'");
 
         opts
     }
 
     fn print_usage(&self) {
-        let usage = format!("\nUsage: {} [-h] [-v] -- See below for all options", self.program);
+        let usage = format!("\nUsage: {} [-h] [-v] -- See below for all options\n       \
+                             {} cache refresh --cache-file /path/to/file.cache [--glob ...]\n       \
+                             {} cache show|stats|query --cache-file /path/to/file.cache [--key-start ...] [--key-end ...]\n       \
+                             {} cache union|subtract|diff /path/to/other.cache ... --cache-file /path/to/file.cache",
+                            self.program, self.program, self.program, self.program);
         println!("{}", self.opts.usage(&usage));
         process::exit(1);
     }
 
-    pub fn error_usage_and_bail(&self, message: &str) {
+    pub fn error_usage_and_bail(&self, message: &str) -> ! {
         error!("{}", message);
         self.print_usage();
         process::exit(1);
@@ -125,7 +361,8 @@ impl MergeSettingsParser {
             _ => {env::set_var("RUST_LOG", "trace")}, // Provided > 2 -v flags
         }
 
-        env_logger::init();
+        // Only the first parse sets up the logger, the tests parse several times over
+        let _ = env_logger::try_init();
 
         debug!("Applied log level: {}", env::var("RUST_LOG").unwrap());
     }
@@ -191,22 +428,291 @@ impl MergeSettingsParser {
 
     fn parse_key_type(&self) -> Result<KeyType, &str> {
         if self.matches.opt_present("key-type") {
-            let result = self.matches.opt_str("key-type");
-
-            if result.is_some() {
-                let result = result.unwrap();
-
-                match result.trim() {
-                    "Unsigned32Integer" => Ok(KeyType::Unsigned32Integer),
-                    "Signed32Integer"   => Ok(KeyType::Signed32Integer),
-                    "String"            => Ok(KeyType::String),
-                    _                   => Err("Your key-type is wrong?"),
-                }
-            } else {
-                Err("Your key-type is wrong?")
+            match self.matches.opt_str("key-type") {
+                Some(result) => result.parse::<KeyType>().map_err(|_| "Your key-type is wrong?"),
+                None => Err("Your key-type is wrong?"),
             }
         } else {
             Ok(KeyType::String)
         }
     }
+
+    fn parse_input_encoding(&self) -> Result<InputEncoding, String> {
+        match self.matches.opt_str("input-encoding") {
+            Some(encoding) => encoding.parse::<InputEncoding>(),
+            None => Ok(InputEncoding::Bytes),
+        }
+    }
+
+    fn parse_input_format(&self) -> Result<InputFormat, String> {
+        match self.matches.opt_str("input-format") {
+            Some(format) => format.parse::<InputFormat>(),
+            None if self.matches.opt_str("delimiter").as_deref() == Some("csv") => Ok(InputFormat::Csv),
+            None => Ok(InputFormat::Delimited),
+        }
+    }
+
+    fn parse_cache_command(&self) -> Result<Option<CacheCommand>, String> {
+        // The first free argument is the program itself
+        match self.matches.free.get(1).map(|command| command.as_str()) {
+            Some("cache") => match self.matches.free.get(2) {
+                Some(command) => command.parse::<CacheCommand>().map(Some),
+                None => Err("Missing the cache command, expected refresh, show, stats, query, union, subtract or diff".to_string()),
+            },
+            Some(command) => Err(format!("Unknown command '{}'", command)),
+            None => Ok(None),
+        }
+    }
+
+    fn parse_cache_checkpoints(&self) -> Result<Option<u64>, String> {
+        match self.matches.opt_str("cache-checkpoints") {
+            Some(lines) => match lines.parse::<u64>() {
+                Ok(lines) if lines > 0 => Ok(Some(lines)),
+                _ => Err(format!("--cache-checkpoints needs a number of lines greater than 0, not '{}'", lines)),
+            },
+            None => Ok(None),
+        }
+    }
+
+    fn parse_other_caches(&self, cache_command: &Option<CacheCommand>) -> Result<Vec<PathBuf>, String> {
+        // Given after the cache command, eg. cache union monday.cache tuesday.cache --cache-file week.cache
+        let other_caches = self.matches.free.iter().skip(3).map(PathBuf::from).collect::<Vec<PathBuf>>();
+
+        match *cache_command {
+            Some(CacheCommand::Union) if other_caches.is_empty() => Err("cache union needs at least one cache to combine into the --cache-file".to_string()),
+            Some(CacheCommand::Subtract) if other_caches.len() < 2 => {
+                Err("cache subtract needs a cache and at least one cache of processed files to take out of it".to_string())
+            },
+            Some(CacheCommand::Diff) if other_caches.len() != 1 => Err("cache diff needs exactly one cache to compare the --cache-file to".to_string()),
+            Some(CacheCommand::Union) | Some(CacheCommand::Subtract) | Some(CacheCommand::Diff) => Ok(other_caches),
+            _ if other_caches.is_empty() => Ok(other_caches),
+            _ => Err(format!("Unexpected arguments {:?}", other_caches)),
+        }
+    }
+
+    fn parse_cache_lock_policy(&self) -> Result<CacheLockPolicy, String> {
+        match self.matches.opt_str("cache-lock") {
+            Some(policy) => policy.parse::<CacheLockPolicy>(),
+            None => Ok(CacheLockPolicy::Wait),
+        }
+    }
+
+    fn parse_stale_cache_policy(&self) -> Result<StaleCachePolicy, String> {
+        match self.matches.opt_str("stale-cache") {
+            Some(policy) => policy.parse::<StaleCachePolicy>(),
+            None => Ok(StaleCachePolicy::Rescan),
+        }
+    }
+
+    fn parse_aggregates(&self) -> Result<Option<Vec<Aggregate>>, String> {
+        match self.matches.opt_str("aggregate") {
+            Some(aggregates) => {
+                let aggregates = aggregates.split(',')
+                                           .map(|aggregate| aggregate.parse::<Aggregate>())
+                                           .collect::<Result<Vec<Aggregate>, String>>()?;
+                Ok(Some(aggregates))
+            },
+            None => Ok(None),
+        }
+    }
+
+    fn parse_set_operation(&self) -> Result<Option<SetOperation>, String> {
+        match self.matches.opt_str("set-op") {
+            Some(set_operation) => Ok(Some(set_operation.parse::<SetOperation>()?)),
+            None if self.matches.opt_present("keys-only") => Ok(Some(SetOperation::Union)),
+            None => Ok(None),
+        }
+    }
+
+    fn parse_annotations(&self) -> Result<Vec<Annotation>, String> {
+        match self.matches.opt_str("annotate") {
+            Some(annotations) => annotations.split(',')
+                                            .map(|annotation| annotation.parse::<Annotation>())
+                                            .collect(),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    fn parse_annotate_position(&self) -> Result<bool, String> {
+        match self.matches.opt_str("annotate-position") {
+            Some(ref x) if x == "prepend" => Ok(true),
+            Some(ref x) if x == "append" => Ok(false),
+            Some(x) => Err(format!("Unknown annotate-position '{}', expected prepend or append", x)),
+            None => Ok(false),
+        }
+    }
+
+    fn parse_output_columns(&self) -> Result<Option<Vec<usize>>, String> {
+        let output_columns = match self.matches.opt_str("output-columns") {
+            Some(output_columns) => output_columns,
+            None => return Ok(None),
+        };
+
+        let invalid = || format!("Invalid --output-columns '{}', expected eg. 0,3,7-9", output_columns);
+        let mut columns = Vec::new();
+
+        for range in output_columns.split(',') {
+            let mut bounds = range.trim().splitn(2, '-');
+            let start = bounds.next().unwrap_or("").parse::<usize>().map_err(|_| invalid())?;
+
+            match bounds.next() {
+                Some(end) => {
+                    let end = end.parse::<usize>().map_err(|_| invalid())?;
+                    if end < start {
+                        return Err(invalid());
+                    }
+                    columns.extend(start..end + 1);
+                },
+                None => columns.push(start),
+            }
+        }
+
+        Ok(Some(columns))
+    }
+
+    fn parse_filter(&self) -> Result<Option<Predicate>, String> {
+        match self.matches.opt_str("filter") {
+            Some(filter) => Ok(Some(filter.parse::<Predicate>()?)),
+            None => Ok(None),
+        }
+    }
+
+    fn parse_partitioning(&self) -> Result<Option<Partitioning>, String> {
+        match self.matches.opt_str("partition-by") {
+            Some(partitioning) => Ok(Some(partitioning.parse::<Partitioning>()?)),
+            None => Ok(None),
+        }
+    }
+
+    fn parse_hash_partitions(&self) -> Result<Option<usize>, String> {
+        match self.matches.opt_str("hash-partitions").map(|partitions| partitions.parse::<usize>()) {
+            Some(Ok(0)) | Some(Err(_)) => Err("--hash-partitions needs to be a positive whole number".to_string()),
+            Some(Ok(partitions)) => Ok(Some(partitions)),
+            None => Ok(None),
+        }
+    }
+
+    fn parse_sort_memory(&self) -> Result<u64, String> {
+        match self.matches.opt_str("sort-memory") {
+            Some(size) => parse_size(&size),
+            None => Ok(256 << 20),
+        }
+    }
+
+    fn parse_max_open_files(&self) -> Result<Option<usize>, String> {
+        match self.matches.opt_str("max-open-files").map(|files| files.parse::<usize>()) {
+            Some(Ok(files)) if files >= 2 => Ok(Some(files)),
+            Some(_) => Err("--max-open-files needs to be a whole number of at least 2".to_string()),
+            None => Ok(None),
+        }
+    }
+
+    fn parse_reader_threads(&self) -> Result<usize, String> {
+        if !self.matches.opt_present("parallel-readers") {
+            if self.matches.opt_present("reader-threads") {
+                return Err("--reader-threads only applies to --parallel-readers".to_string());
+            }
+            return Ok(0);
+        }
+
+        match self.matches.opt_str("reader-threads").map(|threads| threads.parse::<usize>()) {
+            Some(Ok(threads)) if threads >= 1 => Ok(threads),
+            Some(_) => Err("--reader-threads needs to be a whole number of at least 1".to_string()),
+            None => Ok(thread::available_parallelism().map(|threads| threads.get()).unwrap_or(1)),
+        }
+    }
+
+    fn parse_split_bytes(&self) -> Result<Option<u64>, String> {
+        match self.matches.opt_str("split-bytes") {
+            Some(size) => Ok(Some(parse_size(&size)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn parse_split_rows(&self) -> Result<Option<u64>, String> {
+        match self.matches.opt_str("split-rows").map(|rows| rows.parse::<u64>()) {
+            Some(Ok(0)) | Some(Err(_)) => Err("--split-rows needs to be a positive whole number".to_string()),
+            Some(Ok(rows)) => Ok(Some(rows)),
+            None => Ok(None),
+        }
+    }
+}
+
+/// Parses a byte size with an optional K, M, G or T (1024 based) suffix, eg. 512M
+pub fn parse_size(size: &str) -> Result<u64, String> {
+    let size = size.trim();
+    let (number, multiplier) = match size.chars().last().map(|suffix| suffix.to_ascii_uppercase()) {
+        Some('K') => (&size[..size.len() - 1], 1u64 << 10),
+        Some('M') => (&size[..size.len() - 1], 1u64 << 20),
+        Some('G') => (&size[..size.len() - 1], 1u64 << 30),
+        Some('T') => (&size[..size.len() - 1], 1u64 << 40),
+        _ => (size, 1),
+    };
+
+    match number.parse::<u64>() {
+        Ok(number) if number > 0 => number.checked_mul(multiplier).ok_or_else(|| format!("Size '{}' is too large", size)),
+        _ => Err(format!("Invalid size '{}', expected eg. 512M or 1G", size)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_size, MergeSettingsParser};
+
+    fn parse(args: &str) -> Result<(), String> {
+        let args = format!("file-merger {}", args).split(' ').map(|arg| arg.to_string()).collect::<Vec<String>>();
+        MergeSettingsParser::new(args).parse().map(|_| ())
+    }
+
+    #[test]
+    fn sizes() {
+        assert_eq!(parse_size("512"), Ok(512));
+        assert_eq!(parse_size("64k"), Ok(64 << 10));
+        assert_eq!(parse_size(" 2G "), Ok(2 << 30));
+        assert_eq!(parse_size("16777215T"), Ok(16777215 << 40));
+        assert_eq!(parse_size("16777216T"), Err("Size '16777216T' is too large".to_string()));
+        assert_eq!(parse_size("99999999999G"), Err("Size '99999999999G' is too large".to_string()));
+        assert!(parse_size("0M").is_err());
+        assert!(parse_size("1.5G").is_err());
+        assert!(parse_size("G").is_err());
+    }
+
+    #[test]
+    fn conflicting_outputs() {
+        let merge = "--delimiter , --key-index 0 --glob /data/*.csv";
+        assert!(parse(merge).is_ok());
+        assert!(parse(&format!("{} --output-columns 0,2 --annotate filename", merge)).is_ok());
+
+        // Aggregates and set operations write rows of their own
+        let rejected = Err("--output-columns and --annotate can't be used with --aggregate or --set-op".to_string());
+        assert_eq!(parse(&format!("{} --aggregate count --output-columns 0", merge)), rejected);
+        assert_eq!(parse(&format!("{} --set-op intersect --annotate filename", merge)), rejected);
+    }
+
+    #[test]
+    fn cache_commands() {
+        assert_eq!(parse("--glob /data/*.csv"), Err("We need a --delimiter parameter".to_string()));
+        assert_eq!(parse("--delimiter , --glob /data/*.csv --cache-file data.cache"), Err("We need a --key-index parameter".to_string()));
+
+        // Only the cache commands that read new files into the cache need to know how to split their lines
+        for command in &["show", "stats", "query", "refresh"] {
+            assert!(parse(&format!("cache {} --cache-file data.cache", command)).is_ok());
+        }
+        assert!(parse("cache union other.cache --cache-file data.cache").is_ok());
+        assert!(parse("cache diff other.cache --cache-file data.cache").is_ok());
+        assert_eq!(parse("cache refresh --glob /data/*.csv --cache-file data.cache"), Err("We need a --delimiter parameter".to_string()));
+        assert!(parse("cache refresh --delimiter , --key-index 0 --glob /data/*.csv --cache-file data.cache").is_ok());
+    }
+
+    #[test]
+    fn key_bounds() {
+        let merge = "--delimiter , --key-index 0 --glob /data/*.csv";
+        assert!(parse(&format!("{} --key-type Signed32Integer --key-start -5 --key-end 10", merge)).is_ok());
+        assert!(parse(&format!("{} --key-start abc --key-end def", merge)).is_ok());
+
+        // Checked against the key type before any file is opened
+        assert_eq!(parse(&format!("{} --key-type Unsigned32Integer --key-start -5", merge)),
+                   Err("Invalid --key-start '-5' for the Unsigned32Integer key type: invalid digit found in string".to_string()));
+        assert!(parse(&format!("{} --key-type Signed32Integer --key-end 1.5", merge)).is_err());
+    }
 }
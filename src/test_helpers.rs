@@ -0,0 +1,48 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::path::{Path, PathBuf};
+use std::io::prelude::*;
+use std::fs::File;
+use std::process;
+use std::env;
+use std::fs;
+
+static NEXT_TEMP_DIR: AtomicUsize = AtomicUsize::new(0);
+
+/// A directory of a test's own to write its files into, removed along with everything in it when dropped.
+pub struct TempDir {
+    path: PathBuf,
+}
+
+impl TempDir {
+    /// Creates an empty directory named after the test, unique to this process and call so tests can run in parallel.
+    pub fn new(name: &str) -> TempDir {
+        let number = NEXT_TEMP_DIR.fetch_add(1, Ordering::SeqCst);
+        let path = env::temp_dir().join(format!("file-merger-test.{}.{}.{}", name, process::id(), number));
+
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+
+        TempDir { path }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The path of `name` in the directory, as a string as that's how filenames are passed around the merge.
+    pub fn join(&self, name: &str) -> String {
+        self.path.join(name).to_str().unwrap().to_string()
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
+
+/// Creates (or truncates) the file at `path` holding exactly `contents`.
+pub fn create_file<P: AsRef<Path>, C: AsRef<[u8]>>(path: P, contents: C) {
+    let mut file = File::create(path).unwrap();
+    file.write_all(contents.as_ref()).unwrap();
+}
@@ -7,9 +7,9 @@
 #
 
 import os
+import csv
 import sys
 import time
-import filecmp
 import argparse
 import subprocess
 
@@ -51,6 +51,28 @@ REQUIRED_TEST_TYPE_CONTEXT = {
     MERGE_FILES_FROM_GLOB: (DATA_GLOB, DATA_START, DATA_END, DATA_OUTPUT),
 }
 
+def read_cache(path, columns=None):
+    """
+    Reads a cache file into its key type and entries, with filenames resolved against its base_dir.
+    Only the columns given are kept, so caches can be compared on what a fixture records.
+    Caches record when they were written and the data files' mtimes, so they never match byte for byte.
+    """
+    with open(path, newline="") as cache_file:
+        records = list(csv.reader(cache_file))
+
+    header = dict(field.split("=", 1) for field in records[0][1:] if "=" in field)
+    base_dir = os.path.join(os.path.dirname(path), header.get("base_dir", ""))
+    names = records[1]
+    columns = columns or names
+
+    entries = []
+    for record in records[2:]:
+        entry = dict(zip(names, record))
+        entry["filename"] = os.path.realpath(os.path.join(base_dir, entry["filename"]))
+        entries.append(tuple(entry.get(column) for column in columns))
+
+    return header.get("key_type"), columns, sorted(entries)
+
 def run_test(context):
     # Define the required keys in a tests context
     required_context = set(DEFAULT_REQUIRED_CONTEXT + REQUIRED_TEST_TYPE_CONTEXT[context[TEST_TYPE]])
@@ -92,7 +114,18 @@ def run_test(context):
                 print("ERROR: Missing cache file???")
             assert(False)
 
-        assert(filecmp.cmp(context[DATA_CACHE], "./files/cache_files/test1.cache"))
+        key_type, columns, expected_entries = read_cache("./files/cache_files/test1.cache")
+        built_key_type, _, built_entries = read_cache(context[DATA_CACHE], columns)
+
+        if VERBOSE and (built_key_type, built_entries) != (key_type, expected_entries):
+            print("Cache Mismatch!")
+            print("Known cache:")
+            print(key_type, expected_entries)
+            print("Test cache:")
+            print(built_key_type, built_entries)
+
+        assert(built_key_type == key_type)
+        assert(built_entries == expected_entries)
 
     elif context[TEST_TYPE] in [MERGE_FILES_FROM_CACHE, MERGE_FILES_FROM_GLOB]:
         with open(context[DATA_OUTPUT], "rb") as data_output_file: